@@ -0,0 +1,37 @@
+#![no_main]
+
+// Run with `cargo fuzz run process_snapshot` from the bevy_replicate directory.
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy_replicate::{
+    client::{process_snapshot, ReplicateClientConfig, ReplicateClientPlugin},
+    compression::Lz4Codec,
+    network_frame,
+    networked_hierarchy::NetworkParent,
+    networked_transform::TransformNetworked,
+};
+use libfuzzer_sys::fuzz_target;
+
+network_frame!(TransformNetworked, NetworkParent);
+
+fuzz_target!(|data: &[u8]| {
+    let mut app = App::new();
+    app.init_resource::<Time>();
+    app.add_plugin(ReplicateClientPlugin::<NetworkFrame>::new(ReplicateClientConfig {
+        codecs: vec![Arc::new(Lz4Codec)],
+        ..Default::default()
+    }));
+
+    // The input is read as messages prefixed by their length in 2 bytes, as received from the server:
+    // fragments reassembled, decompressed and read, the frames are applied by the update
+    let mut data = data;
+    while data.len() >= 2 {
+        let len = (u16::from_le_bytes([data[0], data[1]]) as usize).min(data.len() - 2);
+        let (message, rest) = data[2..].split_at(len);
+        let _ = process_snapshot::<NetworkFrame>(message.to_vec(), &mut app.world);
+        app.update();
+        data = rest;
+    }
+});
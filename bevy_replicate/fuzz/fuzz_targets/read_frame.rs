@@ -0,0 +1,34 @@
+#![no_main]
+
+// Run with `cargo fuzz run read_frame` from the bevy_replicate directory.
+
+use bevy::prelude::*;
+use bevy_replicate::{
+    client::{ReplicateClientPlugin, SnapshotInterpolationBuffer},
+    network_frame,
+    networked_hierarchy::NetworkParent,
+    networked_transform::TransformNetworked,
+    BitReader, NetworkedFrame,
+};
+use libfuzzer_sys::fuzz_target;
+
+network_frame!(TransformNetworked, NetworkParent);
+
+fuzz_target!(|data: &[u8]| {
+    let mut app = App::new();
+    app.add_plugin(ReplicateClientPlugin::<NetworkFrame>::default());
+
+    let mut reader = match BitReader::new(data) {
+        Ok(reader) => reader,
+        Err(_) => return,
+    };
+
+    // The input is read as a sequence of frames, the frames read are the baselines of the next delta frames
+    let mut applied: Option<NetworkFrame> = None;
+    while let Ok(frame) = NetworkFrame::read_frame(&mut reader, &mut app.world) {
+        frame.apply_in_world(&mut app.world, applied.as_ref());
+        let mut interpolation_buffer = app.world.resource_mut::<SnapshotInterpolationBuffer<NetworkFrame>>();
+        interpolation_buffer.buffer.insert(frame.tick(), frame.clone());
+        applied = Some(frame);
+    }
+});
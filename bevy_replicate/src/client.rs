@@ -1,54 +1,220 @@
 use bevy::prelude::*;
 use bit_serializer::BitReader;
 
-use std::{collections::HashMap, io, marker::PhantomData, time::Duration};
-
-use crate::{sequence_buffer::SequenceBuffer, NetworkID, NetworkedFrame};
+use std::{collections::HashMap, marker::PhantomData, sync::Arc, time::Duration};
+
+use crate::{
+    compression::{decompress_frame, FrameCodec},
+    fragment::FragmentedFrames,
+    message::{ClientMessages, NetworkMessages},
+    network_entity::DEFAULT_ID_BITS,
+    networked_hierarchy::apply_network_parent_system,
+    ownership::update_locally_owned_system,
+    prediction::{adopt_predicted_spawn, read_prediction_reports, track_predicted_spawn_system, PredictedSpawns},
+    sequence_buffer::SequenceBuffer,
+    NetworkEntityMapper, NetworkID, NetworkedFrame, ReplicationError,
+};
 use iyes_loopless::prelude::*;
 
+/// Local entities of the networked entities, shared by the replication streams.
+/// An entity replicated by several streams is despawned once none of them has it.
 #[doc(hidden)]
-pub struct NetworkMapping(pub HashMap<NetworkID, Entity>);
+pub struct NetworkMapping {
+    id_bits: usize,
+    pub entities: HashMap<NetworkID, Entity>,
+    // Number of streams replicating each entity
+    streams: HashMap<NetworkID, usize>,
+}
+
+impl NetworkMapping {
+    pub fn new(id_bits: usize) -> Self {
+        Self {
+            id_bits,
+            entities: HashMap::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Widest NetworkID accepted from the server, frames with wider ids are rejected.
+    pub fn id_bits(&self) -> usize {
+        self.id_bits
+    }
+
+    // Streams with wider ids widen the ids accepted by every stream
+    fn accept_id_bits(&mut self, id_bits: usize) {
+        self.id_bits = self.id_bits.max(id_bits);
+    }
+
+    // A stream started replicating the entity
+    fn acquire(&mut self, world: &mut World, network_id: NetworkID) {
+        *self.streams.entry(network_id).or_default() += 1;
+        if !self.entities.contains_key(&network_id) {
+            let entity = match adopt_predicted_spawn(world, network_id) {
+                Some(entity) => entity,
+                None => world.spawn().insert(network_id).id(),
+            };
+            self.entities.insert(network_id, entity);
+        }
+    }
+
+    // A stream stopped replicating the entity
+    fn release(&mut self, world: &mut World, network_id: NetworkID) {
+        let streams = self.streams.remove(&network_id).unwrap_or(1).saturating_sub(1);
+        if streams > 0 {
+            self.streams.insert(network_id, streams);
+            return;
+        }
+
+        if let Some(entity) = self.entities.remove(&network_id) {
+            // The parent would keep the despawned entity in its children
+            let parent = world.get::<Parent>(entity).map(|parent| parent.get());
+            if let Some(mut parent) = parent.and_then(|parent| world.get_entity_mut(parent)) {
+                parent.remove_children(&[entity]);
+            }
+            world.despawn(entity);
+        }
+    }
+}
+
+/// On the client, entities carrying a NetworkID are mapped to the local entities.
+impl NetworkEntityMapper for NetworkMapping {
+    fn map(&self, entity: Entity) -> Option<Entity> {
+        self.entities.get(&NetworkID::from_entity(entity)).copied()
+    }
+}
+
+/// Client: progress, from 0 to 1, of the interpolation between the last two frames applied by the stream T.
+pub struct NetworkInterpolation<T>(pub f32, PhantomData<T>);
+
+impl<T> Default for NetworkInterpolation<T> {
+    fn default() -> Self {
+        Self(0., PhantomData)
+    }
+}
+
+/// Client: last tick received from the stream T, it should be sent to the server to acknowledge the frames.
+pub struct LastReceivedNetworkTick<T>(pub Option<u64>, PhantomData<T>);
+
+impl<T> Default for LastReceivedNetworkTick<T> {
+    fn default() -> Self {
+        Self(None, PhantomData)
+    }
+}
+
+/// Client: config of the replication stream of the frame type T.
+pub struct StreamConfig<T>(pub ReplicateClientConfig, PhantomData<T>);
 
-pub struct NetworkInterpolation(pub f32);
+impl<T> StreamConfig<T> {
+    pub fn new(config: ReplicateClientConfig) -> Self {
+        Self(config, PhantomData)
+    }
+}
 
-pub struct LastReceivedNetworkTick(pub Option<u64>);
+// Fragments received from the stream T, the ticks of the streams are unrelated
+struct StreamFragments<T>(FragmentedFrames, PhantomData<T>);
 
+impl<T> StreamFragments<T> {
+    fn new(timeout: Duration) -> Self {
+        Self(FragmentedFrames::new(timeout), PhantomData)
+    }
+}
+
+// The shared systems are added to the app once, by the first stream
+struct SharedClientSystems;
+
+// Streams that are active: the plugins without state and the state plugins that are in their state.
+// The resources shared by the streams are inserted when the first one becomes active and removed when the last one leaves its state.
+#[derive(Default)]
+struct ActiveStreams(usize);
+
+/// Several plugins with different frame types can be added, each one is an independent replication stream
+/// with its own received ticks and interpolation, see [`ReplicateServerPlugin`](crate::server::ReplicateServerPlugin).
 pub struct ReplicateClientPlugin<T> {
-    tick_rate: f64,
-    playout_delay: Duration,
-    buffer_size: usize,
+    config: ReplicateClientConfig,
     data: PhantomData<T>,
 }
 
 impl<T> Default for ReplicateClientPlugin<T> {
     fn default() -> Self {
         Self {
-            tick_rate: 20.,
-            playout_delay: Duration::from_millis(100),
-            buffer_size: 60,
+            config: ReplicateClientConfig {
+                playout_delay: Duration::from_millis(100),
+                ..Default::default()
+            },
             data: PhantomData,
         }
     }
 }
 
+impl<T> ReplicateClientPlugin<T> {
+    pub fn new(config: ReplicateClientConfig) -> Self {
+        Self { config, data: PhantomData }
+    }
+}
+
 impl<T: NetworkedFrame> Plugin for ReplicateClientPlugin<T> {
     fn build(&self, app: &mut App) {
         app.add_event::<T>();
-        app.insert_resource(LastReceivedNetworkTick(None));
-        app.insert_resource(NetworkMapping(HashMap::new()));
-        app.insert_resource(NetworkInterpolation(0.));
-
-        let interpolation_buffer = SnapshotInterpolationBuffer::<T>::new(self.buffer_size, self.playout_delay, self.tick_rate);
+        app.insert_resource(LastReceivedNetworkTick::<T>::default());
+        app.insert_resource(NetworkInterpolation::<T>::default());
+        app.insert_resource(StreamFragments::<T>::new(self.config.fragment_timeout));
+        app.insert_resource(StreamConfig::<T>::new(self.config.clone()));
+        app.world
+            .get_resource_or_insert_with(|| NetworkMapping::new(self.config.id_bits))
+            .accept_id_bits(self.config.id_bits);
+        app.init_resource::<PredictedSpawns>();
+        app.init_resource::<ClientMessages>();
+        app.init_resource::<NetworkMessages>();
+
+        let interpolation_buffer =
+            SnapshotInterpolationBuffer::<T>::new(self.config.buffer_size, self.config.playout_delay, self.config.tick_rate);
         app.insert_resource(interpolation_buffer);
         app.add_system_to_stage(CoreStage::PreUpdate, update_frame::<T>.exclusive_system().at_end());
+
+        // The stream is always active
+        app.world.get_resource_or_insert_with(ActiveStreams::default).0 += 1;
+        add_shared_systems(app);
+    }
+}
+
+// The systems shared by the streams are added once and run while any stream is active,
+// whatever the state of the stream that added them.
+fn add_shared_systems(app: &mut App) {
+    if !app.world.contains_resource::<SharedClientSystems>() {
+        app.insert_resource(SharedClientSystems);
+        app.add_system(apply_network_parent_system.run_if(any_stream_active));
+        app.add_system(track_predicted_spawn_system.run_if(any_stream_active));
+        app.add_system(update_locally_owned_system.run_if(any_stream_active));
     }
 }
 
-pub fn process_snapshot<T: NetworkedFrame>(buffer: Vec<u8>, world: &mut World) -> Result<(), io::Error> {
+fn any_stream_active(active_streams: Option<Res<ActiveStreams>>) -> bool {
+    active_streams.map_or(false, |active_streams| active_streams.0 > 0)
+}
+
+/// Process a fragment received from `replicate`, the frame is read once all of its fragments are received.
+/// Malformed fragments and frames are rejected with an error, they can be dropped and the next frames are still read.
+pub fn process_snapshot<T: NetworkedFrame>(fragment: Vec<u8>, world: &mut World) -> Result<(), ReplicationError> {
+    let current_time = world.resource::<Time>().time_since_startup();
+    let max_frame_size = world.resource::<StreamConfig<T>>().0.max_frame_size;
+    let buffer = {
+        let fragmented_frames = &mut world.resource_mut::<StreamFragments<T>>().0;
+        fragmented_frames.remove_expired(current_time);
+        match fragmented_frames.insert(current_time, &fragment, max_frame_size)? {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        }
+    };
+    let buffer = {
+        let config = &world.resource::<StreamConfig<T>>().0;
+        decompress_frame(&buffer, &config.codecs, config.max_frame_size)?
+    };
+
     let mut reader = BitReader::new(&buffer)?;
     let snapshot = T::read_frame(&mut reader, world)?;
+    read_prediction_reports(&mut reader, world, snapshot.tick())?;
 
-    let mut last_received_tick = world.resource_mut::<LastReceivedNetworkTick>();
+    let mut last_received_tick = world.resource_mut::<LastReceivedNetworkTick<T>>();
     match last_received_tick.0 {
         Some(tick) => {
             if snapshot.tick() > tick {
@@ -60,13 +226,30 @@ pub fn process_snapshot<T: NetworkedFrame>(buffer: Vec<u8>, world: &mut World) -
         }
     }
 
-    let current_time = world.resource::<Time>().time_since_startup();
     let mut interpolation_buffer = world.resource_mut::<SnapshotInterpolationBuffer<T>>();
     interpolation_buffer.add_snapshot(current_time, snapshot);
 
     Ok(())
 }
 
+/// Despawn the networked entities that left the stream since its previously applied frame and spawn the new ones,
+/// predicted entities confirmed by the server are used instead of spawning a new entity.
+/// Entities still replicated by other streams are not despawned.
+pub fn sync_network_entities(world: &mut World, mapping: &mut NetworkMapping, entities: &[NetworkID], previous_entities: &[NetworkID]) {
+    // Frames are sorted by NetworkID
+    for network_id in previous_entities.iter() {
+        if entities.binary_search(network_id).is_err() {
+            mapping.release(world, *network_id);
+        }
+    }
+
+    for network_id in entities.iter() {
+        if previous_entities.binary_search(network_id).is_err() {
+            mapping.acquire(world, *network_id);
+        }
+    }
+}
+
 #[doc(hidden)]
 #[derive(Debug)]
 pub struct SnapshotInterpolationBuffer<T> {
@@ -82,6 +265,8 @@ pub struct SnapshotInterpolationBuffer<T> {
     interpolation_end_time: Duration,
     tick_duration: Duration,
     pub buffer: SequenceBuffer<T>,
+    // Frame last applied in the world, the components missing from the next frame are removed
+    applied: Option<T>,
 }
 
 pub fn update_frame<T: NetworkedFrame>(world: &mut World) {
@@ -106,6 +291,7 @@ impl<T: NetworkedFrame> SnapshotInterpolationBuffer<T> {
             interpolation_end_time: Duration::ZERO,
             tick_duration: Duration::from_secs_f64(1. / send_rate),
             buffer: SequenceBuffer::with_capacity(buffer_capacity),
+            applied: None,
         }
     }
 
@@ -132,7 +318,8 @@ impl<T: NetworkedFrame> SnapshotInterpolationBuffer<T> {
         };
 
         let frames_since_start = time.mul_f64(self.tick_rate);
-        let interpolation_tick = frames_since_start.as_secs_f64().floor() as u64 + self.start_tick;
+        // The ticks come from the network, they can be anywhere in the u64 range
+        let interpolation_tick = self.start_tick.saturating_add(frames_since_start.as_secs_f64().floor() as u64);
         if self.interpolating {
             let n: u64 = (self.playout_delay.as_secs_f64() * self.tick_rate).floor() as u64;
 
@@ -168,11 +355,12 @@ impl<T: NetworkedFrame> SnapshotInterpolationBuffer<T> {
             self.interpolation_start_time = self.interpolation_end_time;
 
             for i in 1..=n {
-                let end_tick = self.interpolation_start_tick + i as u64;
+                let end_tick = self.interpolation_start_tick.saturating_add(i as u64);
                 if let Some(snapshot) = self.buffer.get(end_tick) {
                     self.interpolation_end_tick = end_tick;
                     self.interpolation_end_time = self.interpolation_start_time + (self.tick_duration * i as u32);
-                    snapshot.apply_in_world(world);
+                    snapshot.apply_in_world(world, self.applied.as_ref());
+                    self.applied = Some(snapshot.clone());
                     break;
                 }
             }
@@ -187,7 +375,7 @@ impl<T: NetworkedFrame> SnapshotInterpolationBuffer<T> {
         let fract = time.as_secs_f32() - self.interpolation_start_time.as_secs_f32();
         let whole = self.interpolation_end_time.as_secs_f32() - self.interpolation_start_time.as_secs_f32();
         let t = (fract / whole).clamp(0.0, 1.0);
-        let mut interpolation = world.resource_mut::<NetworkInterpolation>();
+        let mut interpolation = world.resource_mut::<NetworkInterpolation<T>>();
         interpolation.0 = t;
     }
 }
@@ -208,10 +396,20 @@ impl<T, S> Default for ReplicateClientStatePlugin<T, S> {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct ReplicateClientConfig {
     pub tick_rate: f64,
     pub playout_delay: Duration,
     pub buffer_size: usize,
+    /// Width in bits of the NetworkIDs accepted from the server, must be at least the server's id_bits.
+    pub id_bits: usize,
+    /// Time to wait for the missing fragments of a frame before dropping it.
+    pub fragment_timeout: Duration,
+    /// Codecs accepted for compressed frames, must include the codec used by the server.
+    pub codecs: Vec<Arc<dyn FrameCodec>>,
+    /// Maximum size in bytes of a decompressed frame, larger frames are rejected. The fragments of a frame
+    /// are dropped as soon as they exceed it, and the incomplete frames hold at most twice this size.
+    pub max_frame_size: usize,
 }
 
 impl Default for ReplicateClientConfig {
@@ -220,6 +418,10 @@ impl Default for ReplicateClientConfig {
             tick_rate: 20.,
             playout_delay: Duration::from_millis(50),
             buffer_size: 60,
+            id_bits: DEFAULT_ID_BITS,
+            fragment_timeout: Duration::from_millis(500),
+            codecs: Vec::new(),
+            max_frame_size: 1024 * 1024,
         }
     }
 }
@@ -234,28 +436,170 @@ impl<T: NetworkedFrame, S: bevy::ecs::schedule::StateData> ReplicateClientStateP
     }
 
     pub fn build(self, app: &mut App, state: S) {
-        app.insert_resource(self.config);
-        app.add_enter_system(state.clone(), resources_setup::<T>);
-        app.add_exit_system(state.clone(), resources_cleanup::<T>);
+        app.insert_resource(StreamConfig::<T>::new(self.config));
+        app.init_resource::<NetworkMessages>();
+        app.add_enter_system(state.clone(), resources_setup::<T>.exclusive_system());
+        app.add_exit_system(state.clone(), resources_cleanup::<T>.exclusive_system());
 
         app.add_system_to_stage(
             CoreStage::PreUpdate,
             iyes_loopless::condition::IntoConditionalExclusiveSystem::run_in_state(update_frame::<T>, state).at_end(),
         );
+
+        add_shared_systems(app);
     }
 }
 
-fn resources_setup<T: NetworkedFrame>(mut commands: Commands, config: Res<ReplicateClientConfig>) {
-    commands.insert_resource(LastReceivedNetworkTick(None));
-    commands.insert_resource(NetworkMapping(HashMap::new()));
-    commands.insert_resource(NetworkInterpolation(0.));
+fn resources_setup<T: NetworkedFrame>(world: &mut World) {
+    let config = world.resource::<StreamConfig<T>>().0.clone();
+    world.insert_resource(LastReceivedNetworkTick::<T>::default());
+    world.insert_resource(NetworkInterpolation::<T>::default());
+    world.insert_resource(StreamFragments::<T>::new(config.fragment_timeout));
     let interpolation_buffer = SnapshotInterpolationBuffer::<T>::new(config.buffer_size, config.playout_delay, config.tick_rate);
-    commands.insert_resource(interpolation_buffer);
+    world.insert_resource(interpolation_buffer);
+
+    let mut active_streams = world.get_resource_or_insert_with(ActiveStreams::default);
+    active_streams.0 += 1;
+    if active_streams.0 == 1 {
+        world.insert_resource(PredictedSpawns::default());
+        world.insert_resource(ClientMessages::default());
+    }
+    world
+        .get_resource_or_insert_with(|| NetworkMapping::new(config.id_bits))
+        .accept_id_bits(config.id_bits);
 }
 
-fn resources_cleanup<T: NetworkedFrame>(mut commands: Commands) {
-    commands.remove_resource::<LastReceivedNetworkTick>();
-    commands.remove_resource::<NetworkMapping>();
-    commands.remove_resource::<NetworkInterpolation>();
-    commands.remove_resource::<SnapshotInterpolationBuffer<T>>();
+fn resources_cleanup<T: NetworkedFrame>(world: &mut World) {
+    world.remove_resource::<LastReceivedNetworkTick<T>>();
+    world.remove_resource::<NetworkInterpolation<T>>();
+    world.remove_resource::<StreamFragments<T>>();
+    // The stream stops replicating the entities of its last applied frame,
+    // they are despawned unless other streams still replicate them
+    let applied = world
+        .remove_resource::<SnapshotInterpolationBuffer<T>>()
+        .and_then(|interpolation_buffer| interpolation_buffer.applied);
+    if let Some(applied) = applied {
+        world.resource_scope(|world, mut mapping: Mut<NetworkMapping>| {
+            sync_network_entities(world, &mut mapping, &[], applied.entities());
+        });
+    }
+
+    let mut active_streams = world.get_resource_or_insert_with(ActiveStreams::default);
+    active_streams.0 = active_streams.0.saturating_sub(1);
+    if active_streams.0 == 0 {
+        world.remove_resource::<NetworkMapping>();
+        world.remove_resource::<PredictedSpawns>();
+        world.remove_resource::<ClientMessages>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dynamic_frame::DynamicFrame;
+    use crate::ownership::{IsLocallyOwned, LocalClientId, NetworkOwner};
+    use crate::test_utils::{NetworkFrame, Simple};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum FirstStream {
+        Off,
+        On,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum SecondStream {
+        Off,
+        On,
+    }
+
+    #[test]
+    fn test_replication_streams() {
+        // The clients accept the widest ids of the streams
+        let mut client = App::new();
+        client.add_plugin(ReplicateClientPlugin::<NetworkFrame>::default());
+        client.add_plugin(ReplicateClientPlugin::<DynamicFrame>::new(ReplicateClientConfig {
+            id_bits: 13,
+            ..Default::default()
+        }));
+        assert_eq!(client.world.resource::<NetworkMapping>().id_bits(), 13);
+
+        // Entities replicated by several streams are despawned once none of them has it
+        let (first, second) = (NetworkID::new(1, 0), NetworkID::new(2, 0));
+        let mut mapping = NetworkMapping::new(DEFAULT_ID_BITS);
+        let world = &mut client.world;
+        sync_network_entities(world, &mut mapping, &[first, second], &[]);
+        sync_network_entities(world, &mut mapping, &[second], &[]);
+        let entity = mapping.entities[&second];
+
+        sync_network_entities(world, &mut mapping, &[first], &[first, second]);
+        assert!(world.get_entity(entity).is_some());
+        sync_network_entities(world, &mut mapping, &[], &[second]);
+        assert!(world.get_entity(entity).is_none());
+        assert!(!mapping.entities.contains_key(&second));
+        assert!(mapping.entities.contains_key(&first));
+
+        // A despawned child is removed from the children of its parent
+        let child_id = NetworkID::new(3, 0);
+        sync_network_entities(world, &mut mapping, &[first, child_id], &[first]);
+        let (parent, child) = (mapping.entities[&first], mapping.entities[&child_id]);
+        world.entity_mut(parent).push_children(&[child]);
+        sync_network_entities(world, &mut mapping, &[first], &[first, child_id]);
+        assert!(world.get_entity(child).is_none());
+        assert!(world.get::<Children>(parent).map_or(true, |children| !children.contains(&child)));
+    }
+
+    #[test]
+    fn test_stream_states() {
+        let mut client = App::new();
+        client.init_resource::<Time>();
+        client.add_loopless_state(FirstStream::Off);
+        client.add_loopless_state(SecondStream::Off);
+        ReplicateClientStatePlugin::<NetworkFrame, FirstStream>::default().build(&mut client, FirstStream::On);
+        ReplicateClientStatePlugin::<DynamicFrame, SecondStream>::default().build(&mut client, SecondStream::On);
+        client.insert_resource(NextState(FirstStream::On));
+        client.insert_resource(NextState(SecondStream::On));
+        client.update();
+
+        // Both streams replicate the shared entity, only the first one the other entity
+        let (shared, only_first) = (NetworkID::new(0, 0), NetworkID::new(1, 0));
+        let first_frame = NetworkFrame::new(0, vec![shared, only_first], vec![Some(Simple(0)), Some(Simple(1))]);
+        let mut server_world = World::new();
+        server_world.spawn().insert(shared);
+        let second_frame = <DynamicFrame>::generate_frame(0, &mut server_world);
+        client
+            .world
+            .resource_scope(|world, mut buffer: Mut<SnapshotInterpolationBuffer<NetworkFrame>>| {
+                first_frame.apply_in_world(world, None);
+                buffer.applied = Some(first_frame);
+            });
+        client
+            .world
+            .resource_scope(|world, mut buffer: Mut<SnapshotInterpolationBuffer<DynamicFrame>>| {
+                second_frame.apply_in_world(world, None);
+                buffer.applied = Some(second_frame);
+            });
+        let mapping = client.world.resource::<NetworkMapping>();
+        let (shared_entity, first_entity) = (mapping.entities[&shared], mapping.entities[&only_first]);
+
+        // The entities of a stream leaving its state are released, the ones still replicated by other streams are kept
+        client.insert_resource(NextState(FirstStream::Off));
+        client.update();
+        assert!(client.world.get_entity(first_entity).is_none());
+        assert!(client.world.get_entity(shared_entity).is_some());
+        let mapping = client.world.resource::<NetworkMapping>();
+        assert_eq!(mapping.entities.get(&shared), Some(&shared_entity));
+        assert!(!mapping.entities.contains_key(&only_first));
+
+        // The shared systems keep running while any stream is active
+        client.insert_resource(LocalClientId(1));
+        client.world.entity_mut(shared_entity).insert(NetworkOwner(1));
+        client.update();
+        assert!(client.world.get::<IsLocallyOwned>(shared_entity).is_some());
+
+        client.insert_resource(NextState(SecondStream::Off));
+        client.update();
+        assert!(client.world.get_entity(shared_entity).is_none());
+        assert!(!client.world.contains_resource::<NetworkMapping>());
+    }
 }
@@ -0,0 +1,241 @@
+use std::{fmt::Debug, io, sync::Arc};
+
+use crate::ReplicationError;
+
+/// Flag written before frames that are not compressed, codecs use any other value.
+pub const UNCOMPRESSED: u8 = 0;
+
+/// Compression algorithm applied to the encoded frames, see [`ReplicateServerConfig::compression`](crate::server::ReplicateServerConfig::compression).
+/// The client must have a codec with the same id to read the frames compressed by the server,
+/// frames compressed with a codec the client doesn't have are rejected with [`ReplicationError::UnknownCodec`].
+pub trait FrameCodec: Debug + Send + Sync + 'static {
+    /// Flag written before the frames compressed by this codec, can't be [`UNCOMPRESSED`].
+    fn id(&self) -> u8;
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, io::Error>;
+
+    /// Decompress a frame, fails if the decompressed frame is larger than `max_size` bytes.
+    fn decompress(&self, compressed: &[u8], max_size: usize) -> Result<Vec<u8>, io::Error>;
+}
+
+/// Prefix the encoded frame with the compression flag, the frame is only compressed when it saves bytes.
+/// The flag is also written without codec, so a client with codecs can always read the frame.
+pub fn compress_frame(payload: &[u8], codec: Option<&dyn FrameCodec>) -> Result<Vec<u8>, io::Error> {
+    if let Some(codec) = codec {
+        if codec.id() == UNCOMPRESSED {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "codec id is the uncompressed flag"));
+        }
+
+        let compressed = codec.compress(payload)?;
+        if compressed.len() < payload.len() {
+            let mut frame = Vec::with_capacity(compressed.len() + 1);
+            frame.push(codec.id());
+            frame.extend_from_slice(&compressed);
+            return Ok(frame);
+        }
+    }
+
+    let mut frame = Vec::with_capacity(payload.len() + 1);
+    frame.push(UNCOMPRESSED);
+    frame.extend_from_slice(payload);
+
+    Ok(frame)
+}
+
+/// Read the compression flag and decompress the frame with the matching codec.
+/// Frames larger than `max_size` bytes once decompressed are rejected.
+pub fn decompress_frame(frame: &[u8], codecs: &[Arc<dyn FrameCodec>], max_size: usize) -> Result<Vec<u8>, ReplicationError> {
+    let (flag, payload) = match frame.split_first() {
+        Some(split) => split,
+        None => return Err(ReplicationError::Truncated),
+    };
+
+    if *flag == UNCOMPRESSED {
+        if payload.len() > max_size {
+            return Err(ReplicationError::InvalidData("frame larger than max frame size"));
+        }
+        return Ok(payload.to_vec());
+    }
+
+    match codecs.iter().find(|codec| codec.id() == *flag) {
+        Some(codec) => Ok(codec.decompress(payload, max_size)?),
+        None => Err(ReplicationError::UnknownCodec(*flag)),
+    }
+}
+
+/// LZ4 block compression, fast with a lower ratio.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lz4Codec;
+
+#[cfg(feature = "lz4")]
+impl FrameCodec for Lz4Codec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, io::Error> {
+        Ok(lz4_flex::compress_prepend_size(payload))
+    }
+
+    fn decompress(&self, compressed: &[u8], max_size: usize) -> Result<Vec<u8>, io::Error> {
+        // The size is prepended by the sender, check it before allocating
+        if compressed.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "lz4 frame without size"));
+        }
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&compressed[..4]);
+        if u32::from_le_bytes(size) as usize > max_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame larger than max frame size"));
+        }
+
+        lz4_flex::decompress_size_prepended(compressed).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Zstandard compression, optionally with a dictionary trained on recorded frames,
+/// which works better for the small frames sent every tick.
+/// The server and the client must use the same dictionary, it is prepared once when the codec is created.
+#[cfg(feature = "zstd")]
+#[derive(Clone)]
+pub struct ZstdCodec {
+    level: i32,
+    dictionaries: Option<Arc<ZstdDictionaries>>,
+}
+
+#[cfg(feature = "zstd")]
+struct ZstdDictionaries {
+    encoder: zstd::dict::EncoderDictionary<'static>,
+    decoder: zstd::dict::DecoderDictionary<'static>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdCodec {
+    pub fn new(level: i32) -> Self {
+        Self { level, dictionaries: None }
+    }
+
+    pub fn with_dictionary(level: i32, dictionary: Vec<u8>) -> Self {
+        let dictionaries = ZstdDictionaries {
+            encoder: zstd::dict::EncoderDictionary::copy(&dictionary, level),
+            decoder: zstd::dict::DecoderDictionary::copy(&dictionary),
+        };
+
+        Self {
+            level,
+            dictionaries: Some(Arc::new(dictionaries)),
+        }
+    }
+
+    /// Train a dictionary from sample frames, e.g. recorded from `replicate` before compression.
+    pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, io::Error> {
+        zstd::dict::from_samples(samples, max_size)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Debug for ZstdCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZstdCodec")
+            .field("level", &self.level)
+            .field("dictionary", &self.dictionaries.is_some())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl FrameCodec for ZstdCodec {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let mut compressor = match &self.dictionaries {
+            Some(dictionaries) => zstd::bulk::Compressor::with_prepared_dictionary(&dictionaries.encoder)?,
+            None => zstd::bulk::Compressor::new(self.level)?,
+        };
+        compressor.compress(payload)
+    }
+
+    fn decompress(&self, compressed: &[u8], max_size: usize) -> Result<Vec<u8>, io::Error> {
+        // Fails instead of growing the output past the capacity
+        let mut decompressor = match &self.dictionaries {
+            Some(dictionaries) => zstd::bulk::Decompressor::with_prepared_dictionary(&dictionaries.decoder)?,
+            None => zstd::bulk::Decompressor::new()?,
+        };
+        decompressor.decompress(compressed, max_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run-length encoding, as (count, byte) pairs.
+    #[derive(Debug)]
+    struct RunLength;
+
+    impl FrameCodec for RunLength {
+        fn id(&self) -> u8 {
+            7
+        }
+
+        fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, io::Error> {
+            let mut compressed: Vec<u8> = vec![];
+            for byte in payload {
+                match compressed.len() {
+                    len if len >= 2 && compressed[len - 1] == *byte && compressed[len - 2] < u8::MAX => compressed[len - 2] += 1,
+                    _ => compressed.extend_from_slice(&[1, *byte]),
+                }
+            }
+
+            Ok(compressed)
+        }
+
+        fn decompress(&self, compressed: &[u8], max_size: usize) -> Result<Vec<u8>, io::Error> {
+            let mut payload = vec![];
+            for run in compressed.chunks_exact(2) {
+                if payload.len() + run[0] as usize > max_size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "frame larger than max frame size"));
+                }
+                payload.extend(std::iter::repeat(run[1]).take(run[0] as usize));
+            }
+
+            Ok(payload)
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let codecs: Vec<Arc<dyn FrameCodec>> = vec![Arc::new(RunLength)];
+        let payload = vec![0u8; 100];
+
+        // 1 byte flag + 2 bytes run
+        let frame = compress_frame(&payload, Some(&RunLength)).unwrap();
+        assert_eq!(frame, vec![7, 100, 0]);
+        assert_eq!(decompress_frame(&frame, &codecs, 100).unwrap(), payload);
+        assert!(decompress_frame(&frame, &codecs, 99).is_err());
+        assert!(matches!(
+            decompress_frame(&[9, 100, 0], &codecs, 100),
+            Err(ReplicationError::UnknownCodec(9))
+        ));
+
+        // Frames are sent uncompressed when compressing doesn't save bytes
+        let payload: Vec<u8> = (0..10).collect();
+        let frame = compress_frame(&payload, Some(&RunLength)).unwrap();
+        assert_eq!(frame[0], UNCOMPRESSED);
+        assert_eq!(decompress_frame(&frame, &codecs, 10).unwrap(), payload);
+
+        // The flag is written without codec, the frames are read whether the client has codecs or not
+        let frame = compress_frame(&payload, None).unwrap();
+        assert_eq!(frame[0], UNCOMPRESSED);
+        assert_eq!(decompress_frame(&frame, &[], 10).unwrap(), payload);
+        assert_eq!(decompress_frame(&frame, &codecs, 10).unwrap(), payload);
+        assert!(decompress_frame(&frame, &[], 9).is_err());
+        assert!(matches!(decompress_frame(&[], &[], 10), Err(ReplicationError::Truncated)));
+
+        // A frame compressed with a codec the client doesn't have is rejected instead of misread
+        let frame = compress_frame(&[0u8; 100], Some(&RunLength)).unwrap();
+        assert!(matches!(decompress_frame(&frame, &[], 100), Err(ReplicationError::UnknownCodec(7))));
+    }
+}
@@ -0,0 +1,489 @@
+use crate::{
+    component_changes, generate_delta_mapping, priority::frame_bits, resource_change, ComponentChange, NetworkID, NetworkedComponent,
+    NetworkedFrame, NetworkedResource,
+};
+
+use bit_serializer::BitWriter;
+use std::{
+    any::type_name,
+    fmt::{self, Write},
+    io,
+    sync::Arc,
+};
+
+/// Debug view of a frame: its entities, the value of each component and the change written for it against
+/// the baseline frame, see [`NetworkedFrame::dump`].
+/// Displayed as a table with one row for each component, or as JSON with [`FrameDump::to_json`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameDump {
+    pub tick: u64,
+    /// Tick of the baseline frame, `None` when dumped as a full frame.
+    pub baseline_tick: Option<u64>,
+    /// Size of the encoded frame.
+    pub bits: usize,
+    pub entities: Vec<EntityDump>,
+    pub resources: Vec<ValueDump>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityDump {
+    pub network_id: NetworkID,
+    /// A value for each replicated component, in the order of the frame columns.
+    pub components: Vec<ValueDump>,
+}
+
+/// Component or resource in the frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueDump {
+    pub name: String,
+    /// Debug output of the value, `None` when it's missing from the frame.
+    pub value: Option<String>,
+    /// Change written for the value, full frames only use FullChange and NoComponent.
+    pub change: ComponentChange,
+    /// Bits written for the value, without its change code.
+    pub bits: usize,
+}
+
+// Bits written by the encoding of a single value.
+fn value_bits(write: impl FnOnce(&mut BitWriter) -> Result<(), io::Error>) -> Result<usize, io::Error> {
+    let mut writer = BitWriter::with_capacity(64);
+    write(&mut writer)?;
+
+    Ok(writer.bits_written())
+}
+
+// Full frames write every component present with a full write.
+fn full_change<C>(value: &Option<C>) -> ComponentChange {
+    match value {
+        Some(_) => ComponentChange::FullChange,
+        None => ComponentChange::NoComponent,
+    }
+}
+
+/// Type name without the module paths, `bevy_replicate::SerdeNetworked<game::Name>` is `SerdeNetworked<Name>`.
+fn short_type_name(name: &str) -> String {
+    let mut short = String::with_capacity(name.len());
+    let mut path_start = 0;
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            short.truncate(path_start);
+        } else {
+            short.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                path_start = short.len();
+            }
+        }
+    }
+
+    short
+}
+
+impl FrameDump {
+    /// Dump with the entities of the frame, the implementations of [`NetworkedFrame::dump`]
+    /// add their columns with [`FrameDump::add_components`] and [`FrameDump::add_resource`].
+    pub fn new<T: NetworkedFrame>(frame: &T, baseline: Option<&T>) -> Result<Self, io::Error> {
+        Ok(Self {
+            tick: frame.tick(),
+            baseline_tick: baseline.map(|baseline| baseline.tick()),
+            bits: frame_bits(frame, baseline)?,
+            entities: frame
+                .entities()
+                .iter()
+                .map(|network_id| EntityDump {
+                    network_id: *network_id,
+                    components: Vec::new(),
+                })
+                .collect(),
+            resources: Vec::new(),
+        })
+    }
+
+    /// Add a column of the frame, the baseline has the entities and the components of the baseline frame.
+    /// The column is named after the type of its NetworkedComponent, without the module path.
+    pub fn add_components<T: NetworkedComponent>(
+        &mut self,
+        components: &[Option<Arc<T::Component>>],
+        baseline: Option<(&[NetworkID], &[Option<Arc<T::Component>>])>,
+    ) -> Result<(), io::Error> {
+        let name = short_type_name(type_name::<T>());
+        let entities: Vec<NetworkID> = self.entities.iter().map(|entity| entity.network_id).collect();
+        let (changes, previous_components): (Vec<ComponentChange>, Vec<Option<&Arc<T::Component>>>) = match baseline {
+            Some((baseline_entities, baseline_components)) => {
+                let delta_mapping = generate_delta_mapping(baseline_entities, &entities);
+                let changes = component_changes::<T>(&entities, components, baseline_components, &delta_mapping);
+                let previous_components = entities
+                    .iter()
+                    .map(|network_id| delta_mapping.get(network_id).and_then(|index| baseline_components[*index].as_ref()))
+                    .collect();
+                (changes, previous_components)
+            }
+            None => (components.iter().map(full_change).collect(), vec![None; entities.len()]),
+        };
+
+        for (i, entity) in self.entities.iter_mut().enumerate() {
+            let bits = match (changes[i], &components[i], previous_components[i]) {
+                (ComponentChange::FullChange, Some(component), _) => value_bits(|writer| T::write_full(component, writer))?,
+                (ComponentChange::DeltaChange, Some(component), Some(previous)) => {
+                    value_bits(|writer| T::write_delta(previous, component, writer))?
+                }
+                _ => 0,
+            };
+
+            entity.components.push(ValueDump {
+                name: name.clone(),
+                value: components[i].as_ref().map(|component| format!("{:?}", component)),
+                change: changes[i],
+                bits,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add a resource of the frame, the baseline is the resource in the baseline frame.
+    /// The resource is named after the type of its NetworkedResource, without the module path.
+    pub fn add_resource<T: NetworkedResource>(
+        &mut self,
+        resource: &Option<Arc<T::Resource>>,
+        baseline: Option<&Option<Arc<T::Resource>>>,
+    ) -> Result<(), io::Error> {
+        let change = match baseline {
+            Some(previous) => resource_change::<T>(resource, previous),
+            None => full_change(resource),
+        };
+        let bits = match (change, resource, baseline) {
+            (ComponentChange::FullChange, Some(resource), _) => value_bits(|writer| T::write_full(resource, writer))?,
+            (ComponentChange::DeltaChange, Some(resource), Some(Some(previous))) => {
+                value_bits(|writer| T::write_delta(previous, resource, writer))?
+            }
+            _ => 0,
+        };
+
+        self.resources.push(ValueDump {
+            name: short_type_name(type_name::<T>()),
+            value: resource.as_ref().map(|resource| format!("{:?}", resource)),
+            change,
+            bits,
+        });
+
+        Ok(())
+    }
+
+    /// Differences between the values of this frame and a newer one.
+    /// Values are compared by their debug output, the components of spawned entities are included as changes.
+    pub fn diff(&self, new: &FrameDump) -> FrameDiff {
+        let mut diff = FrameDiff {
+            from_tick: self.tick,
+            to_tick: new.tick,
+            spawned: Vec::new(),
+            despawned: Vec::new(),
+            changed: Vec::new(),
+        };
+
+        // Frames are sorted by NetworkID
+        let mut old_entities = self.entities.iter().peekable();
+        for entity in new.entities.iter() {
+            while let Some(old_entity) = old_entities.next_if(|old| old.network_id < entity.network_id) {
+                diff.despawned.push(old_entity.network_id);
+            }
+
+            let old_components = match old_entities.next_if(|old| old.network_id == entity.network_id) {
+                Some(old_entity) => &old_entity.components[..],
+                None => {
+                    diff.spawned.push(entity.network_id);
+                    &[]
+                }
+            };
+            diff_values(&mut diff.changed, Some(entity.network_id), old_components, &entity.components);
+        }
+        diff.despawned.extend(old_entities.map(|old| old.network_id));
+
+        diff_values(&mut diff.changed, None, &self.resources, &new.resources);
+
+        diff
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(json, "{{\"tick\":{},\"baseline_tick\":", self.tick).unwrap();
+        match self.baseline_tick {
+            Some(tick) => write!(json, "{}", tick).unwrap(),
+            None => json.push_str("null"),
+        }
+        write!(json, ",\"bits\":{},\"entities\":[", self.bits).unwrap();
+        for (i, entity) in self.entities.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"network_id\":{{\"index\":{},\"generation\":{}}},\"components\":",
+                entity.network_id.index, entity.network_id.generation
+            )
+            .unwrap();
+            write_json_values(&mut json, &entity.components);
+            json.push('}');
+        }
+        json.push_str("],\"resources\":");
+        write_json_values(&mut json, &self.resources);
+        json.push('}');
+
+        json
+    }
+}
+
+// Both dumps have their values in the order of the frame columns, short names of different types can be the same.
+fn diff_values(changed: &mut Vec<ValueDiff>, network_id: Option<NetworkID>, old: &[ValueDump], new: &[ValueDump]) {
+    for (i, value) in new.iter().enumerate() {
+        let old = old.get(i).and_then(|value| value.value.clone());
+        if old != value.value {
+            changed.push(ValueDiff {
+                network_id,
+                name: value.name.clone(),
+                old,
+                new: value.value.clone(),
+            });
+        }
+    }
+}
+
+fn write_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+fn write_json_values(json: &mut String, values: &[ValueDump]) {
+    json.push('[');
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"name\":");
+        write_json_string(json, &value.name);
+        json.push_str(",\"value\":");
+        match &value.value {
+            Some(debug) => write_json_string(json, debug),
+            None => json.push_str("null"),
+        }
+        write!(json, ",\"change\":\"{:?}\",\"bits\":{}}}", value.change, value.bits).unwrap();
+    }
+    json.push(']');
+}
+
+impl fmt::Display for FrameDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.baseline_tick {
+            Some(baseline_tick) => writeln!(f, "frame {} against {}, {} bits", self.tick, baseline_tick, self.bits)?,
+            None => writeln!(f, "frame {}, {} bits", self.tick, self.bits)?,
+        }
+
+        let mut rows = vec![["entity", "component", "change", "bits", "value"].map(String::from)];
+        let entity_values = self
+            .entities
+            .iter()
+            .flat_map(|entity| entity.components.iter().map(move |value| (entity.network_id.to_string(), value)));
+        let resource_values = self.resources.iter().map(|value| ("-".to_string(), value));
+        for (entity, value) in entity_values.chain(resource_values) {
+            rows.push([
+                entity,
+                value.name.clone(),
+                format!("{:?}", value.change),
+                value.bits.to_string(),
+                value.value.clone().unwrap_or_else(|| "-".to_string()),
+            ]);
+        }
+
+        // The value is the last column, it's not padded
+        let mut widths = [0; 4];
+        for row in rows.iter() {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        for row in rows.iter() {
+            writeln!(
+                f,
+                "{:<w0$}  {:<w1$}  {:<w2$}  {:>w3$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                row[4],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3]
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Differences between two frames, see [`diff_frames`]. Displayed with one line for each difference:
+/// `+` for spawned entities, `-` for despawned entities and `~` for changed values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameDiff {
+    pub from_tick: u64,
+    pub to_tick: u64,
+    pub spawned: Vec<NetworkID>,
+    pub despawned: Vec<NetworkID>,
+    pub changed: Vec<ValueDiff>,
+}
+
+/// Component or resource with a different value in the frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueDiff {
+    /// Entity of the component, `None` for resources.
+    pub network_id: Option<NetworkID>,
+    pub name: String,
+    /// Debug output of the values, `None` when it's missing from that frame.
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl FrameDiff {
+    pub fn is_empty(&self) -> bool {
+        self.spawned.is_empty() && self.despawned.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for FrameDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "frame {} -> {}", self.from_tick, self.to_tick)?;
+        for network_id in self.spawned.iter() {
+            writeln!(f, "+ {}", network_id)?;
+        }
+        for network_id in self.despawned.iter() {
+            writeln!(f, "- {}", network_id)?;
+        }
+        for value in self.changed.iter() {
+            let old = value.old.as_deref().unwrap_or("-");
+            let new = value.new.as_deref().unwrap_or("-");
+            match value.network_id {
+                Some(network_id) => writeln!(f, "~ {} {}: {} -> {}", network_id, value.name, old, new)?,
+                None => writeln!(f, "~ {}: {} -> {}", value.name, old, new)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Differences between the values of two frames, e.g. a frame generated by the server and the frame read by a client.
+pub fn diff_frames<T: NetworkedFrame>(old: &T, new: &T) -> Result<FrameDiff, io::Error> {
+    Ok(old.dump(None)?.diff(&new.dump(None)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{NetworkFrame, Simple};
+
+    use super::*;
+
+    #[test]
+    fn test_frame_dump() {
+        let first_frame = NetworkFrame::new(
+            1,
+            vec![NetworkID::new(0, 0), NetworkID::new(1, 0), NetworkID::new(2, 0)],
+            vec![Some(Simple(10)), Some(Simple(0)), Some(Simple(5))],
+        );
+        let second_frame = NetworkFrame::new(
+            2,
+            vec![NetworkID::new(0, 0), NetworkID::new(1, 0), NetworkID::new(3, 0)],
+            vec![Some(Simple(16)), Some(Simple(100)), None],
+        );
+
+        let dump = second_frame.dump(Some(&first_frame)).unwrap();
+        let mut writer = BitWriter::with_capacity(100);
+        second_frame.write_delta_frame(&mut writer, &first_frame).unwrap();
+        assert_eq!(dump.baseline_tick, Some(1));
+        assert_eq!(dump.bits, writer.bits_written());
+
+        let simple: Vec<(ComponentChange, usize)> = dump
+            .entities
+            .iter()
+            .map(|entity| (entity.components[0].change, entity.components[0].bits))
+            .collect();
+        assert_eq!(
+            simple,
+            vec![
+                (ComponentChange::DeltaChange, 6),
+                (ComponentChange::FullChange, 32),
+                (ComponentChange::NoComponent, 0)
+            ]
+        );
+
+        let table = dump.to_string();
+        assert!(table
+            .lines()
+            .any(|line| line.split_whitespace().eq(["0v0", "Simple", "DeltaChange", "6", "Simple(16)"])));
+        assert!(dump.to_json().starts_with(r#"{"tick":2,"baseline_tick":1,"#));
+        assert!(dump
+            .to_json()
+            .contains(r#"{"name":"Simple","value":"Simple(16)","change":"DeltaChange","bits":6}"#));
+
+        let diff = diff_frames(&first_frame, &second_frame).unwrap();
+        assert_eq!(diff.spawned, vec![NetworkID::new(3, 0)]);
+        assert_eq!(diff.despawned, vec![NetworkID::new(2, 0)]);
+        assert_eq!(
+            diff.to_string(),
+            "frame 1 -> 2\n+ 3v0\n- 2v0\n~ 0v0 Simple: Simple(10) -> Simple(16)\n~ 1v0 Simple: Simple(0) -> Simple(100)\n"
+        );
+        assert!(diff_frames(&second_frame, &second_frame).unwrap().is_empty());
+
+        // Values are named after their type without the module paths
+        assert_eq!(
+            short_type_name("bevy_replicate::SerdeNetworked<game::Name>"),
+            "SerdeNetworked<Name>"
+        );
+    }
+
+    #[test]
+    fn test_diff_same_names() {
+        let value = |name: &str, value: &str| ValueDump {
+            name: name.to_string(),
+            value: Some(value.to_string()),
+            change: ComponentChange::FullChange,
+            bits: 0,
+        };
+        let dump = |tick, components| FrameDump {
+            tick,
+            baseline_tick: None,
+            bits: 0,
+            entities: vec![EntityDump {
+                network_id: NetworkID::new(0, 0),
+                components,
+            }],
+            resources: Vec::new(),
+        };
+
+        // Two columns of types from different modules with the same short name
+        let first = dump(1, vec![value("Name", "Name(\"a\")"), value("Name", "Name(1)")]);
+        let second = dump(2, vec![value("Name", "Name(\"a\")"), value("Name", "Name(2)")]);
+        assert_eq!(
+            first.diff(&second).changed,
+            vec![ValueDiff {
+                network_id: Some(NetworkID::new(0, 0)),
+                name: "Name".to_string(),
+                old: Some("Name(1)".to_string()),
+                new: Some("Name(2)".to_string()),
+            }]
+        );
+        assert!(first.diff(&first).is_empty());
+    }
+}
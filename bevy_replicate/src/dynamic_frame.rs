@@ -0,0 +1,671 @@
+use crate::{
+    apply_components,
+    client::{sync_network_entities, NetworkMapping, SnapshotInterpolationBuffer},
+    component_bits, despawned_entities,
+    dump::FrameDump,
+    generate_delta_mapping, hides_component,
+    network_frame::changed_component,
+    read_delta_component, read_delta_entities, read_frame_header, read_full_component, read_full_entities, spawned_entity_bits,
+    visibility::{hide_components, VisibilityContext},
+    write_delta_component, write_delta_entities, write_frame_header, write_full_component, write_full_entities, NetworkID,
+    NetworkedComponent, NetworkedFrame, ReplicationError,
+};
+
+use bevy::prelude::*;
+use bit_serializer::{BitReader, BitWriter};
+use std::{
+    any::{Any, TypeId},
+    collections::{HashMap, HashSet},
+    fmt, io,
+    marker::PhantomData,
+    sync::Arc,
+};
+
+pub trait ReplicateAppExt {
+    /// Add the component to the [`DynamicFrame`], components must be registered in the same order
+    /// in the server and the clients.
+    fn replicate<T: NetworkedComponent + 'static>(&mut self) -> &mut Self;
+    /// Add the component to the `DynamicFrame<L>`, each label has its own registry and frames,
+    /// e.g. to replicate other components in a second stream.
+    fn replicate_labeled<L: Send + Sync + 'static, T: NetworkedComponent + 'static>(&mut self) -> &mut Self;
+}
+
+impl ReplicateAppExt for App {
+    fn replicate<T: NetworkedComponent + 'static>(&mut self) -> &mut Self {
+        self.replicate_labeled::<(), T>()
+    }
+
+    fn replicate_labeled<L: Send + Sync + 'static, T: NetworkedComponent + 'static>(&mut self) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(ReplicationRegistry::<L>::default)
+            .register::<T>();
+
+        self
+    }
+}
+
+/// Components replicated by the `DynamicFrame<L>`, in the order they were registered.
+pub struct ReplicationRegistry<L = ()> {
+    types: HashSet<TypeId>,
+    columns: Vec<Box<dyn FrameColumn>>,
+    label: PhantomData<L>,
+}
+
+impl<L> Default for ReplicationRegistry<L> {
+    fn default() -> Self {
+        Self {
+            types: HashSet::new(),
+            columns: Vec::new(),
+            label: PhantomData,
+        }
+    }
+}
+
+impl<L> fmt::Debug for ReplicationRegistry<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplicationRegistry").field("columns", &self.columns).finish()
+    }
+}
+
+impl<L> ReplicationRegistry<L> {
+    pub fn register<T: NetworkedComponent + 'static>(&mut self) {
+        // Registering the same component twice is ignored, so plugins can register the components they need
+        if self.types.insert(TypeId::of::<T>()) {
+            self.columns.push(Box::new(Column::<T>(Vec::new())));
+        }
+    }
+}
+
+// The previous frame generated, the components that didn't change since share its values.
+struct DynamicFrameGenerator<L> {
+    previous: Option<DynamicFrame<L>>,
+    // Change tick of the world when the previous frame was generated
+    last_change_tick: u32,
+    // Entities with components that kept their previous value because they couldn't be mapped,
+    // they are mapped again even if they didn't change
+    unmapped: HashSet<NetworkID>,
+}
+
+impl<L> Default for DynamicFrameGenerator<L> {
+    fn default() -> Self {
+        Self {
+            previous: None,
+            last_change_tick: 0,
+            unmapped: HashSet::new(),
+        }
+    }
+}
+
+// Rows of the frame being generated, shared by its columns.
+struct GeneratedRows<'a> {
+    entities: &'a [NetworkID],
+    rows: &'a [Entity],
+    previous_rows: &'a [Option<usize>],
+    despawned: &'a [NetworkID],
+    previously_unmapped: &'a HashSet<NetworkID>,
+    last_change_tick: u32,
+    change_tick: u32,
+}
+
+/// Frame with the components registered at runtime with [`ReplicateAppExt::replicate`],
+/// an alternative to the struct generated by [`network_frame!`](crate::network_frame).
+/// Use it as any other frame: `ReplicateServerPlugin::<DynamicFrame>`, `replicate::<DynamicFrame>`, ...
+/// The label `L` separates frames with different components,
+/// see [`ReplicateAppExt::replicate_labeled`].
+/// Resources are only replicated by the frames generated with the macro.
+/// Register [`NetworkOwner`](crate::NetworkOwner) to replicate the owner of the entities.
+pub struct DynamicFrame<L = ()> {
+    tick: u64,
+    entities: Vec<NetworkID>,
+    columns: Vec<Box<dyn FrameColumn>>,
+    label: PhantomData<L>,
+}
+
+impl<L> fmt::Debug for DynamicFrame<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynamicFrame")
+            .field("tick", &self.tick)
+            .field("entities", &self.entities)
+            .field("columns", &self.columns)
+            .finish()
+    }
+}
+
+impl<L> Clone for DynamicFrame<L> {
+    fn clone(&self) -> Self {
+        Self {
+            tick: self.tick,
+            entities: self.entities.clone(),
+            columns: self.columns.iter().map(|column| column.clone_column()).collect(),
+            label: PhantomData,
+        }
+    }
+}
+
+impl<L> PartialEq for DynamicFrame<L> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tick == other.tick
+            && self.entities == other.entities
+            && self.columns.len() == other.columns.len()
+            && self.columns.iter().zip(other.columns.iter()).all(|(a, b)| a.eq_column(&**b))
+    }
+}
+
+impl<L> DynamicFrame<L> {
+    fn check_columns(&self, other: &Self) -> Result<(), io::Error> {
+        let same_components = self.columns.len() == other.columns.len()
+            && self
+                .columns
+                .iter()
+                .zip(other.columns.iter())
+                .all(|(a, b)| a.as_any().type_id() == b.as_any().type_id());
+        if !same_components {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frames with different registered components",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<L: Send + Sync + 'static> NetworkedFrame for DynamicFrame<L> {
+    fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    fn entities(&self) -> &[NetworkID] {
+        &self.entities
+    }
+
+    fn select_entities(&self, selected: &[bool], baseline: Option<&Self>) -> Self {
+        // A baseline with other components can't be used to encode the frame
+        let baseline = baseline.filter(|baseline| self.check_columns(baseline).is_ok());
+        let mut entities = Vec::with_capacity(self.entities.len());
+        let mut columns: Vec<Box<dyn FrameColumn>> = self.columns.iter().map(|column| column.empty(self.entities.len())).collect();
+        for (i, network_id) in self.entities.iter().enumerate() {
+            let (frame, index) = if selected[i] {
+                (self, i)
+            } else {
+                match baseline.and_then(|baseline| Some((baseline, baseline.entities.binary_search(network_id).ok()?))) {
+                    Some(baseline_row) => baseline_row,
+                    None => continue,
+                }
+            };
+
+            entities.push(*network_id);
+            for (column, source) in columns.iter_mut().zip(frame.columns.iter()) {
+                column.push_row(&**source, index);
+            }
+        }
+
+        Self {
+            tick: self.tick,
+            entities,
+            columns,
+            label: PhantomData,
+        }
+    }
+
+    fn hide_components(&mut self, client_id: u64, context: &VisibilityContext) {
+        for column in self.columns.iter_mut() {
+            column.hide(client_id, context, &self.entities);
+        }
+    }
+
+    fn hides_components(&self) -> bool {
+        self.columns.iter().any(|column| column.hides())
+    }
+
+    fn entity_bits(&self, index: usize, baseline: Option<&Self>, writer: &mut BitWriter) -> Result<usize, io::Error> {
+        if let Some(baseline) = baseline {
+            self.check_columns(baseline)?;
+        }
+
+        let network_id = self.entities[index];
+        let baseline_row = baseline.and_then(|baseline| Some((baseline, baseline.entities.binary_search(&network_id).ok()?)));
+        let mut bits = match baseline_row {
+            Some(_) => 0,
+            None => spawned_entity_bits(network_id),
+        };
+        for (i, column) in self.columns.iter().enumerate() {
+            let previous = baseline_row.and_then(|(baseline, row)| Some((&**baseline.columns.get(i)?, row)));
+            bits += column.bits(writer, index, previous)?;
+        }
+
+        Ok(bits)
+    }
+
+    fn generate_frame(tick: u64, world: &mut World) -> Self {
+        if !world.contains_resource::<ReplicationRegistry<L>>() {
+            world.insert_resource(ReplicationRegistry::<L>::default());
+        }
+        let mut generator = world.remove_resource::<DynamicFrameGenerator<L>>().unwrap_or_default();
+        let previous = generator.previous.take();
+        let previously_unmapped = std::mem::take(&mut generator.unmapped);
+        let change_tick = world.increment_change_tick();
+
+        let mut query = world.query::<(Entity, &NetworkID)>();
+        let mut rows: Vec<(Entity, NetworkID)> = query.iter(world).map(|(entity, network_id)| (entity, *network_id)).collect();
+        rows.sort_unstable_by_key(|(_, network_id)| *network_id);
+
+        let entities: Vec<NetworkID> = rows.iter().map(|(_, network_id)| *network_id).collect();
+        let rows: Vec<Entity> = rows.into_iter().map(|(entity, _)| entity).collect();
+        // Frames are sorted by NetworkID
+        let previous_rows: Vec<Option<usize>> = entities
+            .iter()
+            .map(|network_id| previous.as_ref()?.entities.binary_search(network_id).ok())
+            .collect();
+        let despawned = previous
+            .as_ref()
+            .map_or(Vec::new(), |previous| despawned_entities(&previous.entities, &entities));
+        let generated = GeneratedRows {
+            entities: &entities,
+            rows: &rows,
+            previous_rows: &previous_rows,
+            despawned: &despawned,
+            previously_unmapped: &previously_unmapped,
+            last_change_tick: generator.last_change_tick,
+            change_tick,
+        };
+
+        let registry = world.resource::<ReplicationRegistry<L>>();
+        let columns = registry
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                let previous = previous.as_ref().and_then(|previous| previous.columns.get(i));
+                column.generate(world, &generated, previous.map(|column| &**column), &mut generator.unmapped)
+            })
+            .collect();
+
+        let frame = Self {
+            tick,
+            entities,
+            columns,
+            label: PhantomData,
+        };
+        generator.previous = Some(frame.clone());
+        generator.last_change_tick = change_tick;
+        world.insert_resource(generator);
+
+        frame
+    }
+
+    fn remove_generator(world: &mut World) {
+        world.remove_resource::<DynamicFrameGenerator<L>>();
+    }
+
+    fn apply_in_world(&self, world: &mut World, previous: Option<&Self>) {
+        world.resource_scope(|world, mut mapping: Mut<NetworkMapping>| {
+            let previous_entities = previous.map_or(&[][..], |previous| &previous.entities);
+            sync_network_entities(world, &mut mapping, &self.entities, previous_entities);
+            for (i, column) in self.columns.iter().enumerate() {
+                let previous = previous.and_then(|previous| previous.columns.get(i));
+                column.apply(world, &mapping, &self.entities, previous_entities, previous.map(|column| &**column));
+            }
+        });
+    }
+
+    fn write_full_frame(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        write_frame_header(writer, self.tick, None)?;
+        write_full_entities(writer, &self.entities)?;
+
+        for column in self.columns.iter() {
+            column.write_full(writer)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_delta_frame(&self, writer: &mut BitWriter, delta_frame: &Self) -> Result<(), io::Error> {
+        self.check_columns(delta_frame)?;
+        write_frame_header(writer, self.tick, Some(delta_frame.tick))?;
+        write_delta_entities(writer, &self.entities, &delta_frame.entities)?;
+        let delta_mapping = generate_delta_mapping(&delta_frame.entities, &self.entities);
+
+        for (column, previous) in self.columns.iter().zip(delta_frame.columns.iter()) {
+            column.write_delta(writer, &self.entities, &**previous, &delta_mapping)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_frame(reader: &mut BitReader, world: &mut World) -> Result<Self, ReplicationError> {
+        let max_id_bits = world.resource::<NetworkMapping>().id_bits();
+        let registry = match world.get_resource::<ReplicationRegistry<L>>() {
+            Some(registry) => registry,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no components registered").into()),
+        };
+        let header = read_frame_header(reader)?;
+
+        match header.delta_tick {
+            Some(delta_tick) => {
+                let frame_buffer = world.resource::<SnapshotInterpolationBuffer<Self>>();
+                let delta_frame = match frame_buffer.buffer.get(delta_tick) {
+                    Some(delta_frame) => delta_frame,
+                    None => return Err(ReplicationError::UnknownBaseline { tick: delta_tick }),
+                };
+                if delta_frame.columns.len() != registry.columns.len() {
+                    return Err(ReplicationError::InvalidData("delta frame with different registered components"));
+                }
+
+                let entities = read_delta_entities(reader, max_id_bits, &delta_frame.entities)?;
+                let delta_mapping = generate_delta_mapping(&delta_frame.entities, &entities);
+                let mut columns = Vec::with_capacity(registry.columns.len());
+                for (column, previous) in registry.columns.iter().zip(delta_frame.columns.iter()) {
+                    columns.push(column.read_delta(reader, &entities, &**previous, &delta_mapping)?);
+                }
+
+                Ok(Self {
+                    tick: header.tick,
+                    entities,
+                    columns,
+                    label: PhantomData,
+                })
+            }
+            None => {
+                let entities = read_full_entities(reader, max_id_bits)?;
+                let mut columns = Vec::with_capacity(registry.columns.len());
+                for column in registry.columns.iter() {
+                    columns.push(column.read_full(reader, entities.len())?);
+                }
+
+                Ok(Self {
+                    tick: header.tick,
+                    entities,
+                    columns,
+                    label: PhantomData,
+                })
+            }
+        }
+    }
+
+    fn dump(&self, baseline: Option<&Self>) -> Result<FrameDump, io::Error> {
+        if let Some(baseline) = baseline {
+            self.check_columns(baseline)?;
+        }
+
+        let mut dump = FrameDump::new(self, baseline)?;
+        for (i, column) in self.columns.iter().enumerate() {
+            let baseline = baseline.map(|baseline| (&baseline.entities[..], &*baseline.columns[i]));
+            column.dump(&mut dump, baseline)?;
+        }
+
+        Ok(dump)
+    }
+}
+
+// Type erased column of a registered component.
+trait FrameColumn: fmt::Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn clone_column(&self) -> Box<dyn FrameColumn>;
+    fn eq_column(&self, other: &dyn FrameColumn) -> bool;
+    // Empty column of the same component
+    fn empty(&self, capacity: usize) -> Box<dyn FrameColumn>;
+    // The infallible methods ignore a source or previous column of another component,
+    // the frames check their columns before using them
+    fn push_row(&mut self, source: &dyn FrameColumn, index: usize);
+    // Entities with components that couldn't be mapped are added to `unmapped`
+    fn generate(
+        &self,
+        world: &World,
+        generated: &GeneratedRows,
+        previous: Option<&dyn FrameColumn>,
+        unmapped: &mut HashSet<NetworkID>,
+    ) -> Box<dyn FrameColumn>;
+    fn hide(&mut self, client_id: u64, context: &VisibilityContext, entities: &[NetworkID]);
+    fn hides(&self) -> bool;
+    // Estimated bits of the row, against the row of the previous column
+    fn bits(&self, writer: &mut BitWriter, index: usize, previous: Option<(&dyn FrameColumn, usize)>) -> Result<usize, io::Error>;
+    fn apply(
+        &self,
+        world: &mut World,
+        mapping: &NetworkMapping,
+        entities: &[NetworkID],
+        previous_entities: &[NetworkID],
+        previous: Option<&dyn FrameColumn>,
+    );
+    fn write_full(&self, writer: &mut BitWriter) -> Result<(), io::Error>;
+    fn write_delta(
+        &self,
+        writer: &mut BitWriter,
+        entities: &[NetworkID],
+        previous: &dyn FrameColumn,
+        delta_mapping: &HashMap<NetworkID, usize>,
+    ) -> Result<(), io::Error>;
+    fn read_full(&self, reader: &mut BitReader, len: usize) -> Result<Box<dyn FrameColumn>, ReplicationError>;
+    fn read_delta(
+        &self,
+        reader: &mut BitReader,
+        entities: &[NetworkID],
+        previous: &dyn FrameColumn,
+        delta_mapping: &HashMap<NetworkID, usize>,
+    ) -> Result<Box<dyn FrameColumn>, ReplicationError>;
+    fn dump(&self, dump: &mut FrameDump, baseline: Option<(&[NetworkID], &dyn FrameColumn)>) -> Result<(), io::Error>;
+}
+
+struct Column<T: NetworkedComponent>(Vec<Option<Arc<T::Component>>>);
+
+impl<T: NetworkedComponent> fmt::Debug for Column<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.iter()).finish()
+    }
+}
+
+// Columns of the same position have the same component when the frames are built from the same registry
+fn cells<T: NetworkedComponent + 'static>(column: &dyn FrameColumn) -> Result<&[Option<Arc<T::Component>>], io::Error> {
+    match column.as_any().downcast_ref::<Column<T>>() {
+        Some(column) => Ok(&column.0),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame columns with different components",
+        )),
+    }
+}
+
+impl<T: NetworkedComponent + 'static> FrameColumn for Column<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_column(&self) -> Box<dyn FrameColumn> {
+        Box::new(Column::<T>(self.0.clone()))
+    }
+
+    fn eq_column(&self, other: &dyn FrameColumn) -> bool {
+        match other.as_any().downcast_ref::<Column<T>>() {
+            Some(other) => self.0 == other.0,
+            None => false,
+        }
+    }
+
+    fn empty(&self, capacity: usize) -> Box<dyn FrameColumn> {
+        Box::new(Column::<T>(Vec::with_capacity(capacity)))
+    }
+
+    fn push_row(&mut self, source: &dyn FrameColumn, index: usize) {
+        let component = cells::<T>(source).ok().and_then(|cells| cells[index].clone());
+        self.0.push(component);
+    }
+
+    // Unchanged components share the value of the previous frame, like the frames generated with the macro
+    fn generate(
+        &self,
+        world: &World,
+        generated: &GeneratedRows,
+        previous: Option<&dyn FrameColumn>,
+        unmapped: &mut HashSet<NetworkID>,
+    ) -> Box<dyn FrameColumn> {
+        let previous = previous.and_then(|previous| cells::<T>(previous).ok());
+        let mut components = Vec::with_capacity(generated.rows.len());
+        for (i, entity) in generated.rows.iter().enumerate() {
+            let network_id = generated.entities[i];
+            let entity_ref = world.entity(*entity);
+            let changed = entity_ref
+                .get_change_ticks::<T::Component>()
+                .map_or(true, |ticks| ticks.is_changed(generated.last_change_tick, generated.change_tick));
+            let previous = generated.previous_rows[i].and_then(|index| Some(&previous?[index]));
+            let share = !generated.previously_unmapped.contains(&network_id);
+            let component = changed_component::<T>(
+                entity_ref.get::<T::Component>(),
+                changed,
+                previous,
+                share,
+                generated.despawned,
+                world,
+            )
+            .unwrap_or_else(|previous| {
+                unmapped.insert(network_id);
+                previous
+            });
+            components.push(component);
+        }
+
+        Box::new(Column::<T>(components))
+    }
+
+    fn hide(&mut self, client_id: u64, context: &VisibilityContext, entities: &[NetworkID]) {
+        hide_components::<T>(client_id, context, entities, &mut self.0);
+    }
+
+    fn hides(&self) -> bool {
+        hides_component::<T>()
+    }
+
+    fn bits(&self, writer: &mut BitWriter, index: usize, previous: Option<(&dyn FrameColumn, usize)>) -> Result<usize, io::Error> {
+        let previous = match previous {
+            Some((previous, row)) => Some(&cells::<T>(previous)?[row]),
+            None => None,
+        };
+        component_bits::<T>(writer, &self.0[index], previous)
+    }
+
+    fn apply(
+        &self,
+        world: &mut World,
+        mapping: &NetworkMapping,
+        entities: &[NetworkID],
+        previous_entities: &[NetworkID],
+        previous: Option<&dyn FrameColumn>,
+    ) {
+        let previous = previous.and_then(|previous| cells::<T>(previous).ok()).unwrap_or(&[]);
+        apply_components::<T>(world, mapping, entities, &self.0, previous_entities, previous);
+    }
+
+    fn write_full(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        write_full_component::<T>(writer, &self.0)
+    }
+
+    fn write_delta(
+        &self,
+        writer: &mut BitWriter,
+        entities: &[NetworkID],
+        previous: &dyn FrameColumn,
+        delta_mapping: &HashMap<NetworkID, usize>,
+    ) -> Result<(), io::Error> {
+        write_delta_component::<T>(writer, entities, &self.0, cells::<T>(previous)?, delta_mapping)
+    }
+
+    fn read_full(&self, reader: &mut BitReader, len: usize) -> Result<Box<dyn FrameColumn>, ReplicationError> {
+        Ok(Box::new(Column::<T>(read_full_component::<T>(reader, len)?)))
+    }
+
+    fn read_delta(
+        &self,
+        reader: &mut BitReader,
+        entities: &[NetworkID],
+        previous: &dyn FrameColumn,
+        delta_mapping: &HashMap<NetworkID, usize>,
+    ) -> Result<Box<dyn FrameColumn>, ReplicationError> {
+        let components = read_delta_component::<T>(reader, entities, cells::<T>(previous)?, delta_mapping)?;
+        Ok(Box::new(Column::<T>(components)))
+    }
+
+    fn dump(&self, dump: &mut FrameDump, baseline: Option<(&[NetworkID], &dyn FrameColumn)>) -> Result<(), io::Error> {
+        let baseline = match baseline {
+            Some((entities, previous)) => Some((entities, cells::<T>(previous)?)),
+            None => None,
+        };
+        dump.add_components::<T>(&self.0, baseline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::network_entity::DEFAULT_ID_BITS;
+    use crate::test_utils::{Secret, Simple};
+
+    use super::*;
+
+    #[test]
+    fn test_dynamic_frame() {
+        // Registering twice is ignored
+        let mut server = App::new();
+        server.replicate::<Simple>().replicate::<Secret>().replicate::<Simple>();
+        let entity = server.world.spawn().insert(NetworkID::new(1, 0)).insert(Simple(3)).id();
+        server.world.spawn().insert(NetworkID::new(0, 0)).insert(Secret(4));
+        let first_frame = <DynamicFrame>::generate_frame(0, &mut server.world);
+        assert_eq!(first_frame.entities(), &[NetworkID::new(0, 0), NetworkID::new(1, 0)]);
+
+        let mut client = App::new();
+        client.replicate::<Simple>().replicate::<Secret>();
+        client.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        client.insert_resource(SnapshotInterpolationBuffer::<DynamicFrame>::new(5, Duration::ZERO, 60.));
+
+        let mut writer = BitWriter::with_capacity(100);
+        first_frame.write_full_frame(&mut writer).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_first = <DynamicFrame>::read_frame(&mut reader, &mut client.world).unwrap();
+        assert_eq!(read_first, first_frame);
+        read_first.apply_in_world(&mut client.world, None);
+        client
+            .world
+            .resource_mut::<SnapshotInterpolationBuffer<DynamicFrame>>()
+            .add_snapshot(Duration::ZERO, read_first.clone());
+
+        server.world.get_mut::<Simple>(entity).unwrap().0 = 5;
+        let second_frame = <DynamicFrame>::generate_frame(1, &mut server.world);
+        // Only the changed component is cloned, the other one is shared with the previous frame
+        let first_secret = cells::<Secret>(&*first_frame.columns[1]).unwrap()[0].clone().unwrap();
+        let second_secret = cells::<Secret>(&*second_frame.columns[1]).unwrap()[0].clone().unwrap();
+        assert!(Arc::ptr_eq(&first_secret, &second_secret));
+        let first_simple = cells::<Simple>(&*first_frame.columns[0]).unwrap()[1].clone().unwrap();
+        let second_simple = cells::<Simple>(&*second_frame.columns[0]).unwrap()[1].clone().unwrap();
+        assert!(!Arc::ptr_eq(&first_simple, &second_simple));
+        let mut writer = BitWriter::with_capacity(100);
+        second_frame.write_delta_frame(&mut writer, &first_frame).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_second = <DynamicFrame>::read_frame(&mut reader, &mut client.world).unwrap();
+        assert_eq!(read_second, second_frame);
+        read_second.apply_in_world(&mut client.world, Some(&read_first));
+
+        let mapping = client.world.resource::<NetworkMapping>();
+        let client_entity = mapping.entities[&NetworkID::new(1, 0)];
+        let owned_entity = mapping.entities[&NetworkID::new(0, 0)];
+        assert_eq!(client.world.get::<Simple>(client_entity), Some(&Simple(5)));
+        assert_eq!(client.world.get::<Secret>(owned_entity), Some(&Secret(4)));
+
+        // Each label has its own components
+        struct Owned;
+        server.replicate_labeled::<Owned, Secret>();
+        let owned_frame = <DynamicFrame<Owned>>::generate_frame(2, &mut server.world);
+        assert_eq!(owned_frame.columns.len(), 1);
+        assert_eq!(server.world.resource::<ReplicationRegistry>().columns.len(), 2);
+
+        // Frames with other components can't be a baseline
+        server.replicate_labeled::<Owned, Simple>();
+        let third_frame = <DynamicFrame<Owned>>::generate_frame(3, &mut server.world);
+        let mut writer = BitWriter::with_capacity(100);
+        assert!(third_frame.write_delta_frame(&mut writer, &owned_frame).is_err());
+        assert!(third_frame
+            .entity_bits(0, Some(&owned_frame), &mut BitWriter::with_capacity(100))
+            .is_err());
+    }
+}
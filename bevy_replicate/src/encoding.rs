@@ -0,0 +1,411 @@
+use crate::{read_entity, read_network_id, write_entity, write_network_id, NetworkID};
+
+use bevy::prelude::*;
+use bit_serializer::{BitReader, BitWriter};
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::io;
+
+/// Encoding of a single value, used for the fields of `#[derive(NetworkedComponent)]` without encoding attributes.
+pub trait NetworkedField: Sized {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error>;
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error>;
+}
+
+macro_rules! impl_networked_field_bits {
+    ($($type:ty => $unsigned:ty, $bits:expr);+) => {
+        $(
+            impl NetworkedField for $type {
+                fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+                    writer.write_bits(*self as $unsigned as u32, $bits)
+                }
+
+                fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+                    Ok(reader.read_bits($bits)? as $unsigned as $type)
+                }
+            }
+        )+
+    };
+}
+
+impl_networked_field_bits!(
+    u8 => u8, 8;
+    i8 => u8, 8;
+    u16 => u16, 16;
+    i16 => u16, 16
+);
+
+impl NetworkedField for u32 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u32(*self)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        reader.read_u32()
+    }
+}
+
+impl NetworkedField for i32 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u32(*self as u32)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        Ok(reader.read_u32()? as i32)
+    }
+}
+
+impl NetworkedField for bool {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_bool(*self)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        reader.read_bool()
+    }
+}
+
+impl NetworkedField for u64 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u64(*self)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        reader.read_u64()
+    }
+}
+
+impl NetworkedField for i64 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u64(*self as u64)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        Ok(reader.read_u64()? as i64)
+    }
+}
+
+impl NetworkedField for f32 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u32(self.to_bits())
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        Ok(f32::from_bits(reader.read_u32()?))
+    }
+}
+
+impl NetworkedField for f64 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u64(self.to_bits())
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        Ok(f64::from_bits(reader.read_u64()?))
+    }
+}
+
+impl NetworkedField for Vec2 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        self.x.write_field(writer)?;
+        self.y.write_field(writer)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        Ok(Vec2::new(f32::read_field(reader)?, f32::read_field(reader)?))
+    }
+}
+
+impl NetworkedField for Vec3 {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        self.x.write_field(writer)?;
+        self.y.write_field(writer)?;
+        self.z.write_field(writer)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        Ok(Vec3::new(
+            f32::read_field(reader)?,
+            f32::read_field(reader)?,
+            f32::read_field(reader)?,
+        ))
+    }
+}
+
+impl NetworkedField for Quat {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        write_quat(writer, *self, 16)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        read_quat(reader, 16)
+    }
+}
+
+/// Entities must be mapped with `NetworkedComponent::map_entities`.
+impl NetworkedField for Entity {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        write_entity(writer, *self)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        read_entity(reader)
+    }
+}
+
+impl NetworkedField for NetworkID {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        write_network_id(writer, *self)
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        read_network_id(reader)
+    }
+}
+
+impl<T: NetworkedField> NetworkedField for Option<T> {
+    fn write_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_bool(self.is_some())?;
+        match self {
+            Some(value) => value.write_field(writer),
+            None => Ok(()),
+        }
+    }
+
+    fn read_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+        match reader.read_bool()? {
+            true => Ok(Some(T::read_field(reader)?)),
+            false => Ok(None),
+        }
+    }
+}
+
+/// Unsigned integer written with a fixed number of bits, used by `#[replicate(bits = N)]`.
+/// Values that don't fit in the bits, or in the type when read, are rejected instead of truncated.
+pub trait BitsField: Sized {
+    fn write_bits_field(&self, writer: &mut BitWriter, bits: usize) -> Result<(), io::Error>;
+    fn read_bits_field(reader: &mut BitReader, bits: usize) -> Result<Self, io::Error>;
+}
+
+macro_rules! impl_bits_field {
+    ($($type:ty),+) => {
+        $(
+            impl BitsField for $type {
+                fn write_bits_field(&self, writer: &mut BitWriter, bits: usize) -> Result<(), io::Error> {
+                    let value = *self as u64;
+                    if bits > 32 || value >> bits != 0 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, "value doesn't fit in the field bits"));
+                    }
+
+                    writer.write_bits(value as u32, bits)
+                }
+
+                fn read_bits_field(reader: &mut BitReader, bits: usize) -> Result<Self, io::Error> {
+                    let value = reader.read_bits(bits)?;
+                    <$type>::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "field value above its type"))
+                }
+            }
+        )+
+    };
+}
+
+impl_bits_field!(u8, u16, u32, u64, usize);
+
+/// Integer written as a varint, used by `#[replicate(varint)]`.
+/// Signed integers are zig-zag encoded, so small negative values are also written with few bits.
+pub trait VarintField: Sized {
+    fn write_varint_field(&self, writer: &mut BitWriter) -> Result<(), io::Error>;
+    fn read_varint_field(reader: &mut BitReader) -> Result<Self, io::Error>;
+}
+
+macro_rules! impl_varint_field {
+    ($($type:ty),+) => {
+        $(
+            impl VarintField for $type {
+                fn write_varint_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+                    writer.write_varint_u64(*self as u64)
+                }
+
+                fn read_varint_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+                    let value = reader.read_varint_u64()?;
+                    <$type>::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "field value above its type"))
+                }
+            }
+        )+
+    };
+}
+
+macro_rules! impl_varint_field_zigzag {
+    ($($type:ty),+) => {
+        $(
+            impl VarintField for $type {
+                fn write_varint_field(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+                    let value = *self as i64;
+                    writer.write_varint_u64(((value << 1) ^ (value >> 63)) as u64)
+                }
+
+                fn read_varint_field(reader: &mut BitReader) -> Result<Self, io::Error> {
+                    let value = reader.read_varint_u64()?;
+                    let value = (value >> 1) as i64 ^ -((value & 1) as i64);
+                    <$type>::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "field value out of its type range"))
+                }
+            }
+        )+
+    };
+}
+
+impl_varint_field!(u8, u16, u32, u64, usize);
+impl_varint_field_zigzag!(i8, i16, i32, i64, isize);
+
+pub fn bits_required(min: u32, max: u32) -> usize {
+    let diff = max - min;
+    (u32::BITS - diff.leading_zeros()) as usize
+}
+
+pub fn write_f32_range(writer: &mut BitWriter, value: f32, min: f32, max: f32, precision: f32) -> Result<(), io::Error> {
+    let delta = max - min;
+    let values = delta / precision;
+
+    let max_integer_value = values.ceil() as u32;
+    let bits = bits_required(0, max_integer_value);
+
+    let normalized_value = ((value - min) / delta).clamp(0., 1.);
+    let integer_value = (normalized_value * max_integer_value as f32 + 0.5).floor() as u32;
+
+    writer.write_bits(integer_value, bits)?;
+
+    Ok(())
+}
+
+pub fn read_f32_range(reader: &mut BitReader, min: f32, max: f32, precision: f32) -> Result<f32, io::Error> {
+    let delta = max - min;
+    let values = delta / precision;
+
+    let max_integer_value = values.ceil() as u32;
+    let bits = bits_required(0, max_integer_value);
+
+    let integer_value = reader.read_bits(bits)?;
+    // The bits can hold more values than the range, only a malformed frame writes them
+    if integer_value > max_integer_value {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "value above range"));
+    }
+
+    let normalized_value = integer_value as f32 / max_integer_value as f32;
+    let value = normalized_value * delta + min;
+
+    Ok(value)
+}
+
+pub fn write_f32_range_bits(writer: &mut BitWriter, mut value: f32, min: f32, max: f32, bits: usize) -> Result<(), io::Error> {
+    let delta = max - min;
+    let umax = (1 << bits) - 1;
+    let q = umax as f32 / delta;
+
+    if value < min {
+        value = min;
+    }
+
+    let mut u = ((value - min) * q) as u32;
+
+    if u > umax {
+        u = umax;
+    }
+
+    writer.write_bits(u, bits)
+}
+
+pub fn read_f32_range_bits(reader: &mut BitReader, min: f32, max: f32, bits: usize) -> Result<f32, io::Error> {
+    let delta = max - min;
+    let umax = (1 << bits) - 1;
+    let q = umax as f32 / delta;
+
+    let u = reader.read_bits(bits)?;
+    if u > umax {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "value above range"));
+    }
+    // Rounding could still go past the range
+    let value = (min + (u as f32 / q)).clamp(min, max);
+
+    Ok(value)
+}
+
+pub fn write_quat(writer: &mut BitWriter, quat: Quat, bits: usize) -> Result<(), io::Error> {
+    let quat = quat.normalize();
+    let mut largest_index = 3; // w
+    let mut quat = quat.to_array();
+    for i in 0..3 {
+        if quat[i].abs() > quat[largest_index].abs() {
+            largest_index = i;
+        }
+    }
+
+    if quat[largest_index] < 0.0 {
+        for i in 0..4 {
+            quat[i] *= -1.0;
+        }
+    }
+
+    writer.write_bits(largest_index as u32, 2)?;
+
+    for i in 0..4 {
+        if i != largest_index {
+            write_f32_range_bits(writer, quat[i], -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_quat(reader: &mut BitReader, bits: usize) -> Result<Quat, io::Error> {
+    let largest_index = reader.read_bits(2)? as usize;
+
+    let a = read_f32_range_bits(reader, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
+    let b = read_f32_range_bits(reader, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
+    let c = read_f32_range_bits(reader, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
+
+    let mut result = [0.0; 4];
+    // Invalid components from the network would make it negative
+    result[largest_index] = f32::sqrt((1.0 - a * a - b * b - c * c).max(0.0));
+
+    let values = [a, b, c];
+    let mut index_value = 0;
+    for i in 0..4 {
+        if i != largest_index {
+            result[i] = values[index_value];
+            index_value += 1;
+        }
+    }
+
+    let quat = Quat::from_array(result);
+    Ok(quat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f32_range() {
+        // 3200 values in 12 bits
+        let mut writer = BitWriter::with_capacity(8);
+        write_f32_range(&mut writer, 12.5, 0., 32., 0.01).unwrap();
+        writer.write_bits(3201, 12).unwrap();
+        let buffer = writer.consume().unwrap();
+
+        let mut reader = BitReader::new(&buffer).unwrap();
+        assert!((read_f32_range(&mut reader, 0., 32., 0.01).unwrap() - 12.5).abs() < 0.01);
+        assert!(read_f32_range(&mut reader, 0., 32., 0.01).is_err());
+    }
+
+    #[test]
+    fn test_f32_range_bits() {
+        let mut writer = BitWriter::with_capacity(8);
+        write_f32_range_bits(&mut writer, 0.25, -1., 1., 10).unwrap();
+        writer.write_bits(1023, 10).unwrap();
+        let buffer = writer.consume().unwrap();
+
+        let mut reader = BitReader::new(&buffer).unwrap();
+        assert!((read_f32_range_bits(&mut reader, -1., 1., 10).unwrap() - 0.25).abs() < 0.01);
+        let value = read_f32_range_bits(&mut reader, -1., 1., 10).unwrap();
+        assert!((-1. ..=1.).contains(&value));
+    }
+}
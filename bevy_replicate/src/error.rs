@@ -0,0 +1,99 @@
+use std::{fmt, io};
+
+/// Error reading or producing a replicated frame.
+/// Frames are read from untrusted network data, malformed frames are reported with these errors and never panic.
+#[derive(Debug)]
+pub enum ReplicationError {
+    /// The data ended before the frame was fully read.
+    Truncated,
+    /// The delta frame was encoded against a frame the client doesn't have, it was never received or is too old.
+    UnknownBaseline {
+        tick: u64,
+    },
+    /// Server: no frame was generated for the tick.
+    MissingFrame {
+        tick: u64,
+    },
+    /// More entities, or a NetworkID index above, what the configured id_bits allow.
+    EntityLimit {
+        id_bits: usize,
+    },
+    /// Invalid change code, or change encoding, of a component column.
+    InvalidChange(u8),
+    /// The networked component failed to read, `component` is the type name of its [`NetworkedComponent`](crate::NetworkedComponent).
+    Component {
+        component: &'static str,
+        error: io::Error,
+    },
+    /// The networked resource failed to read, `resource` is the type name of its [`NetworkedResource`](crate::NetworkedResource).
+    Resource {
+        resource: &'static str,
+        error: io::Error,
+    },
+    /// The frame is compressed with a codec missing from the client config, see [`FrameCodec`](crate::compression::FrameCodec).
+    UnknownCodec(u8),
+    /// The frame is malformed or doesn't match its baseline frame, e.g. a despawned entity that wasn't in the baseline.
+    InvalidData(&'static str),
+    Io(io::Error),
+}
+
+impl ReplicationError {
+    pub(crate) fn component<T>(error: io::Error) -> Self {
+        Self::Component {
+            component: std::any::type_name::<T>(),
+            error,
+        }
+    }
+
+    pub(crate) fn resource<T>(error: io::Error) -> Self {
+        Self::Resource {
+            resource: std::any::type_name::<T>(),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "frame data ended before the frame was read"),
+            Self::UnknownBaseline { tick } => write!(f, "delta frame encoded against unknown tick {}", tick),
+            Self::MissingFrame { tick } => write!(f, "no frame generated for tick {}", tick),
+            Self::EntityLimit { id_bits } => write!(f, "more network entities than the configured id_bits ({}) allow", id_bits),
+            Self::InvalidChange(change) => write!(f, "invalid component change {}", change),
+            Self::Component { component, error } => write!(f, "failed to read component {}: {}", component, error),
+            Self::Resource { resource, error } => write!(f, "failed to read resource {}: {}", resource, error),
+            Self::UnknownCodec(id) => write!(f, "frame compressed with unknown codec {}", id),
+            Self::InvalidData(reason) => write!(f, "invalid frame: {}", reason),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ReplicationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Component { error, .. } | Self::Resource { error, .. } | Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReplicationError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(error),
+        }
+    }
+}
+
+impl From<ReplicationError> for io::Error {
+    fn from(error: ReplicationError) -> Self {
+        match error {
+            ReplicationError::Io(error) => error,
+            ReplicationError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, error),
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::{collections::HashMap, io, time::Duration};
+
+/// Maximum size in bytes of the header written before every fragment: the tick with the fragmented flag
+/// as a varint (up to 10 bytes), the index of the fragment and the number of fragments of the frame as u16.
+/// Frames that fit in one message are only prefixed by a single byte with the flag unset.
+pub const MAX_FRAGMENT_HEADER_SIZE: usize = 14;
+/// Maximum number of fragments a frame can be split into, larger frames fail to be fragmented.
+/// With the default fragment size of 1024 bytes, frames up to about 64MB can be sent.
+pub const MAX_FRAGMENTS: usize = u16::MAX as usize;
+/// Maximum number of incomplete frames kept while waiting for their fragments, the oldest tick is dropped first.
+/// The incomplete frames also hold at most twice the maximum frame size of the client, see [`FragmentedFrames`].
+pub const MAX_PENDING_FRAMES: usize = 32;
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+// Returns the value and the number of bytes read.
+fn read_varint(bytes: &[u8]) -> Result<(u64, usize), io::Error> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, "invalid fragment header"))
+}
+
+/// Split an encoded frame into fragments of at most `fragment_size` bytes, header included.
+/// The lowest bit of the header marks fragmented frames, frames that fit in one fragment only have that bit
+/// in a single byte. The tick in the header of the fragments only groups them, its highest bit is dropped.
+/// Fails when the frame needs more than [`MAX_FRAGMENTS`] fragments.
+pub fn fragment_frame(tick: u64, payload: &[u8], fragment_size: usize) -> Result<Vec<Vec<u8>>, io::Error> {
+    if payload.len() < fragment_size {
+        let mut fragment = Vec::with_capacity(payload.len() + 1);
+        fragment.push(0);
+        fragment.extend_from_slice(payload);
+        return Ok(vec![fragment]);
+    }
+
+    let mut header = Vec::with_capacity(MAX_FRAGMENT_HEADER_SIZE);
+    write_varint(&mut header, tick << 1 | 1);
+    let chunk_size = match fragment_size.checked_sub(header.len() + 4) {
+        Some(chunk_size) if chunk_size > 0 => chunk_size,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "fragment size smaller than fragment header",
+            ))
+        }
+    };
+
+    let count = (payload.len() + chunk_size - 1) / chunk_size;
+    if count > MAX_FRAGMENTS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large to be fragmented"));
+    }
+
+    let mut fragments = Vec::with_capacity(count);
+    for index in 0..count {
+        let start = index * chunk_size;
+        let end = (start + chunk_size).min(payload.len());
+        let mut fragment = Vec::with_capacity(header.len() + 4 + end - start);
+        fragment.extend_from_slice(&header);
+        fragment.extend_from_slice(&(index as u16).to_le_bytes());
+        fragment.extend_from_slice(&(count as u16).to_le_bytes());
+        fragment.extend_from_slice(&payload[start..end]);
+        fragments.push(fragment);
+    }
+
+    Ok(fragments)
+}
+
+#[derive(Debug)]
+struct PendingFrame {
+    count: usize,
+    // Only the received fragments are stored, the count comes from the network
+    fragments: HashMap<usize, Vec<u8>>,
+    bytes: usize,
+    first_received: Duration,
+}
+
+/// Reassembles the fragmented frames received from the server, fragments can arrive in any order.
+/// Frames that are not completed before the timeout are dropped, as well as the oldest frames when
+/// more than [`MAX_PENDING_FRAMES`] are incomplete or when the incomplete frames hold more than twice
+/// the maximum frame size.
+#[derive(Debug)]
+pub struct FragmentedFrames {
+    timeout: Duration,
+    pending: HashMap<u64, PendingFrame>,
+    pending_bytes: usize,
+}
+
+impl FragmentedFrames {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    /// Store a received fragment, returns the reassembled frame once all of its fragments were received.
+    /// Fragments of frames larger than `max_frame_size` bytes, the compression flag aside, are rejected
+    /// before the frame is reassembled.
+    pub fn insert(&mut self, current_time: Duration, fragment: &[u8], max_frame_size: usize) -> Result<Option<Vec<u8>>, io::Error> {
+        let max_size = max_frame_size + 1;
+        let (header, header_len) = read_varint(fragment)?;
+        if header & 1 == 0 {
+            if header != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid fragment header"));
+            }
+            if fragment.len() - header_len > max_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "frame larger than max frame size"));
+            }
+            return Ok(Some(fragment[header_len..].to_vec()));
+        }
+
+        let tick = header >> 1;
+        let (index, count, payload) = match &fragment[header_len..] {
+            [i0, i1, c0, c1, payload @ ..] => (
+                u16::from_le_bytes([*i0, *i1]) as usize,
+                u16::from_le_bytes([*c0, *c1]) as usize,
+                payload,
+            ),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "fragment smaller than fragment header")),
+        };
+
+        if index >= count {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "fragment index above fragment count"));
+        }
+        if payload.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty fragment"));
+        }
+        // Every fragment but the last one is full, they give the minimum size of the frame
+        if index + 1 < count && (count - 1) * payload.len() + 1 > max_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame larger than max frame size"));
+        }
+
+        // Make room for the fragment, dropping the oldest frames first
+        while (!self.pending.contains_key(&tick) && self.pending.len() >= MAX_PENDING_FRAMES)
+            || self.pending_bytes + payload.len() > 2 * max_size
+        {
+            let oldest = self.pending.keys().filter(|pending_tick| **pending_tick != tick).min().copied();
+            match oldest {
+                Some(oldest) => self.remove(oldest),
+                None => break,
+            };
+        }
+
+        let pending = self.pending.entry(tick).or_insert_with(|| PendingFrame {
+            count,
+            fragments: HashMap::new(),
+            bytes: 0,
+            first_received: current_time,
+        });
+
+        if pending.count != count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fragment count doesn't match previous fragments",
+            ));
+        }
+
+        // Duplicated fragments are ignored
+        if pending.fragments.contains_key(&index) {
+            return Ok(None);
+        }
+        if pending.bytes + payload.len() > max_size {
+            self.remove(tick);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame larger than max frame size"));
+        }
+        pending.fragments.insert(index, payload.to_vec());
+        pending.bytes += payload.len();
+        self.pending_bytes += payload.len();
+
+        if pending.fragments.len() < count {
+            return Ok(None);
+        }
+
+        let mut pending = self.remove(tick).unwrap();
+        let frame = (0..count).filter_map(|index| pending.fragments.remove(&index)).flatten().collect();
+
+        Ok(Some(frame))
+    }
+
+    /// Drop the frames that were not completed before the timeout.
+    pub fn remove_expired(&mut self, current_time: Duration) {
+        let timeout = self.timeout;
+        self.pending
+            .retain(|_, pending| current_time.saturating_sub(pending.first_received) < timeout);
+        self.pending_bytes = self.pending.values().map(|pending| pending.bytes).sum();
+    }
+
+    fn remove(&mut self, tick: u64) -> Option<PendingFrame> {
+        let pending = self.pending.remove(&tick)?;
+        self.pending_bytes -= pending.bytes;
+        Some(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_FRAME_SIZE: usize = 4096;
+
+    fn fragment(tick: u64, index: u16, count: u16, payload: &[u8]) -> Vec<u8> {
+        let mut fragment = vec![];
+        write_varint(&mut fragment, tick << 1 | 1);
+        fragment.extend_from_slice(&index.to_le_bytes());
+        fragment.extend_from_slice(&count.to_le_bytes());
+        fragment.extend_from_slice(payload);
+        fragment
+    }
+
+    #[test]
+    fn test_fragments() {
+        let payload: Vec<u8> = (0..25).collect();
+        // 5 bytes header + 10 bytes payload per fragment
+        let fragments = fragment_frame(3, &payload, 15).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 15));
+
+        // Out of order and duplicated fragments
+        let mut fragmented_frames = FragmentedFrames::new(Duration::from_millis(100));
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[2], MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[0], MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[2], MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[1], MAX_FRAME_SIZE).unwrap(),
+            Some(payload.clone())
+        );
+
+        // Incomplete frames are dropped after the timeout
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[0], MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+        fragmented_frames.remove_expired(Duration::from_millis(100));
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[1], MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[2], MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+
+        assert!(fragment_frame(3, &payload, 5).is_err());
+
+        // Frames that fit in one message only have a single byte header
+        let fragments = fragment_frame(3, &payload, 26).unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].len(), 26);
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[0], MAX_FRAME_SIZE).unwrap(),
+            Some(payload.clone())
+        );
+        assert!(fragmented_frames.insert(Duration::ZERO, &[2], MAX_FRAME_SIZE).is_err());
+        assert!(fragmented_frames.insert(Duration::ZERO, &[], MAX_FRAME_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_pending_frames_limit() {
+        let payload: Vec<u8> = (0..20).collect();
+        let mut fragmented_frames = FragmentedFrames::new(Duration::from_millis(100));
+        let frames: Vec<Vec<Vec<u8>>> = (0..=MAX_PENDING_FRAMES as u64)
+            .map(|tick| fragment_frame(tick, &payload, 15).unwrap())
+            .collect();
+        for fragments in frames.iter() {
+            assert_eq!(
+                fragmented_frames.insert(Duration::ZERO, &fragments[0], MAX_FRAME_SIZE).unwrap(),
+                None
+            );
+        }
+
+        // The oldest frame was dropped, it starts again
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &frames[0][1], MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &frames[2][1], MAX_FRAME_SIZE).unwrap(),
+            Some(payload.clone())
+        );
+    }
+
+    #[test]
+    fn test_many_fragments() {
+        // More fragments than a single byte can count
+        let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let fragments = fragment_frame(1, &payload, 15).unwrap();
+        assert_eq!(fragments.len(), 300);
+
+        let mut fragmented_frames = FragmentedFrames::new(Duration::from_millis(100));
+        for fragment in fragments[..299].iter() {
+            assert_eq!(fragmented_frames.insert(Duration::ZERO, fragment, MAX_FRAME_SIZE).unwrap(), None);
+        }
+        assert_eq!(
+            fragmented_frames.insert(Duration::ZERO, &fragments[299], MAX_FRAME_SIZE).unwrap(),
+            Some(payload.clone())
+        );
+
+        assert!(fragment_frame(1, &vec![0; MAX_FRAGMENTS * 10 + 1], 15).is_err());
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let mut fragmented_frames = FragmentedFrames::new(Duration::from_millis(100));
+
+        // Header announcing the largest fragment count, rejected before anything is stored
+        assert!(fragmented_frames
+            .insert(Duration::ZERO, &fragment(1, 0, u16::MAX, &[0; 100]), MAX_FRAME_SIZE)
+            .is_err());
+        assert!(fragmented_frames.pending.is_empty());
+
+        // The last fragment is larger than the other ones, the frame is dropped once its bytes exceed the max frame size
+        let last = fragment(2, 1, 2, &[0; MAX_FRAME_SIZE]);
+        assert_eq!(fragmented_frames.insert(Duration::ZERO, &last, MAX_FRAME_SIZE).unwrap(), None);
+        assert!(fragmented_frames
+            .insert(Duration::ZERO, &fragment(2, 0, 2, &[0; 10]), MAX_FRAME_SIZE)
+            .is_err());
+        assert!(fragmented_frames.pending.is_empty());
+        assert_eq!(fragmented_frames.pending_bytes, 0);
+
+        // Many frames each under the max frame size, the oldest are dropped to keep the pending bytes bounded
+        let payload = vec![0u8; MAX_FRAME_SIZE];
+        for tick in 0..MAX_PENDING_FRAMES as u64 {
+            let fragments = fragment_frame(tick, &payload, 1024).unwrap();
+            for fragment in fragments[1..].iter() {
+                assert_eq!(fragmented_frames.insert(Duration::ZERO, fragment, MAX_FRAME_SIZE).unwrap(), None);
+            }
+            assert!(fragmented_frames.pending_bytes <= 2 * (MAX_FRAME_SIZE + 1));
+        }
+        assert!(fragmented_frames.pending.len() < 3);
+
+        // Unfragmented frames are checked as well
+        assert!(fragmented_frames
+            .insert(Duration::ZERO, &[0; MAX_FRAME_SIZE + 3], MAX_FRAME_SIZE)
+            .is_err());
+    }
+}
@@ -1,17 +1,41 @@
 pub mod client;
+pub mod compression;
+pub mod dump;
+pub mod dynamic_frame;
+pub mod encoding;
+pub mod error;
+pub mod fragment;
+pub mod message;
 mod network_entity;
 pub mod network_frame;
+pub mod networked_hierarchy;
+#[cfg(feature = "serde")]
+pub mod networked_serde;
 pub mod networked_transform;
+pub mod ownership;
+pub mod prediction;
+pub mod priority;
+pub mod relevancy;
 pub mod sequence_buffer;
 pub mod server;
+#[cfg(test)]
+mod test_utils;
+pub mod visibility;
 
 #[doc(hidden)]
 pub use bevy;
 #[doc(hidden)]
 pub use paste;
 
+pub use bevy_replicate_derive::NetworkedComponent;
 pub use bit_serializer::{BitReader, BitWriter};
+pub use error::ReplicationError;
 
-pub use network_entity::{NetworkEntities, NetworkID};
+pub use network_entity::{
+    read_entity, read_network_id, write_entity, write_network_id, NetworkEntities, NetworkEntityMapper, NetworkID, NetworkIdLimitReached,
+    NetworkIdsExhausted, ReplicateCommandsExt, Replicated, ReplicatedBy, StreamEntities, DEFAULT_ID_BITS, GENERATION_BITS, MAX_ID_BITS,
+    MIN_ID_BITS,
+};
 
 pub use network_frame::*;
+pub use ownership::{IsLocallyOwned, LocalClientId, NetworkOwner};
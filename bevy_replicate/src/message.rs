@@ -0,0 +1,416 @@
+use crate::{client::NetworkMapping, priority::ClientReplication, NetworkEntities, NetworkEntityMapper, NetworkID, NetworkedFrame};
+
+use bevy::prelude::*;
+use bit_serializer::{BitReader, BitWriter};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt::Debug,
+    io,
+};
+
+/// Discrete message sent between the server and the clients, e.g. an emote or an ability request.
+/// Messages must be registered in the same order in the server and the clients, see [`NetworkMessageAppExt`].
+pub trait NetworkMessage: Debug + Clone + Send + Sync + 'static {
+    fn write(&self, writer: &mut BitWriter) -> Result<(), io::Error>;
+    fn read(reader: &mut BitReader) -> Result<Self, io::Error>;
+
+    /// Map the entities referenced by the message, see [`NetworkedComponent::map_entities`](crate::NetworkedComponent::map_entities).
+    /// The sender maps its entities to their NetworkID, the receiver maps them to its own entities.
+    /// Received messages with entities that can't be mapped are dropped.
+    fn map_entities(&mut self, _mapper: &dyn NetworkEntityMapper) -> Option<()> {
+        Some(())
+    }
+}
+
+/// Server: message received from a client.
+#[derive(Debug, Clone)]
+pub struct FromClient<M> {
+    pub client_id: u64,
+    pub message: M,
+}
+
+/// Client: message received from the server.
+#[derive(Debug, Clone)]
+pub struct FromServer<M>(pub M);
+
+type ReadMessage = fn(&mut BitReader, Option<u64>, &mut World) -> Result<(), io::Error>;
+
+/// Registered message types, the id of each type is the order it was registered.
+#[derive(Debug, Default)]
+pub struct NetworkMessages {
+    ids: HashMap<TypeId, u32>,
+    readers: Vec<ReadMessage>,
+}
+
+impl NetworkMessages {
+    fn id<M: NetworkMessage>(&self) -> Result<u32, io::Error> {
+        match self.ids.get(&TypeId::of::<M>()) {
+            Some(id) => Ok(*id),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "message type not registered")),
+        }
+    }
+}
+
+pub trait NetworkMessageAppExt {
+    /// Register a message type that can be sent in both directions.
+    /// Received messages are sent as [`FromClient`] events in the server and [`FromServer`] events in the clients.
+    fn add_network_message<M: NetworkMessage>(&mut self) -> &mut Self;
+}
+
+impl NetworkMessageAppExt for App {
+    fn add_network_message<M: NetworkMessage>(&mut self) -> &mut Self {
+        self.add_event::<FromClient<M>>();
+        self.add_event::<FromServer<M>>();
+
+        let mut messages = self.world.get_resource_or_insert_with(NetworkMessages::default);
+        let id = messages.readers.len() as u32;
+        messages.ids.insert(TypeId::of::<M>(), id);
+        messages.readers.push(read_message::<M>);
+
+        self
+    }
+}
+
+// Erased message waiting to be written, the entities are mapped when it is written.
+trait QueuedMessage: Debug + Send + Sync {
+    // Message with its entities mapped, `None` when an entity can't be mapped
+    fn map(&self, mapper: &dyn NetworkEntityMapper) -> Option<Box<dyn QueuedMessage>>;
+    fn write(&self, writer: &mut BitWriter, messages: &NetworkMessages) -> Result<(), io::Error>;
+    fn boxed(&self) -> Box<dyn QueuedMessage>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<M: NetworkMessage> QueuedMessage for M {
+    fn map(&self, mapper: &dyn NetworkEntityMapper) -> Option<Box<dyn QueuedMessage>> {
+        let mut message = self.clone();
+        message.map_entities(mapper)?;
+        Some(Box::new(message))
+    }
+
+    fn boxed(&self) -> Box<dyn QueuedMessage> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn write(&self, writer: &mut BitWriter, messages: &NetworkMessages) -> Result<(), io::Error> {
+        writer.write_varint_u64(messages.id::<M>()? as u64)?;
+        NetworkMessage::write(self, writer)
+    }
+}
+
+/// Messages written for a client or the server, see [`write_server_messages`] and [`write_client_messages`].
+#[derive(Debug, Default)]
+pub struct MessagesPayload {
+    /// Encoded messages, `None` when there are no messages to send.
+    pub payload: Option<Vec<u8>>,
+    /// Messages left out because they reference entities without NetworkID.
+    pub unmapped: Vec<UnmappedMessage>,
+}
+
+/// Message left out of a [`MessagesPayload`], as it was queued.
+#[derive(Debug)]
+pub struct UnmappedMessage(Box<dyn QueuedMessage>);
+
+impl UnmappedMessage {
+    /// The message, `None` when it is not a message of type M.
+    pub fn downcast_ref<M: NetworkMessage>(&self) -> Option<&M> {
+        self.0.as_any().downcast_ref()
+    }
+}
+
+#[derive(Debug)]
+enum MessageTarget {
+    Client(u64),
+    All,
+    // Clients the entity is relevant to
+    Relevant(NetworkID),
+}
+
+/// Server: messages queued to be sent to the clients, they are written per client with [`write_server_messages`]
+/// and cleared at the end of every frame.
+#[derive(Debug, Default)]
+pub struct ServerMessages {
+    queued: Vec<(MessageTarget, Box<dyn QueuedMessage>)>,
+}
+
+impl ServerMessages {
+    pub fn send<M: NetworkMessage>(&mut self, client_id: u64, message: M) {
+        self.queued.push((MessageTarget::Client(client_id), Box::new(message)));
+    }
+
+    pub fn broadcast<M: NetworkMessage>(&mut self, message: M) {
+        self.queued.push((MessageTarget::All, Box::new(message)));
+    }
+
+    /// Send the message to the clients the entity is relevant to, see [`Relevancy`](crate::relevancy::Relevancy).
+    pub fn send_to_relevant<M: NetworkMessage>(&mut self, network_id: NetworkID, message: M) {
+        self.queued.push((MessageTarget::Relevant(network_id), Box::new(message)));
+    }
+}
+
+pub(crate) fn clear_server_messages_system(mut messages: ResMut<ServerMessages>) {
+    messages.queued.clear();
+}
+
+/// Client: messages queued to be sent to the server, they are written with [`write_client_messages`].
+#[derive(Debug, Default)]
+pub struct ClientMessages {
+    queued: Vec<Box<dyn QueuedMessage>>,
+}
+
+impl ClientMessages {
+    pub fn send<M: NetworkMessage>(&mut self, message: M) {
+        self.queued.push(Box::new(message));
+    }
+}
+
+fn write_queued(
+    queued: &[&dyn QueuedMessage],
+    messages: &NetworkMessages,
+    mapper: &dyn NetworkEntityMapper,
+) -> Result<MessagesPayload, io::Error> {
+    let mut unmapped = Vec::new();
+    let mapped: Vec<Box<dyn QueuedMessage>> = queued
+        .iter()
+        .filter_map(|message| {
+            let mapped = message.map(mapper);
+            if mapped.is_none() {
+                unmapped.push(UnmappedMessage(message.boxed()));
+            }
+            mapped
+        })
+        .collect();
+    if mapped.is_empty() {
+        return Ok(MessagesPayload { payload: None, unmapped });
+    }
+
+    let mut writer = BitWriter::with_capacity(1000);
+    writer.write_varint_u64(mapped.len() as u64)?;
+    for message in mapped.iter() {
+        message.write(&mut writer, messages)?;
+    }
+
+    Ok(MessagesPayload {
+        payload: Some(writer.consume()?),
+        unmapped,
+    })
+}
+
+/// Encode the messages queued for the client, the payload is `None` when there are no messages for it.
+/// The payload should be sent in a reliable channel and given to [`process_server_messages`] when received.
+/// The mapper maps the server entities to their NetworkID, e.g. the World or a `Query<&NetworkID>`.
+/// Messages referencing entities without NetworkID are left out and reported in [`MessagesPayload::unmapped`].
+pub fn write_server_messages<T: NetworkedFrame>(
+    client_id: u64,
+    server_messages: &ServerMessages,
+    messages: &NetworkMessages,
+    replication: &ClientReplication<T>,
+    mapper: &dyn NetworkEntityMapper,
+) -> Result<MessagesPayload, io::Error> {
+    let has_relevant_target = server_messages
+        .queued
+        .iter()
+        .any(|(target, _)| matches!(target, MessageTarget::Relevant(_)));
+    // Without relevancy strategies every entity is relevant
+    let relevant = if has_relevant_target { replication.relevant_entities(client_id) } else { None };
+
+    let queued: Vec<&dyn QueuedMessage> = server_messages
+        .queued
+        .iter()
+        .filter(|(target, _)| match target {
+            MessageTarget::Client(target_id) => *target_id == client_id,
+            MessageTarget::All => true,
+            MessageTarget::Relevant(network_id) => relevant.as_ref().map_or(true, |relevant| relevant.contains(network_id)),
+        })
+        .map(|(_, message)| message.as_ref())
+        .collect();
+
+    write_queued(&queued, messages, mapper)
+}
+
+/// Encode the messages queued for the server, the payload is `None` when there are no messages.
+/// The payload should be sent in a reliable channel and given to [`process_client_messages`] when received.
+/// The mapper maps the client entities to their NetworkID, e.g. the World or a `Query<&NetworkID>`.
+/// Messages referencing entities without NetworkID are left out and reported in [`MessagesPayload::unmapped`].
+pub fn write_client_messages(
+    client_messages: &mut ClientMessages,
+    messages: &NetworkMessages,
+    mapper: &dyn NetworkEntityMapper,
+) -> Result<MessagesPayload, io::Error> {
+    let queued: Vec<&dyn QueuedMessage> = client_messages.queued.iter().map(|message| message.as_ref()).collect();
+    let payload = write_queued(&queued, messages, mapper);
+    client_messages.queued.clear();
+
+    payload
+}
+
+fn read_messages(payload: &[u8], sender: Option<u64>, world: &mut World) -> Result<(), io::Error> {
+    let mut reader = BitReader::new(payload)?;
+    let len = reader.read_varint_u64()?;
+    for _ in 0..len {
+        let id = reader.read_varint_u64()?;
+        let read = match world.resource::<NetworkMessages>().readers.get(id as usize) {
+            Some(read) => *read,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "message type not registered")),
+        };
+        read(&mut reader, sender, world)?;
+    }
+
+    Ok(())
+}
+
+/// Read the messages sent by the server, they are sent as [`FromServer`] events.
+pub fn process_server_messages(payload: &[u8], world: &mut World) -> Result<(), io::Error> {
+    read_messages(payload, None, world)
+}
+
+/// Read the messages sent by the client, they are sent as [`FromClient`] events.
+pub fn process_client_messages(client_id: u64, payload: &[u8], world: &mut World) -> Result<(), io::Error> {
+    read_messages(payload, Some(client_id), world)
+}
+
+// Without shared NetworkIDs, messages referencing entities are dropped
+struct NoNetworkEntities;
+
+impl NetworkEntityMapper for NoNetworkEntities {
+    fn map(&self, _entity: Entity) -> Option<Entity> {
+        None
+    }
+}
+
+fn read_message<M: NetworkMessage>(reader: &mut BitReader, sender: Option<u64>, world: &mut World) -> Result<(), io::Error> {
+    let mut message = M::read(reader)?;
+    match sender {
+        Some(client_id) => {
+            // Streams with separate NetworkIDs don't share their entities, only the shared ones are mapped
+            let mapped = match world.get_resource::<NetworkEntities>() {
+                Some(network_entities) => message.map_entities(network_entities),
+                None => message.map_entities(&NoNetworkEntities),
+            };
+            if mapped.is_some() {
+                world
+                    .resource_mut::<Events<FromClient<M>>>()
+                    .send(FromClient { client_id, message });
+            }
+        }
+        None => {
+            if message.map_entities(world.resource::<NetworkMapping>()).is_some() {
+                world.resource_mut::<Events<FromServer<M>>>().send(FromServer(message));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network_entity::DEFAULT_ID_BITS;
+    use crate::relevancy::GlobalRelevancy;
+    use crate::test_utils::NetworkFrame;
+    use crate::{read_entity, write_entity};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Clone)]
+    struct Emote {
+        entity: Entity,
+        emote: u8,
+    }
+
+    impl NetworkMessage for Emote {
+        fn write(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+            write_entity(writer, self.entity)?;
+            writer.write_bits(self.emote as u32, 8)
+        }
+
+        fn read(reader: &mut BitReader) -> Result<Self, io::Error> {
+            let entity = read_entity(reader)?;
+            let emote = reader.read_bits(8)? as u8;
+
+            Ok(Self { entity, emote })
+        }
+
+        fn map_entities(&mut self, mapper: &dyn NetworkEntityMapper) -> Option<()> {
+            self.entity = mapper.map(self.entity)?;
+            Some(())
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut server = App::new();
+        server.add_network_message::<Emote>();
+        let network_id = NetworkID::new(2, 0);
+        let server_entity = server.world.spawn().insert(network_id).id();
+        let not_replicated = server.world.spawn().id();
+        let mut network_entities = NetworkEntities::new(DEFAULT_ID_BITS);
+        network_entities.network_map.insert(network_id, server_entity);
+        server.insert_resource(network_entities);
+
+        let mut client = App::new();
+        client.add_network_message::<Emote>();
+        let client_entity = client.world.spawn().insert(network_id).id();
+        let mut mapping = NetworkMapping::new(DEFAULT_ID_BITS);
+        mapping.entities.insert(network_id, client_entity);
+        client.insert_resource(mapping);
+
+        let emote = |entity| Emote { entity, emote: 3 };
+        let mut server_messages = ServerMessages::default();
+        server_messages.send(1, emote(server_entity));
+        server_messages.broadcast(emote(server_entity));
+        // Without AlwaysRelevant the entity is not relevant to any client
+        server_messages.send_to_relevant(network_id, emote(server_entity));
+        let mut replication = ClientReplication::<NetworkFrame>::new(8);
+        replication.add_relevancy(GlobalRelevancy::default());
+
+        let messages = server.world.resource::<NetworkMessages>();
+        let payload = write_server_messages(1, &server_messages, messages, &replication, &server.world).unwrap();
+        assert!(payload.unmapped.is_empty());
+        process_server_messages(&payload.payload.unwrap(), &mut client.world).unwrap();
+        let events = client.world.resource::<Events<FromServer<Emote>>>();
+        let received: Vec<Emote> = events.get_reader().iter(events).map(|event| event.0.clone()).collect();
+        assert_eq!(received, vec![emote(client_entity), emote(client_entity)]);
+
+        // Messages referencing entities without NetworkID are left out, the other messages are still sent
+        server_messages.send(2, emote(not_replicated));
+        let payload = write_server_messages(2, &server_messages, messages, &replication, &server.world).unwrap();
+        assert_eq!(payload.unmapped.len(), 1);
+        assert_eq!(payload.unmapped[0].downcast_ref::<Emote>(), Some(&emote(not_replicated)));
+        let mut reader = BitReader::new(payload.payload.as_ref().unwrap()).unwrap();
+        assert_eq!(reader.read_varint_u64().unwrap(), 1);
+
+        let mut client_messages = ClientMessages::default();
+        client_messages.send(emote(client_entity));
+        let messages = client.world.resource::<NetworkMessages>();
+        let payload = write_client_messages(&mut client_messages, messages, &client.world).unwrap();
+        process_client_messages(7, &payload.payload.unwrap(), &mut server.world).unwrap();
+        let events = server.world.resource::<Events<FromClient<Emote>>>();
+        let received: Vec<(u64, Emote)> = events
+            .get_reader()
+            .iter(events)
+            .map(|event| (event.client_id, event.message.clone()))
+            .collect();
+        assert_eq!(received, vec![(7, emote(server_entity))]);
+
+        // Nothing left to send
+        let messages = client.world.resource::<NetworkMessages>();
+        let payload = write_client_messages(&mut client_messages, messages, &client.world).unwrap();
+        assert!(payload.payload.is_none() && payload.unmapped.is_empty());
+
+        // Without shared NetworkIDs, the messages referencing entities are dropped
+        server.world.remove_resource::<NetworkEntities>();
+        let mut client_messages = ClientMessages::default();
+        client_messages.send(emote(client_entity));
+        let messages = client.world.resource::<NetworkMessages>();
+        let payload = write_client_messages(&mut client_messages, messages, &client.world).unwrap();
+        server.world.resource_mut::<Events<FromClient<Emote>>>().clear();
+        process_client_messages(7, &payload.payload.unwrap(), &mut server.world).unwrap();
+        let events = server.world.resource::<Events<FromClient<Emote>>>();
+        assert_eq!(events.get_reader().iter(events).count(), 0);
+    }
+}
@@ -1,64 +1,373 @@
-use std::collections::HashMap;
+use std::any::TypeId;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::{fmt, io};
 
+use bevy::ecs::component::TableStorage;
+use bevy::ecs::system::EntityCommands;
 use bevy::prelude::*;
+use bit_serializer::{BitReader, BitWriter};
 
-const ID_BITS: usize = 12;
-const MAX_ID: u16 = (1 << ID_BITS) - 1;
-pub(crate) const MAX_LENGTH: usize = 1 << ID_BITS;
+/// Smallest supported width, in bits, of a NetworkID.
+pub const MIN_ID_BITS: usize = 8;
+/// Largest supported width, in bits, of a NetworkID.
+pub const MAX_ID_BITS: usize = 24;
+/// Default width, in bits, of a NetworkID (4096 networked entities).
+pub const DEFAULT_ID_BITS: usize = 12;
+/// Number of bits used to write the generation of a NetworkID.
+pub const GENERATION_BITS: usize = 8;
 
-#[derive(Debug, Component, Copy, Clone, PartialEq, Eq, Hash)]
-pub struct NetworkID(pub u16);
+/// Identifies a networked entity between the server and the clients.
+/// The index is reused after the entity is removed and the clients acknowledged a frame without it,
+/// the generation is increased every time that happens, so a recycled index is seen as a different entity.
+#[derive(Debug, Component, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NetworkID {
+    pub index: u32,
+    pub generation: u8,
+}
+
+impl NetworkID {
+    pub const fn new(index: u32, generation: u8) -> Self {
+        Self { index, generation }
+    }
+
+    /// Placeholder entity used to carry the NetworkID in components between the entity mappings.
+    pub fn to_entity(self) -> Entity {
+        Entity::from_raw((self.generation as u32) << MAX_ID_BITS | self.index)
+    }
+
+    pub fn from_entity(entity: Entity) -> Self {
+        let id = entity.id();
+        Self::new(id & (max_entities(MAX_ID_BITS) as u32 - 1), (id >> MAX_ID_BITS) as u8)
+    }
+}
+
+/// Written as `{index}v{generation}`, like bevy's Entity.
+impl fmt::Display for NetworkID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+/// Maps entities referenced inside components, see [`NetworkedComponent::map_entities`](crate::NetworkedComponent::map_entities).
+pub trait NetworkEntityMapper {
+    fn map(&self, entity: Entity) -> Option<Entity>;
+}
+
+/// On the server, entities are mapped to their NetworkID.
+impl NetworkEntityMapper for World {
+    fn map(&self, entity: Entity) -> Option<Entity> {
+        self.get::<NetworkID>(entity).map(|network_id| network_id.to_entity())
+    }
+}
+
+/// Entities are mapped to their NetworkID, e.g. to send messages referencing them.
+impl NetworkEntityMapper for Query<'_, '_, &NetworkID> {
+    fn map(&self, entity: Entity) -> Option<Entity> {
+        self.get(entity).ok().map(|network_id| network_id.to_entity())
+    }
+}
+
+/// On the server, the NetworkIDs received from the clients are mapped to the server entities.
+impl NetworkEntityMapper for NetworkEntities {
+    fn map(&self, entity: Entity) -> Option<Entity> {
+        self.network_map.get(&NetworkID::from_entity(entity)).copied()
+    }
+}
+
+/// Write an entity mapped by [`NetworkEntityMapper`] on the server.
+pub fn write_entity(writer: &mut BitWriter, entity: Entity) -> Result<(), io::Error> {
+    write_network_id(writer, NetworkID::from_entity(entity))
+}
+
+pub fn read_entity(reader: &mut BitReader) -> Result<Entity, io::Error> {
+    Ok(read_network_id(reader)?.to_entity())
+}
+
+/// Write a NetworkID referenced inside a component, the index is written as a varint
+/// since the configured id width is not known when encoding components.
+pub fn write_network_id(writer: &mut BitWriter, network_id: NetworkID) -> Result<(), io::Error> {
+    writer.write_varint_u64(network_id.index as u64)?;
+    writer.write_bits(network_id.generation as u32, GENERATION_BITS)
+}
+
+pub fn read_network_id(reader: &mut BitReader) -> Result<NetworkID, io::Error> {
+    let index = reader.read_varint_u64()?;
+    if index >= max_entities(MAX_ID_BITS) as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "network id index above limit"));
+    }
+    let generation = reader.read_bits(GENERATION_BITS)? as u8;
+
+    Ok(NetworkID::new(index as u32, generation))
+}
+
+/// Marks an entity to be replicated, the server plugin assigns it a NetworkID when the marker is added
+/// and releases the NetworkID when the marker is removed.
+#[derive(Debug, Default, Component, Copy, Clone)]
+pub struct Replicated;
+
+/// Marks an entity to be replicated by the stream of the frame type T, for streams with separate NetworkID allocation,
+/// see [`NetworkIdAllocation`](crate::server::NetworkIdAllocation).
+/// The entity is only replicated by that stream, it gets a NetworkID from the [`StreamEntities`] of the stream.
+pub struct ReplicatedBy<T>(PhantomData<T>);
+
+impl<T> Default for ReplicatedBy<T> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T> fmt::Debug for ReplicatedBy<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReplicatedBy")
+    }
+}
+
+impl<T: Send + Sync + 'static> Component for ReplicatedBy<T> {
+    type Storage = TableStorage;
+}
+
+/// Event sent when a [`Replicated`] entity could not be assigned a NetworkID because all ids are in use.
+/// The entity is not retried, remove and insert the marker again to request a new id.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkIdsExhausted {
+    pub entity: Entity,
+    pub error: NetworkIdLimitReached,
+}
+
+pub trait ReplicateCommandsExt {
+    /// Insert the [`Replicated`] marker in the entity.
+    fn replicate(&mut self) -> &mut Self;
+
+    /// Insert the [`ReplicatedBy`] marker of the stream in the entity.
+    fn replicate_by<T: Send + Sync + 'static>(&mut self) -> &mut Self;
+}
+
+impl ReplicateCommandsExt for EntityCommands<'_, '_, '_> {
+    fn replicate(&mut self) -> &mut Self {
+        self.insert(Replicated)
+    }
+
+    fn replicate_by<T: Send + Sync + 'static>(&mut self) -> &mut Self {
+        self.insert(ReplicatedBy::<T>::default())
+    }
+}
+
+/// Returned by [`NetworkEntities::generate`] when every id available with the configured width is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkIdLimitReached {
+    pub id_bits: usize,
+}
+
+impl fmt::Display for NetworkIdLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "all {} network ids are in use, increase the configured id_bits ({})",
+            max_entities(self.id_bits),
+            self.id_bits
+        )
+    }
+}
+
+impl std::error::Error for NetworkIdLimitReached {}
+
+/// Maximum number of networked entities that can exist with the given id width.
+pub fn max_entities(id_bits: usize) -> usize {
+    1 << id_bits
+}
+
+// Number of indices released when each frame of a stream was generated
+#[derive(Debug, Default)]
+struct StreamReleases {
+    frames: VecDeque<(u64, u64)>,
+    // Released indices that no client of the stream can still have in its acknowledged frame
+    acknowledged: u64,
+}
 
 #[derive(Debug)]
 pub struct NetworkEntities {
-    used: Box<[bool; MAX_LENGTH]>,
-    entity_map: HashMap<Entity, NetworkID>,
-    current_id: usize,
+    id_bits: usize,
+    first_index: u32,
+    generations: Vec<u8>,
+    free_ids: VecDeque<u32>,
+    // Released indices the clients may still have in their acknowledged frame, in release order
+    held_back: VecDeque<u32>,
+    // Number of indices released since the allocator was created
+    released: u64,
+    streams: HashMap<TypeId, StreamReleases>,
+    pub(crate) entity_map: HashMap<Entity, NetworkID>,
+    pub(crate) network_map: HashMap<NetworkID, Entity>,
 }
 
 impl Default for NetworkEntities {
     fn default() -> Self {
+        Self::new(DEFAULT_ID_BITS)
+    }
+}
+
+impl NetworkEntities {
+    pub fn new(id_bits: usize) -> Self {
+        Self::with_first_index(id_bits, 0)
+    }
+
+    /// Allocator for the indices starting at `first_index`, used by streams with separate NetworkID allocation.
+    pub fn with_first_index(id_bits: usize, first_index: u32) -> Self {
+        assert!(
+            (MIN_ID_BITS..=MAX_ID_BITS).contains(&id_bits),
+            "NetworkID width must be between {} and {} bits, got {}",
+            MIN_ID_BITS,
+            MAX_ID_BITS,
+            id_bits
+        );
+        assert!(
+            first_index as usize + max_entities(id_bits) <= max_entities(MAX_ID_BITS),
+            "NetworkID indices must be below {}",
+            max_entities(MAX_ID_BITS)
+        );
+
         Self {
-            used: Box::new([false; MAX_LENGTH]),
+            id_bits,
+            first_index,
+            generations: Vec::new(),
+            free_ids: VecDeque::new(),
+            held_back: VecDeque::new(),
+            released: 0,
+            streams: HashMap::new(),
             entity_map: HashMap::new(),
-            current_id: 0,
+            network_map: HashMap::new(),
         }
     }
-}
 
-impl NetworkEntities {
-    pub fn generate(&mut self) -> Option<NetworkID> {
-        let mut count = 0;
-        loop {
-            if !self.used[self.current_id] {
-                let network_id = NetworkID(self.current_id as u16);
-                self.used[self.current_id] = true;
-                self.current_id += 1;
-                return Some(network_id);
-            }
+    pub fn id_bits(&self) -> usize {
+        self.id_bits
+    }
 
-            if self.current_id as u16 > MAX_ID {
-                self.current_id = 0;
-            }
+    /// Whether the NetworkID is in the range of indices handed out by this allocator.
+    pub fn contains(&self, network_id: NetworkID) -> bool {
+        match network_id.index.checked_sub(self.first_index) {
+            Some(offset) => (offset as usize) < max_entities(self.id_bits),
+            None => false,
+        }
+    }
 
-            count += 1;
-            if count >= MAX_LENGTH {
-                return None;
-            }
+    pub fn generate(&mut self) -> Result<NetworkID, NetworkIdLimitReached> {
+        // Hand out indices that were never used first, freed indices are reused in the order they were released
+        // so the same index takes as long as possible to come back.
+        if self.generations.len() < max_entities(self.id_bits) {
+            let index = self.first_index + self.generations.len() as u32;
+            self.generations.push(0);
+            return Ok(NetworkID::new(index, 0));
+        }
+
+        match self.free_ids.pop_front() {
+            Some(index) => Ok(NetworkID::new(index, self.generations[(index - self.first_index) as usize])),
+            None => Err(NetworkIdLimitReached { id_bits: self.id_bits }),
         }
     }
 
+    /// Release the NetworkID of the entity. The index is held back until the clients of every stream using
+    /// the allocator acknowledged a frame generated after the release, see [`NetworkEntities::acknowledge_frame`].
+    /// The generation of the index wraps around: the clients no longer have the old entity when it comes back,
+    /// even when a single index is free and reused every time.
     pub fn remove(&mut self, entity: Entity) {
         if let Some(network_id) = self.entity_map.remove(&entity) {
-            let index = network_id.0 as usize;
-            self.used[index] = false;
+            self.network_map.remove(&network_id);
+            let index = (network_id.index - self.first_index) as usize;
+            self.generations[index] = self.generations[index].wrapping_add(1);
+            self.held_back.push_back(network_id.index);
+            self.released += 1;
+            self.reuse_acknowledged();
+        }
+    }
+
+    /// Record the frame of the stream T generated at `tick`, with the oldest tick of the stream acknowledged
+    /// by the clients, `None` when no client has a frame to delta encode against.
+    /// Frames older than `buffer_size` ticks can't be used as baselines, they are considered acknowledged.
+    pub fn acknowledge_frame<T: 'static>(&mut self, tick: u64, acknowledged_tick: Option<u64>, buffer_size: usize) {
+        let released = self.released;
+        let stream = self.streams.entry(TypeId::of::<T>()).or_default();
+        stream.frames.push_back((tick, released));
+
+        while let Some(&(frame_tick, frame_released)) = stream.frames.front() {
+            let acknowledged = acknowledged_tick.map_or(true, |acknowledged_tick| frame_tick <= acknowledged_tick);
+            if !acknowledged && stream.frames.len() <= buffer_size {
+                break;
+            }
+            stream.acknowledged = frame_released;
+            stream.frames.pop_front();
         }
+
+        self.reuse_acknowledged();
+    }
+
+    /// Stop waiting for the clients of the stream T, e.g. when it leaves its state.
+    pub fn remove_stream<T: 'static>(&mut self) {
+        self.streams.remove(&TypeId::of::<T>());
+        self.reuse_acknowledged();
+    }
+
+    // Without streams no client can have the released entities
+    fn reuse_acknowledged(&mut self) {
+        let acknowledged = self
+            .streams
+            .values()
+            .map(|stream| stream.acknowledged)
+            .min()
+            .unwrap_or(self.released);
+        let first_held_back = self.released - self.held_back.len() as u64;
+        let reusable = (acknowledged.saturating_sub(first_held_back) as usize).min(self.held_back.len());
+        self.free_ids.extend(self.held_back.drain(..reusable));
+    }
+
+    // NetworkIDs from other allocators are not tracked, their entities belong to another stream
+    fn track(&mut self, entity: Entity, network_id: NetworkID) {
+        if self.contains(network_id) {
+            self.entity_map.insert(entity, network_id);
+            self.network_map.insert(network_id, entity);
+        }
+    }
+}
+
+/// Server: NetworkIDs of the stream of the frame type T, when it uses separate NetworkID allocation.
+pub struct StreamEntities<T> {
+    network_entities: NetworkEntities,
+    data: PhantomData<T>,
+}
+
+impl<T> StreamEntities<T> {
+    pub fn new(network_entities: NetworkEntities) -> Self {
+        Self {
+            network_entities,
+            data: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for StreamEntities<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.network_entities.fmt(f)
+    }
+}
+
+impl<T> Deref for StreamEntities<T> {
+    type Target = NetworkEntities;
+
+    fn deref(&self) -> &NetworkEntities {
+        &self.network_entities
+    }
+}
+
+impl<T> DerefMut for StreamEntities<T> {
+    fn deref_mut(&mut self) -> &mut NetworkEntities {
+        &mut self.network_entities
     }
 }
 
 pub fn track_network_entity_system(mut network_entities: ResMut<NetworkEntities>, query: Query<(Entity, &NetworkID), Added<NetworkID>>) {
     for (entity, network_id) in query.iter() {
-        network_entities.entity_map.insert(entity, *network_id);
+        network_entities.track(entity, *network_id);
     }
 }
 
@@ -67,3 +376,213 @@ pub fn cleanup_network_entity_system(mut network_entities: ResMut<NetworkEntitie
         network_entities.remove(entity);
     }
 }
+
+pub fn assign_network_id_system(
+    mut commands: Commands,
+    mut network_entities: ResMut<NetworkEntities>,
+    mut exhausted_events: EventWriter<NetworkIdsExhausted>,
+    query: Query<Entity, (Added<Replicated>, Without<NetworkID>)>,
+) {
+    for entity in query.iter() {
+        match network_entities.generate() {
+            Ok(network_id) => {
+                commands.entity(entity).insert(network_id);
+            }
+            Err(error) => exhausted_events.send(NetworkIdsExhausted { entity, error }),
+        }
+    }
+}
+
+pub fn release_network_id_system(mut commands: Commands, removals: RemovedComponents<Replicated>, query: Query<(), With<NetworkID>>) {
+    for entity in removals.iter() {
+        // Despawned entities are cleaned up by cleanup_network_entity_system
+        if query.get(entity).is_ok() {
+            commands.entity(entity).remove::<NetworkID>();
+        }
+    }
+}
+
+pub fn track_stream_entity_system<T: Send + Sync + 'static>(
+    mut stream_entities: ResMut<StreamEntities<T>>,
+    query: Query<(Entity, &NetworkID), Added<NetworkID>>,
+) {
+    for (entity, network_id) in query.iter() {
+        stream_entities.track(entity, *network_id);
+    }
+}
+
+pub fn cleanup_stream_entity_system<T: Send + Sync + 'static>(
+    mut stream_entities: ResMut<StreamEntities<T>>,
+    removals: RemovedComponents<NetworkID>,
+) {
+    for entity in removals.iter() {
+        stream_entities.remove(entity);
+    }
+}
+
+pub fn assign_stream_id_system<T: Send + Sync + 'static>(
+    mut commands: Commands,
+    mut stream_entities: ResMut<StreamEntities<T>>,
+    mut exhausted_events: EventWriter<NetworkIdsExhausted>,
+    query: Query<Entity, (Added<ReplicatedBy<T>>, Without<NetworkID>)>,
+) {
+    for entity in query.iter() {
+        match stream_entities.generate() {
+            Ok(network_id) => {
+                commands.entity(entity).insert(network_id);
+            }
+            Err(error) => exhausted_events.send(NetworkIdsExhausted { entity, error }),
+        }
+    }
+}
+
+pub fn release_stream_id_system<T: Send + Sync + 'static>(
+    mut commands: Commands,
+    removals: RemovedComponents<ReplicatedBy<T>>,
+    query: Query<(), With<NetworkID>>,
+) {
+    for entity in removals.iter() {
+        if query.get(entity).is_ok() {
+            commands.entity(entity).remove::<NetworkID>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generate_delta_mapping;
+
+    use super::*;
+
+    #[test]
+    fn test_recycled_id() {
+        let mut network_entities = NetworkEntities::new(MIN_ID_BITS);
+        let mut world = World::new();
+        let entity = world.spawn().id();
+
+        let network_id = network_entities.generate().unwrap();
+        network_entities.entity_map.insert(entity, network_id);
+        for _ in 1..max_entities(MIN_ID_BITS) {
+            network_entities.generate().unwrap();
+        }
+        assert!(network_entities.generate().is_err());
+
+        network_entities.remove(entity);
+        let recycled_id = network_entities.generate().unwrap();
+        assert_eq!(recycled_id.index, network_id.index);
+        assert_ne!(recycled_id, network_id);
+
+        // The recycled id is not delta encoded against the old entity
+        let delta_mapping = generate_delta_mapping(&[network_id], &[recycled_id]);
+        assert!(delta_mapping.is_empty());
+
+        // The generation wraps around instead of retiring the index, so the ids never run out
+        let mut network_id = recycled_id;
+        while network_id.generation < u8::MAX {
+            network_entities.entity_map.insert(entity, network_id);
+            network_entities.remove(entity);
+            network_id = network_entities.generate().unwrap();
+            assert_eq!(network_id.index, recycled_id.index);
+        }
+        network_entities.entity_map.insert(entity, network_id);
+        network_entities.remove(entity);
+        let wrapped_id = network_entities.generate().unwrap();
+        assert_eq!(wrapped_id, NetworkID::new(recycled_id.index, 0));
+    }
+
+    #[test]
+    fn test_single_free_index() {
+        struct Stream;
+
+        let mut network_entities = NetworkEntities::new(MIN_ID_BITS);
+        let mut world = World::new();
+        let entity = world.spawn().id();
+        for _ in 0..max_entities(MIN_ID_BITS) {
+            network_entities.generate().unwrap();
+        }
+        network_entities.acknowledge_frame::<Stream>(0, Some(0), 8);
+
+        // The only free index is held back until the client acknowledges a frame generated after its release,
+        // the same NetworkID never comes back while the client may have the old entity
+        let mut network_id = NetworkID::new(0, 0);
+        let mut tick = 0;
+        for _ in 0..=u8::MAX as usize {
+            network_entities.entity_map.insert(entity, network_id);
+            network_entities.remove(entity);
+            assert!(network_entities.generate().is_err());
+
+            tick += 1;
+            network_entities.acknowledge_frame::<Stream>(tick, Some(tick - 1), 8);
+            assert!(network_entities.generate().is_err());
+            network_entities.acknowledge_frame::<Stream>(tick + 1, Some(tick), 8);
+            tick += 1;
+
+            let recycled_id = network_entities.generate().unwrap();
+            assert_eq!(recycled_id.index, network_id.index);
+            assert_eq!(recycled_id.generation, network_id.generation.wrapping_add(1));
+            network_id = recycled_id;
+        }
+        assert_eq!(network_id.generation, 1);
+
+        // Without acknowledged frames, the index comes back once the frames with the old entity left the buffer
+        network_entities.entity_map.insert(entity, network_id);
+        network_entities.remove(entity);
+        for _ in 0..8 {
+            tick += 1;
+            network_entities.acknowledge_frame::<Stream>(tick, Some(0), 8);
+            assert!(network_entities.generate().is_err());
+        }
+        network_entities.acknowledge_frame::<Stream>(tick + 1, Some(0), 8);
+        assert_eq!(network_entities.generate().unwrap(), NetworkID::new(0, 2));
+
+        // Stopping the stream reuses the held back indices
+        network_entities.entity_map.insert(entity, NetworkID::new(0, 2));
+        network_entities.remove(entity);
+        assert!(network_entities.generate().is_err());
+        network_entities.remove_stream::<Stream>();
+        assert_eq!(network_entities.generate().unwrap(), NetworkID::new(0, 3));
+    }
+
+    #[test]
+    fn test_network_id_systems() {
+        let mut app = App::new();
+        app.insert_resource(NetworkEntities::new(MIN_ID_BITS));
+        app.add_event::<NetworkIdsExhausted>();
+        app.add_system_to_stage(CoreStage::PostUpdate, assign_network_id_system);
+        app.add_system_to_stage(CoreStage::PostUpdate, release_network_id_system);
+        app.add_system(track_network_entity_system);
+        app.add_system_to_stage(CoreStage::Last, cleanup_network_entity_system);
+
+        let entity = app.world.spawn().insert(Replicated).id();
+        app.update();
+        let network_id = *app.world.get::<NetworkID>(entity).unwrap();
+        app.update();
+        assert_eq!(app.world.resource::<NetworkEntities>().network_map.get(&network_id), Some(&entity));
+
+        // Removing the marker releases the NetworkID
+        app.world.entity_mut(entity).remove::<Replicated>();
+        app.update();
+        assert!(app.world.get::<NetworkID>(entity).is_none());
+        assert!(app.world.resource::<NetworkEntities>().network_map.is_empty());
+
+        // The released index is reused once the others are in use, the entity left without id is reported
+        let entities: Vec<Entity> = (0..=max_entities(MIN_ID_BITS))
+            .map(|_| app.world.spawn().insert(Replicated).id())
+            .collect();
+        app.update();
+        let without_id: Vec<Entity> = entities
+            .iter()
+            .copied()
+            .filter(|entity| app.world.get::<NetworkID>(*entity).is_none())
+            .collect();
+        assert_eq!(without_id.len(), 1);
+
+        let events = app.world.resource::<Events<NetworkIdsExhausted>>();
+        let exhausted: Vec<(Entity, usize)> = events
+            .get_reader()
+            .iter(events)
+            .map(|event| (event.entity, event.error.id_bits))
+            .collect();
+        assert_eq!(exhausted, vec![(without_id[0], MIN_ID_BITS)]);
+    }
+}
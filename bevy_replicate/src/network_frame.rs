@@ -1,11 +1,19 @@
 use bevy::ecs::world::EntityMut;
 use bevy::prelude::*;
 use bit_serializer::{BitReader, BitWriter};
-use std::{collections::HashMap, io};
-
-use crate::{network_entity, NetworkID};
-
-#[derive(Debug, Clone, Copy)]
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::Arc,
+};
+
+use crate::client::NetworkMapping;
+use crate::dump::FrameDump;
+use crate::network_entity::{self, GENERATION_BITS};
+use crate::visibility::{ComponentVisibility, VisibilityContext};
+use crate::{NetworkEntityMapper, NetworkID, ReplicationError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ComponentChange {
     FullChange,
     NoComponent,
@@ -31,11 +39,35 @@ impl TryFrom<u8> for ComponentChange {
 
 pub trait NetworkedFrame: std::fmt::Debug + Clone + Sized + Send + Sync + 'static {
     fn tick(&self) -> u64;
+    /// Entities in the frame, sorted by NetworkID.
+    fn entities(&self) -> &[NetworkID];
+    /// Frame with only the selected entities from this frame, the entities not selected keep their state
+    /// from the baseline frame or are left out when the baseline doesn't have them.
+    fn select_entities(&self, selected: &[bool], baseline: Option<&Self>) -> Self;
+    /// Remove the components the client can't see, see [`NetworkedComponent::visibility`].
+    fn hide_components(&mut self, client_id: u64, context: &VisibilityContext);
+    /// Whether a component is hidden from some clients, the frames are then built for each client.
+    fn hides_components(&self) -> bool;
+    /// Estimated bits written for the entity at `index` against the baseline frame, used to fill the bit budgets.
+    /// The components are written in the scratch `writer`, shared by the entities of the frame.
+    fn entity_bits(&self, index: usize, baseline: Option<&Self>, writer: &mut BitWriter) -> Result<usize, io::Error>;
     fn generate_frame(tick: u64, world: &mut bevy::prelude::World) -> Self;
-    fn apply_in_world(&self, world: &mut bevy::prelude::World);
+    /// Remove the state kept in the world by [`NetworkedFrame::generate_frame`] between ticks.
+    fn remove_generator(world: &mut bevy::prelude::World);
+    /// Keep the entities that lost components for the next [`NetworkedFrame::generate_frame`], the world only keeps
+    /// the removals until the end of the update. Called every update by the server plugins.
+    fn track_removals(_world: &mut bevy::prelude::World) {}
+    /// Apply the frame in the client world, components missing since the previously applied frame are removed.
+    fn apply_in_world(&self, world: &mut bevy::prelude::World, previous: Option<&Self>);
     fn write_full_frame(&self, writer: &mut BitWriter) -> Result<(), io::Error>;
     fn write_delta_frame(&self, writer: &mut BitWriter, delta_frame: &Self) -> Result<(), io::Error>;
-    fn read_frame(reader: &mut BitReader, world: &mut bevy::prelude::World) -> Result<Self, io::Error>;
+    /// Read a frame received from the server, delta frames are read against the frames in the `SnapshotInterpolationBuffer`.
+    fn read_frame(reader: &mut BitReader, world: &mut bevy::prelude::World) -> Result<Self, ReplicationError>;
+    /// Debug view of the frame with the changes written against the baseline frame, see [`FrameDump`].
+    /// By default only the entities and the size of the frame are dumped.
+    fn dump(&self, baseline: Option<&Self>) -> Result<FrameDump, io::Error> {
+        FrameDump::new(self, baseline)
+    }
 }
 
 pub trait NetworkedComponent {
@@ -46,112 +78,378 @@ pub trait NetworkedComponent {
     }
 
     fn write_delta(_old: &Self::Component, _new: &Self::Component, _writer: &mut BitWriter) -> Result<(), io::Error> {
-        panic!("Delta encoding not implemented for component");
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "delta encoding not implemented for component",
+        ))
     }
 
     fn read_delta(_old: &Self::Component, _reader: &mut BitReader) -> Result<Self::Component, io::Error> {
-        panic!("Delta encoding not implemented for component");
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "delta encoding not implemented for component",
+        ))
     }
 
     fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error>;
     fn read_full(reader: &mut BitReader) -> Result<Self::Component, io::Error>;
 
+    /// Map the entities referenced by the component, similar to bevy's `MapEntities`.
+    /// On the server the entities are mapped to their NetworkID before the component is written,
+    /// on the client they are mapped to the local entities before the component is applied.
+    /// Write and read the mapped entities with [`write_entity`](crate::write_entity) and [`read_entity`](crate::read_entity).
+    /// Return `None` when an entity can't be mapped yet, the component is then skipped and retried later.
+    fn map_entities(_component: &mut Self::Component, _mapper: &dyn NetworkEntityMapper) -> Option<()> {
+        Some(())
+    }
+
+    /// Which clients receive the component, everyone by default.
+    fn visibility() -> ComponentVisibility {
+        ComponentVisibility::Everyone
+    }
+
     fn apply(mut entity_mut: EntityMut<'_>, component: &Self::Component) {
         entity_mut.insert(component.clone());
     }
+
+    /// Called on the client when the entity lost the component on the server.
+    fn remove(mut entity_mut: EntityMut<'_>) {
+        entity_mut.remove::<Self::Component>();
+    }
+}
+
+/// Global state replicated once per frame, e.g. a match timer or a score board.
+/// Works like [`NetworkedComponent`] for a resource, see [`network_frame!`] to list them.
+pub trait NetworkedResource {
+    type Resource: bevy::ecs::system::Resource + PartialEq + Clone + std::fmt::Debug;
+
+    fn can_delta(_old: &Self::Resource, _new: &Self::Resource) -> bool {
+        false
+    }
+
+    fn write_delta(_old: &Self::Resource, _new: &Self::Resource, _writer: &mut BitWriter) -> Result<(), io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "delta encoding not implemented for resource",
+        ))
+    }
+
+    fn read_delta(_old: &Self::Resource, _reader: &mut BitReader) -> Result<Self::Resource, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "delta encoding not implemented for resource",
+        ))
+    }
+
+    fn write_full(resource: &Self::Resource, writer: &mut BitWriter) -> Result<(), io::Error>;
+    fn read_full(reader: &mut BitReader) -> Result<Self::Resource, io::Error>;
+
+    fn apply(world: &mut World, resource: &Self::Resource) {
+        world.insert_resource(resource.clone());
+    }
+
+    /// Called on the client when the resource was removed on the server.
+    fn remove(world: &mut World) {
+        world.remove_resource::<Self::Resource>();
+    }
 }
 
 /// Generate a NetworkFrame that contains all desired networked components
 /// Usage: network_frame!(ComponentA, ComponentB);
 /// All components need to implement the NetworkedComponent trait.
+/// Resources are listed after the components: network_frame!(ComponentA, ComponentB; ResourceA),
+/// they need to implement the NetworkedResource trait.
+/// List [`NetworkOwner`](crate::NetworkOwner) to replicate the owner of the entities, the clients need it for
+/// [`IsLocallyOwned`](crate::IsLocallyOwned) and the server for the owner visibility of the components.
+/// The columns are collected with a single query, its items are nested in pairs so the number of components isn't limited
+/// by the size of the query tuples.
 // This traits generate an struct like:
 // struct NetworkFrame {
 //    tick: u64,
 //    entities: Vec<NetworkID>,
-//    component_a: Vec<Option<ComponentA>>
-//    component_b: Vec<Option<ComponentA>>
+//    component_a: Vec<Option<Arc<ComponentA>>>
+//    component_b: Vec<Option<Arc<ComponentA>>>
+//    resource_resource_a: Option<Arc<ResourceA>>
 // }
 //
 // Instead of Vec<(NetworkID, Option<ComponentA>, Option<ComponentB>)> we store them in separeted vecs,
 // Easier to get and apply with ecs.
+// The components are shared between frames, only the ones that changed since the previous frame are cloned.
 #[macro_export]
 macro_rules! network_frame {
     ($($type:ty),+) => {
+        $crate::network_frame!($($type),+;);
+    };
+    ($($type:ty),+; $($resource:ty),*) => {
         $crate::paste::paste! {
             #[derive(Debug, PartialEq, Clone)]
             pub struct NetworkFrame {
                 tick: u64,
                 entities: Vec<$crate::NetworkID>,
                 $(
-                    [<$type:snake:lower>]: Vec<Option<<$type as $crate::NetworkedComponent>::Component>>,
+                    [<$type:snake:lower>]: Vec<Option<std::sync::Arc<<$type as $crate::NetworkedComponent>::Component>>>,
+                )*
+                $(
+                    [<resource_ $resource:snake:lower>]: Option<std::sync::Arc<<$resource as $crate::NetworkedResource>::Resource>>,
                 )*
             }
 
+            /// Keeps the query used to generate the frames between ticks, it tracks which components changed
+            /// since the previous frame was generated. Only the rows of the entities that changed are read again,
+            /// the other rows share the components of the previous frame.
+            pub struct NetworkFrameGenerator {
+                state: $crate::bevy::ecs::system::SystemState<(
+                    $crate::bevy::prelude::Query<'static, 'static, (
+                        $crate::bevy::prelude::Entity,
+                        &'static $crate::NetworkID,
+                        $crate::__frame_column_query!($($type),*)
+                    )>,
+                    $crate::bevy::prelude::Query<
+                        'static,
+                        'static,
+                        $crate::bevy::prelude::Entity,
+                        (
+                            $crate::bevy::prelude::With<$crate::NetworkID>,
+                            $crate::bevy::prelude::Or<(
+                                $crate::bevy::prelude::Changed<$crate::NetworkID>,
+                                $crate::__frame_changed_filter!($($type),*),
+                            )>,
+                        ),
+                    >,
+                )>,
+                // Entities of the rows of the previous frame
+                rows: Vec<$crate::bevy::prelude::Entity>,
+                network_ids: std::collections::HashMap<$crate::bevy::prelude::Entity, $crate::NetworkID>,
+                // Entities that lost their NetworkID or a component since the previous frame
+                removed: std::collections::HashSet<$crate::bevy::prelude::Entity>,
+                previous: Option<NetworkFrame>,
+                // Entities with components that kept their previous value because they couldn't be mapped,
+                // they are mapped again even if they didn't change
+                unmapped: std::collections::HashSet<$crate::bevy::prelude::Entity>,
+            }
+
             impl $crate::NetworkedFrame for NetworkFrame {
                 fn tick(&self) -> u64 {
                     self.tick
                 }
 
-                fn generate_frame(tick: u64, world: &mut $crate::bevy::prelude::World) -> Self {
-                    let entities = $crate::networked_entities(world);
+                fn entities(&self) -> &[$crate::NetworkID] {
+                    &self.entities
+                }
+
+                fn select_entities(&self, selected: &[bool], baseline: Option<&Self>) -> Self {
+                    let mut entities = Vec::with_capacity(self.entities.len());
                     $(
-                        let [<$type:snake:lower>] = $crate::networked_components::<$type>(world);
+                        let mut [<$type:snake:lower>] = Vec::with_capacity(self.entities.len());
                     )*
+                    for (i, network_id) in self.entities.iter().enumerate() {
+                        let (frame, index) = if selected[i] {
+                            (self, i)
+                        } else {
+                            match baseline.and_then(|baseline| Some((baseline, baseline.entities.binary_search(network_id).ok()?))) {
+                                Some(baseline_row) => baseline_row,
+                                None => continue,
+                            }
+                        };
+
+                        entities.push(*network_id);
+                        $(
+                            [<$type:snake:lower>].push(frame.[<$type:snake:lower>][index].clone());
+                        )*
+                    }
 
                     Self {
-                        tick,
+                        tick: self.tick,
                         entities,
                         $([<$type:snake:lower>],)*
+                        $([<resource_ $resource:snake:lower>]: self.[<resource_ $resource:snake:lower>].clone(),)*
                     }
                 }
 
-
-                fn apply_in_world(&self, world: &mut $crate::bevy::prelude::World) {
-                    world.resource_scope(|world, mut mapping: $crate::bevy::prelude::Mut<$crate::client::NetworkMapping>| {
-                        // Remove entities
-                        mapping.0.retain(|network_id, entity| {
-                            let removed = !self.entities.contains(network_id);
-                            if removed {
-                                world.despawn(*entity);
-                            }
-
-                            !removed
+                fn generate_frame(tick: u64, world: &mut $crate::bevy::prelude::World) -> Self {
+                    if !world.contains_resource::<NetworkFrameGenerator>() {
+                        let state = $crate::bevy::ecs::system::SystemState::new(world);
+                        world.insert_resource(NetworkFrameGenerator {
+                            state,
+                            rows: Vec::new(),
+                            network_ids: std::collections::HashMap::new(),
+                            removed: std::collections::HashSet::new(),
+                            previous: None,
+                            unmapped: std::collections::HashSet::new(),
                         });
+                    }
+                    <Self as $crate::NetworkedFrame>::track_removals(world);
+
+                    world.resource_scope(|world, mut generator: $crate::bevy::prelude::Mut<NetworkFrameGenerator>| {
+                        let generator = &mut *generator;
+                        let (query, changed) = generator.state.get(world);
+                        let previous = generator.previous.take();
+                        let previously_unmapped = std::mem::take(&mut generator.unmapped);
+
+                        // Entities that changed since the previous frame, all of them for the first frame
+                        let mut dirty = std::mem::take(&mut generator.removed);
+                        if previous.is_some() {
+                            dirty.extend(changed.iter());
+                        } else {
+                            dirty.extend(query.iter().map(|(entity, _, _)| entity));
+                        }
+                        dirty.extend(previously_unmapped.iter().copied());
+                        let (rows, despawned) = $crate::frame_rows(
+                            previous.as_ref().map_or(&[][..], |previous| &previous.entities),
+                            &generator.rows,
+                            &mut generator.network_ids,
+                            dirty,
+                            |entity| query.get(entity).ok().map(|(_, network_id, _)| *network_id),
+                        );
 
-                        // Create new networked entities
-                        for network_id in self.entities.iter() {
-                            if !mapping.0.contains_key(network_id) {
-                                let entity_id = world.spawn().insert($crate::NetworkID(network_id.0)).id();
-                                mapping.0.insert(*network_id, entity_id);
+                        $(
+                            let mut [<$type:snake:lower>] = Vec::with_capacity(rows.len());
+                        )*
+                        for row in rows.iter() {
+                            let previous_row = row.previous.and_then(|index| Some((previous.as_ref()?, index)));
+                            // Components referencing a despawned entity are mapped again
+                            let item = if row.generate || !despawned.is_empty() {
+                                query.get(row.entity).ok()
+                            } else {
+                                None
+                            };
+                            match item {
+                                Some((_, _, $crate::__frame_column_items!($([<$type:snake:lower _component>]),*))) => {
+                                    let share = !previously_unmapped.contains(&row.entity);
+                                    $(
+                                        let component = $crate::networked_component::<$type>(
+                                            [<$type:snake:lower _component>],
+                                            previous_row.map(|(previous, index)| &previous.[<$type:snake:lower>][index]),
+                                            share,
+                                            &despawned,
+                                            world
+                                        ).unwrap_or_else(|previous| {
+                                            generator.unmapped.insert(row.entity);
+                                            previous
+                                        });
+                                        [<$type:snake:lower>].push(component);
+                                    )*
+                                }
+                                None => {
+                                    $(
+                                        [<$type:snake:lower>].push(previous_row.and_then(|(previous, index)| previous.[<$type:snake:lower>][index].clone()));
+                                    )*
+                                }
                             }
                         }
 
+                        $(
+                            let [<resource_ $resource:snake:lower>] = $crate::networked_resource::<$resource>(
+                                world,
+                                previous.as_ref().map(|previous| &previous.[<resource_ $resource:snake:lower>])
+                            );
+                        )*
+
+                        generator.rows = rows.iter().map(|row| row.entity).collect();
+                        let frame = Self {
+                            tick,
+                            entities: rows.iter().map(|row| row.network_id).collect(),
+                            $([<$type:snake:lower>],)*
+                            $([<resource_ $resource:snake:lower>],)*
+                        };
+                        generator.previous = Some(frame.clone());
+
+                        frame
+                    })
+                }
+
+                fn track_removals(world: &mut $crate::bevy::prelude::World) {
+                    let mut removed: Vec<$crate::bevy::prelude::Entity> = world.removed::<$crate::NetworkID>().collect();
+                    $(
+                        removed.extend(world.removed::<<$type as $crate::NetworkedComponent>::Component>());
+                    )*
+                    if let Some(mut generator) = world.get_resource_mut::<NetworkFrameGenerator>() {
+                        generator.removed.extend(removed);
+                    }
+                }
+
+                fn remove_generator(world: &mut $crate::bevy::prelude::World) {
+                    world.remove_resource::<NetworkFrameGenerator>();
+                }
+
+                fn hide_components(&mut self, client_id: u64, context: &$crate::visibility::VisibilityContext) {
+                    $(
+                        $crate::visibility::hide_components::<$type>(
+                            client_id,
+                            context,
+                            &self.entities,
+                            &mut self.[<$type:snake:lower>]
+                        );
+                    )*
+                }
+
+                fn hides_components(&self) -> bool {
+                    false $(|| $crate::hides_component::<$type>())*
+                }
+
+                fn entity_bits(&self, index: usize, baseline: Option<&Self>, writer: &mut $crate::BitWriter) -> Result<usize, std::io::Error> {
+                    let network_id = self.entities[index];
+                    let baseline_row = baseline.and_then(|baseline| Some((baseline, baseline.entities.binary_search(&network_id).ok()?)));
+                    let mut bits = match baseline_row {
+                        Some(_) => 0,
+                        None => $crate::spawned_entity_bits(network_id),
+                    };
+                    $(
+                        bits += $crate::component_bits::<$type>(
+                            writer,
+                            &self.[<$type:snake:lower>][index],
+                            baseline_row.map(|(baseline, row)| &baseline.[<$type:snake:lower>][row])
+                        )?;
+                    )*
+
+                    Ok(bits)
+                }
+
+                fn apply_in_world(&self, world: &mut $crate::bevy::prelude::World, previous: Option<&Self>) {
+                    world.resource_scope(|world, mut mapping: $crate::bevy::prelude::Mut<$crate::client::NetworkMapping>| {
+                        let previous_entities = previous.map_or(&[][..], |previous| &previous.entities);
+                        $crate::client::sync_network_entities(world, &mut mapping, &self.entities, previous_entities);
+
                         // Replicate components
                         $(
-                            for (i, network_id) in self.entities.iter().enumerate() {
-                                if let Some(component) = &self.[<$type:snake:lower>][i] {
-                                    // Should always exist a mapped entity by now
-                                    let mapped_entity = mapping.0.get(network_id).unwrap();
-                                    let entity_mut = world.entity_mut(*mapped_entity);
-                                    <$type as $crate::NetworkedComponent>::apply(entity_mut, component);
-                                }
-                            }
+                            $crate::apply_components::<$type>(
+                                world,
+                                &mapping,
+                                &self.entities,
+                                &self.[<$type:snake:lower>],
+                                previous_entities,
+                                previous.map_or(&[][..], |previous| &previous.[<$type:snake:lower>])
+                            );
+                        )*
+
+                        // Replicate resources
+                        $(
+                            $crate::apply_resource::<$resource>(
+                                world,
+                                &self.[<resource_ $resource:snake:lower>],
+                                previous.and_then(|previous| previous.[<resource_ $resource:snake:lower>].as_ref())
+                            );
                         )*
                     });
                 }
 
                 fn write_full_frame(&self, writer: &mut $crate::BitWriter) -> Result<(), std::io::Error> {
-                    $crate::write_frame_header(writer, self.tick, None, &self.entities)?;
+                    $crate::write_frame_header(writer, self.tick, None)?;
+                    $crate::write_full_entities(writer, &self.entities)?;
 
                     $(
                         $crate::write_full_component::<$type>(writer, &self.[<$type:snake:lower>])?;
                     )*
+                    $(
+                        $crate::write_full_resource::<$resource>(writer, &self.[<resource_ $resource:snake:lower>])?;
+                    )*
 
                     Ok(())
                 }
 
                 fn write_delta_frame(&self, writer: &mut $crate::BitWriter, delta_frame: &Self) -> Result<(), std::io::Error> {
-                    $crate::write_frame_header(writer, self.tick, Some(delta_frame.tick), &self.entities)?;
+                    $crate::write_frame_header(writer, self.tick, Some(delta_frame.tick))?;
+                    $crate::write_delta_entities(writer, &self.entities, &delta_frame.entities)?;
                     let delta_mapping = $crate::generate_delta_mapping(&delta_frame.entities, &self.entities);
 
                     $(
@@ -163,83 +461,361 @@ macro_rules! network_frame {
                             &delta_mapping
                         )?;
                     )*
+                    $(
+                        $crate::write_delta_resource::<$resource>(writer, &self.[<resource_ $resource:snake:lower>], &delta_frame.[<resource_ $resource:snake:lower>])?;
+                    )*
 
                     Ok(())
                 }
 
-                fn read_frame(reader: &mut $crate::BitReader, world: &mut $crate::bevy::prelude::World) -> Result<Self, std::io::Error> {
+                fn read_frame(reader: &mut $crate::BitReader, world: &mut $crate::bevy::prelude::World) -> Result<Self, $crate::ReplicationError> {
+                    let max_id_bits = world.resource::<$crate::client::NetworkMapping>().id_bits();
                     let header = $crate::read_frame_header(reader)?;
                     if let Some(delta_tick) = header.delta_tick {
                         let frame_buffer = world.resource::<$crate::client::SnapshotInterpolationBuffer<Self>>();
                         if let Some(delta_frame) = frame_buffer.buffer.get(delta_tick) {
-                            let delta_mapping = $crate::generate_delta_mapping(&delta_frame.entities, &header.entities);
+                            let entities = $crate::read_delta_entities(reader, max_id_bits, &delta_frame.entities)?;
+                            let delta_mapping = $crate::generate_delta_mapping(&delta_frame.entities, &entities);
                             $(
                                 let [<$type:snake:lower>] = $crate::read_delta_component::<$type>(
                                     reader,
-                                    &header.entities,
+                                    &entities,
                                     &delta_frame.[<$type:snake:lower>],
                                     &delta_mapping
                                 )?;
                             )*
+                            $(
+                                let [<resource_ $resource:snake:lower>] = $crate::read_delta_resource::<$resource>(reader, &delta_frame.[<resource_ $resource:snake:lower>])?;
+                            )*
 
                             Ok(Self {
                                 tick: header.tick,
-                                entities: header.entities,
+                                entities,
                                 $([<$type:snake:lower>],)*
+                                $([<resource_ $resource:snake:lower>],)*
                             })
                         } else {
-                            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Delta frame not available"));
+                            return Err($crate::ReplicationError::UnknownBaseline { tick: delta_tick });
                         }
                     } else {
+                        let entities = $crate::read_full_entities(reader, max_id_bits)?;
+                        $(
+                            let [<$type:snake:lower>] = $crate::read_full_component::<$type>(reader, entities.len())?;
+                        )*
                         $(
-                            let [<$type:snake:lower>] = $crate::read_full_component::<$type>(reader, header.entities.len())?;
+                            let [<resource_ $resource:snake:lower>] = $crate::read_full_resource::<$resource>(reader)?;
                         )*
 
                         Ok(Self {
                             tick: header.tick,
-                            entities: header.entities,
+                            entities,
                             $([<$type:snake:lower>],)*
+                            $([<resource_ $resource:snake:lower>],)*
                         })
                     }
                 }
+
+                fn dump(&self, baseline: Option<&Self>) -> Result<$crate::dump::FrameDump, std::io::Error> {
+                    let mut dump = $crate::dump::FrameDump::new(self, baseline)?;
+                    $(
+                        dump.add_components::<$type>(
+                            &self.[<$type:snake:lower>],
+                            baseline.map(|baseline| (&baseline.entities[..], &baseline.[<$type:snake:lower>][..]))
+                        )?;
+                    )*
+                    $(
+                        dump.add_resource::<$resource>(
+                            &self.[<resource_ $resource:snake:lower>],
+                            baseline.map(|baseline| &baseline.[<resource_ $resource:snake:lower>])
+                        )?;
+                    )*
+
+                    Ok(dump)
+                }
             }
 
         }
     }
 }
 
-pub fn networked_entities(world: &mut World) -> Vec<NetworkID> {
-    let mut query = world.query::<&NetworkID>();
-    query.iter(world).copied().collect()
+// Nested query of the frame columns: (A, (B, (C, ()))).
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __frame_column_query {
+    () => {
+        ()
+    };
+    ($type:ty $(, $rest:ty)*) => {
+        ($crate::FrameColumnQuery<$type>, $crate::__frame_column_query!($($rest),*))
+    };
+}
+
+// Filter of the entities with a frame column that changed: Or<(Changed<A>, Or<(Changed<B>, Changed<C>)>)>.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __frame_changed_filter {
+    ($type:ty) => {
+        $crate::bevy::prelude::Changed<<$type as $crate::NetworkedComponent>::Component>
+    };
+    ($type:ty, $($rest:ty),+) => {
+        $crate::bevy::prelude::Or<(
+            $crate::bevy::prelude::Changed<<$type as $crate::NetworkedComponent>::Component>,
+            $crate::__frame_changed_filter!($($rest),+),
+        )>
+    };
+}
+
+// Pattern of the items returned by the nested query of the frame columns.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __frame_column_items {
+    () => {
+        ()
+    };
+    ($item:ident $(, $rest:ident)*) => {
+        ($item, $crate::__frame_column_items!($($rest),*))
+    };
+}
+
+/// Query items used to generate a frame column, the component and its change ticks.
+pub type FrameColumnQuery<T> = (
+    Option<&'static <T as NetworkedComponent>::Component>,
+    Option<ChangeTrackers<<T as NetworkedComponent>::Component>>,
+);
+
+/// Row of a frame generated by [`network_frame!`].
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRow {
+    pub network_id: NetworkID,
+    pub entity: Entity,
+    /// Row of the entity in the previous frame.
+    pub previous: Option<usize>,
+    /// Whether the components are read again, the other rows share the components of the previous frame.
+    pub generate: bool,
+}
+
+impl FrameRow {
+    fn added(network_id: NetworkID, entity: Entity) -> Self {
+        Self {
+            network_id,
+            entity,
+            previous: None,
+            generate: true,
+        }
+    }
+}
+
+/// Rows of the next frame: the rows of the previous frame without the entities that lost their NetworkID,
+/// and the entities that got one, sorted by NetworkID. Only the `dirty` entities, the ones that changed since
+/// the previous frame, are looked up with `network_id`, `network_ids` keeps the NetworkID of each row.
+/// Returns the despawned NetworkIDs too, sorted.
+#[doc(hidden)]
+pub fn frame_rows(
+    previous_entities: &[NetworkID],
+    previous_rows: &[Entity],
+    network_ids: &mut HashMap<Entity, NetworkID>,
+    dirty: impl IntoIterator<Item = Entity>,
+    network_id: impl Fn(Entity) -> Option<NetworkID>,
+) -> (Vec<FrameRow>, Vec<NetworkID>) {
+    let mut changed = HashSet::new();
+    let mut removed = HashSet::new();
+    let mut added = Vec::new();
+    for entity in dirty {
+        let previous_id = network_ids.get(&entity).copied();
+        let current_id = network_id(entity);
+        if previous_id == current_id {
+            changed.insert(entity);
+            continue;
+        }
+
+        if let Some(previous_id) = previous_id {
+            removed.insert(previous_id);
+            network_ids.remove(&entity);
+        }
+        if let Some(current_id) = current_id {
+            added.push((current_id, entity));
+            network_ids.insert(entity, current_id);
+        }
+    }
+    added.sort_unstable();
+
+    let mut despawned: Vec<NetworkID> = removed
+        .iter()
+        .filter(|network_id| added.binary_search_by_key(network_id, |(added_id, _)| added_id).is_err())
+        .copied()
+        .collect();
+    despawned.sort_unstable();
+
+    let mut rows = Vec::with_capacity(previous_entities.len() + added.len());
+    let mut added = added.into_iter().peekable();
+    for (index, (network_id, entity)) in previous_entities.iter().zip(previous_rows.iter()).enumerate() {
+        if removed.contains(network_id) {
+            continue;
+        }
+        while let Some((added_id, added_entity)) = added.next_if(|(added_id, _)| added_id < network_id) {
+            rows.push(FrameRow::added(added_id, added_entity));
+        }
+        rows.push(FrameRow {
+            network_id: *network_id,
+            entity: *entity,
+            previous: Some(index),
+            generate: changed.contains(entity),
+        });
+    }
+    rows.extend(added.map(|(network_id, entity)| FrameRow::added(network_id, entity)));
+
+    (rows, despawned)
+}
+
+// Entities of the previous frame missing from the current one, both sorted by NetworkID.
+pub fn despawned_entities(previous_entities: &[NetworkID], entities: &[NetworkID]) -> Vec<NetworkID> {
+    previous_entities
+        .iter()
+        .filter(|network_id| entities.binary_search(network_id).is_err())
+        .copied()
+        .collect()
+}
+
+// Fails to map the despawned entities, to find the components referencing them.
+struct DespawnedMapper<'a>(&'a [NetworkID]);
+
+impl NetworkEntityMapper for DespawnedMapper<'_> {
+    fn map(&self, entity: Entity) -> Option<Entity> {
+        match self.0.binary_search(&NetworkID::from_entity(entity)) {
+            Ok(_) => None,
+            Err(_) => Some(entity),
+        }
+    }
 }
 
-pub fn networked_components<T: NetworkedComponent>(world: &mut World) -> Vec<Option<T::Component>> {
-    let mut query = world.query_filtered::<Option<&T::Component>, With<NetworkID>>();
-    query.iter(world).map(|c| c.cloned()).collect()
+// Components that didn't change since the previous frame share its value instead of being cloned again,
+// unless it references despawned entities, it would keep their stale NetworkIDs.
+// Components referencing entities that don't have a NetworkID yet return an error with the value of the previous frame
+// to keep, a missing component is removed on the clients. They are replicated once the referenced entities are.
+#[allow(clippy::type_complexity)]
+pub fn networked_component<T: NetworkedComponent>(
+    (component, change_trackers): (Option<&T::Component>, Option<ChangeTrackers<T::Component>>),
+    previous: Option<&Option<Arc<T::Component>>>,
+    share: bool,
+    despawned: &[NetworkID],
+    world: &World,
+) -> Result<Option<Arc<T::Component>>, Option<Arc<T::Component>>> {
+    let changed = change_trackers.map_or(true, |change_trackers| change_trackers.is_changed());
+    changed_component::<T>(component, changed, previous, share, despawned, world)
+}
+
+// Same as networked_component, with the change of the component already checked.
+#[allow(clippy::type_complexity)]
+pub(crate) fn changed_component<T: NetworkedComponent>(
+    component: Option<&T::Component>,
+    changed: bool,
+    previous: Option<&Option<Arc<T::Component>>>,
+    share: bool,
+    despawned: &[NetworkID],
+    world: &World,
+) -> Result<Option<Arc<T::Component>>, Option<Arc<T::Component>>> {
+    if let (true, false, Some(Some(previous))) = (share, changed, previous) {
+        let stale = !despawned.is_empty() && T::map_entities(&mut T::Component::clone(previous), &DespawnedMapper(despawned)).is_none();
+        if !stale {
+            return Ok(Some(previous.clone()));
+        }
+    }
+
+    let mut component = match component {
+        Some(component) => component.clone(),
+        None => return Ok(None),
+    };
+    match T::map_entities(&mut component, world) {
+        Some(()) => Ok(Some(Arc::new(component))),
+        None => Err(previous.cloned().flatten()),
+    }
 }
 
-pub fn write_frame_header(writer: &mut BitWriter, tick: u64, delta_tick: Option<u64>, entities: &[NetworkID]) -> Result<(), io::Error> {
+/// Whether the component is hidden from some clients, see [`NetworkedComponent::visibility`].
+pub fn hides_component<T: NetworkedComponent>() -> bool {
+    !matches!(T::visibility(), ComponentVisibility::Everyone)
+}
+
+/// Estimated bits written for an entity spawned since the baseline frame: its index and its generation.
+pub fn spawned_entity_bits(network_id: NetworkID) -> usize {
+    varint_bits(network_id.index as u64) + GENERATION_BITS
+}
+
+/// Estimated bits written for a component against its value in the baseline frame, measured in the scratch writer.
+/// Only the components written or removed count their 2 bits change code, the unchanged ones cost almost nothing
+/// with the smallest change encoding. The budgets check the encoded frame for the rest of the change encodings.
+pub fn component_bits<T: NetworkedComponent>(
+    writer: &mut BitWriter,
+    component: &Option<Arc<T::Component>>,
+    previous: Option<&Option<Arc<T::Component>>>,
+) -> Result<usize, io::Error> {
+    let start = writer.bits_written();
+    match (previous, component) {
+        (Some(None), None) => return Ok(0),
+        (Some(Some(previous)), Some(component)) if previous == component => return Ok(0),
+        (_, None) => {}
+        (Some(Some(previous)), Some(component)) if T::can_delta(previous, component) => T::write_delta(previous, component, writer)?,
+        (_, Some(component)) => T::write_full(component, writer)?,
+    }
+
+    Ok(writer.bits_written() - start + 2)
+}
+
+/// Apply the components in the client world, the components present in the previously applied frame
+/// and missing in this one are removed.
+pub fn apply_components<T: NetworkedComponent>(
+    world: &mut World,
+    mapping: &NetworkMapping,
+    entities: &[NetworkID],
+    components: &[Option<Arc<T::Component>>],
+    previous_entities: &[NetworkID],
+    previous_components: &[Option<Arc<T::Component>>],
+) {
+    for (network_id, component) in entities.iter().zip(components.iter()) {
+        let component = match component {
+            Some(component) => component,
+            None => {
+                // Frames are sorted by NetworkID
+                let removed = match previous_entities.binary_search(network_id) {
+                    Ok(index) => previous_components[index].is_some(),
+                    Err(_) => false,
+                };
+                if let (true, Some(entity)) = (removed, mapping.entities.get(network_id)) {
+                    T::remove(world.entity_mut(*entity));
+                }
+                continue;
+            }
+        };
+
+        let mut component = T::Component::clone(component);
+        // Referenced entities that are not spawned yet are retried when the next frame is applied
+        if T::map_entities(&mut component, mapping).is_none() {
+            continue;
+        }
+
+        // Should always exist a mapped entity by now
+        let mapped_entity = mapping.entities.get(network_id).unwrap();
+        let entity_mut = world.entity_mut(*mapped_entity);
+        T::apply(entity_mut, &component);
+    }
+}
+
+pub fn write_frame_header(writer: &mut BitWriter, tick: u64, delta_tick: Option<u64>) -> Result<(), io::Error> {
     writer.write_bool(delta_tick.is_some())?;
     if let Some(delta_tick) = delta_tick {
         writer.write_varint_u64(delta_tick)?;
     }
-    writer.write_varint_u64(tick)?;
-    writer.write_varint_u16(entities.len() as u16)?;
-    for network_id in entities.iter() {
-        writer.write_bits(network_id.0 as u32, 12)?;
-    }
-
-    Ok(())
+    writer.write_varint_u64(tick)
 }
 
 #[derive(Debug)]
 pub struct FrameHeader {
     pub tick: u64,
     pub delta_tick: Option<u64>,
-    pub entities: Vec<NetworkID>,
 }
 
-pub fn read_frame_header(reader: &mut BitReader) -> Result<FrameHeader, io::Error> {
+pub fn read_frame_header(reader: &mut BitReader) -> Result<FrameHeader, ReplicationError> {
     let is_delta = reader.read_bool()?;
 
     let delta_tick = if is_delta {
@@ -250,27 +826,197 @@ pub fn read_frame_header(reader: &mut BitReader) -> Result<FrameHeader, io::Erro
     };
 
     let tick = reader.read_varint_u64()?;
-    let len = reader.read_varint_u16()? as usize;
-    if len > network_entity::MAX_LENGTH {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "network entities length above limit"));
+
+    Ok(FrameHeader { tick, delta_tick })
+}
+
+// Number of bits used by a varint, each byte carries 7 bits of the value.
+fn varint_bits(value: u64) -> usize {
+    let bits = (u64::BITS - value.leading_zeros()).max(1) as usize;
+    (bits + 6) / 7 * 8
+}
+
+// The entities are sorted and their indices are unique, so the indices are written as the gaps between them:
+// the first gap is the index itself and the following ones are the distance to the previous index minus one.
+fn index_gaps(entities: &[NetworkID]) -> impl Iterator<Item = u64> + '_ {
+    let mut next = 0;
+    entities.iter().map(move |network_id| {
+        let gap = network_id.index - next;
+        next = network_id.index + 1;
+        gap as u64
+    })
+}
+
+fn write_index_gaps(writer: &mut BitWriter, entities: &[NetworkID]) -> Result<(), io::Error> {
+    for gap in index_gaps(entities) {
+        writer.write_varint_u64(gap)?;
     }
-    let mut entities = Vec::with_capacity(len);
+
+    Ok(())
+}
+
+fn read_index_gaps(reader: &mut BitReader, len: usize, max_id_bits: usize) -> Result<Vec<u32>, ReplicationError> {
+    let max_entities = network_entity::max_entities(max_id_bits) as u64;
+    // The length comes from the network, only trust it after the indices were actually read
+    let mut indices = Vec::with_capacity(len.min(1024));
+    let mut next: u64 = 0;
     for _ in 0..len {
-        let network_id = reader.read_bits(12)? as u16;
-        let network_id = NetworkID(network_id);
-        entities.push(network_id);
+        let index = next.saturating_add(reader.read_varint_u64()?);
+        if index >= max_entities {
+            return Err(ReplicationError::EntityLimit { id_bits: max_id_bits });
+        }
+        indices.push(index as u32);
+        next = index + 1;
     }
 
-    Ok(FrameHeader {
-        tick,
-        delta_tick,
-        entities,
-    })
+    Ok(indices)
+}
+
+fn write_generations(writer: &mut BitWriter, entities: &[NetworkID]) -> Result<(), io::Error> {
+    for network_id in entities.iter() {
+        writer.write_bits(network_id.generation as u32, GENERATION_BITS)?;
+    }
+
+    Ok(())
+}
+
+fn read_generations(reader: &mut BitReader, indices: &[u32]) -> Result<Vec<NetworkID>, ReplicationError> {
+    let mut entities = Vec::with_capacity(indices.len());
+    for &index in indices.iter() {
+        let generation = reader.read_bits(GENERATION_BITS)? as u8;
+        entities.push(NetworkID::new(index, generation));
+    }
+
+    Ok(entities)
+}
+
+fn is_sorted(entities: &[NetworkID]) -> bool {
+    entities.windows(2).all(|pair| pair[0].index < pair[1].index)
+}
+
+/// Write the entities of a full frame. The sorted indices are written either as the gaps between them
+/// or as a bitset of the indices in use, whichever is smaller, followed by the generations.
+pub fn write_full_entities(writer: &mut BitWriter, entities: &[NetworkID]) -> Result<(), io::Error> {
+    debug_assert!(is_sorted(entities), "frame entities must be sorted by NetworkID");
+
+    writer.write_varint_u64(entities.len() as u64)?;
+    if let Some(last) = entities.last() {
+        let bitset_len = last.index as u64 + 1;
+        let bitset_bits = varint_bits(bitset_len) + bitset_len as usize;
+        let gap_bits: usize = index_gaps(entities).map(varint_bits).sum();
+
+        let use_bitset = bitset_bits < gap_bits;
+        writer.write_bool(use_bitset)?;
+        if use_bitset {
+            writer.write_varint_u64(bitset_len)?;
+            let mut entities = entities.iter().peekable();
+            for index in 0..=last.index {
+                let in_use = entities.next_if(|network_id| network_id.index == index).is_some();
+                writer.write_bool(in_use)?;
+            }
+        } else {
+            write_index_gaps(writer, entities)?;
+        }
+    }
+
+    write_generations(writer, entities)
+}
+
+pub fn read_full_entities(reader: &mut BitReader, max_id_bits: usize) -> Result<Vec<NetworkID>, ReplicationError> {
+    let max_entities = network_entity::max_entities(max_id_bits) as u64;
+    let len = reader.read_varint_u64()?;
+    if len > max_entities {
+        return Err(ReplicationError::EntityLimit { id_bits: max_id_bits });
+    }
+    let len = len as usize;
+
+    let mut indices = Vec::new();
+    if len > 0 {
+        let use_bitset = reader.read_bool()?;
+        if use_bitset {
+            let bitset_len = reader.read_varint_u64()?;
+            if bitset_len > max_entities {
+                return Err(ReplicationError::EntityLimit { id_bits: max_id_bits });
+            }
+            for index in 0..bitset_len as u32 {
+                if reader.read_bool()? {
+                    indices.push(index);
+                }
+            }
+            if indices.len() != len {
+                return Err(ReplicationError::InvalidData("network entities bitset doesn't match the length"));
+            }
+        } else {
+            indices = read_index_gaps(reader, len, max_id_bits)?;
+        }
+    }
+
+    read_generations(reader, &indices)
+}
+
+/// Write the entities of a delta frame as the entities despawned and spawned since the baseline frame.
+/// Despawned entities are written only with their index, it's unique in the baseline frame.
+/// A recycled index is written as despawned and spawned again with the new generation.
+pub fn write_delta_entities(writer: &mut BitWriter, entities: &[NetworkID], baseline: &[NetworkID]) -> Result<(), io::Error> {
+    debug_assert!(is_sorted(entities), "frame entities must be sorted by NetworkID");
+
+    let despawned: Vec<NetworkID> = baseline.iter().filter(|id| entities.binary_search(id).is_err()).copied().collect();
+    let spawned: Vec<NetworkID> = entities.iter().filter(|id| baseline.binary_search(id).is_err()).copied().collect();
+
+    writer.write_varint_u64(despawned.len() as u64)?;
+    write_index_gaps(writer, &despawned)?;
+
+    writer.write_varint_u64(spawned.len() as u64)?;
+    write_index_gaps(writer, &spawned)?;
+    write_generations(writer, &spawned)
+}
+
+/// Rebuild the sorted entities of a delta frame from the baseline frame.
+pub fn read_delta_entities(reader: &mut BitReader, max_id_bits: usize, baseline: &[NetworkID]) -> Result<Vec<NetworkID>, ReplicationError> {
+    let despawned_len = reader.read_varint_u64()?;
+    if despawned_len > baseline.len() as u64 {
+        return Err(ReplicationError::InvalidData(
+            "despawned entities length above baseline frame length",
+        ));
+    }
+    let despawned = read_index_gaps(reader, despawned_len as usize, max_id_bits)?;
+
+    let spawned_len = reader.read_varint_u64()?;
+    if spawned_len > network_entity::max_entities(max_id_bits) as u64 {
+        return Err(ReplicationError::EntityLimit { id_bits: max_id_bits });
+    }
+    let spawned_indices = read_index_gaps(reader, spawned_len as usize, max_id_bits)?;
+    let spawned = read_generations(reader, &spawned_indices)?;
+
+    let mut retained = Vec::with_capacity(baseline.len());
+    retained.extend(baseline.iter().filter(|id| despawned.binary_search(&id.index).is_err()));
+    if retained.len() + despawned.len() != baseline.len() {
+        return Err(ReplicationError::InvalidData("despawned entity not found in baseline frame"));
+    }
+
+    // Merge the spawned entities keeping the entities sorted
+    let mut entities = Vec::with_capacity(retained.len() + spawned.len());
+    let mut retained = retained.into_iter().peekable();
+    for spawned_id in spawned {
+        while let Some(network_id) = retained.next_if(|id| id.index < spawned_id.index) {
+            entities.push(network_id);
+        }
+        if retained.peek().map_or(false, |id| id.index == spawned_id.index) {
+            return Err(ReplicationError::InvalidData("spawned entity already in baseline frame"));
+        }
+        entities.push(spawned_id);
+    }
+    entities.extend(retained);
+
+    Ok(entities)
 }
 
 // When serializing a Vec<Option<Component>> without delta, we use 1 bit for each component to
 // check if there is Some(component) and do a full write.
-pub fn write_full_component<T: NetworkedComponent>(writer: &mut BitWriter, components: &[Option<T::Component>]) -> Result<(), io::Error> {
+pub fn write_full_component<T: NetworkedComponent>(
+    writer: &mut BitWriter,
+    components: &[Option<Arc<T::Component>>],
+) -> Result<(), io::Error> {
     for component in components.iter() {
         writer.write_bool(component.is_some())?;
     }
@@ -285,18 +1031,18 @@ pub fn write_full_component<T: NetworkedComponent>(writer: &mut BitWriter, compo
 pub fn read_full_component<T: NetworkedComponent>(
     reader: &mut BitReader,
     entities_len: usize,
-) -> Result<Vec<Option<T::Component>>, io::Error> {
+) -> Result<Vec<Option<Arc<T::Component>>>, ReplicationError> {
     let mut has_components = Vec::with_capacity(entities_len);
     for _ in 0..entities_len {
         let has_component = reader.read_bool()?;
         has_components.push(has_component);
     }
 
-    let mut components: Vec<Option<T::Component>> = Vec::with_capacity(entities_len);
+    let mut components: Vec<Option<Arc<T::Component>>> = Vec::with_capacity(entities_len);
     for &has_component in has_components.iter() {
         if has_component {
-            let component = T::read_full(reader)?;
-            components.push(Some(component));
+            let component = T::read_full(reader).map_err(ReplicationError::component::<T>)?;
+            components.push(Some(Arc::new(component)));
         } else {
             components.push(None);
         }
@@ -308,46 +1054,166 @@ pub fn read_full_component<T: NetworkedComponent>(
 pub fn generate_delta_mapping(previous_entities: &[NetworkID], current_entities: &[NetworkID]) -> HashMap<NetworkID, usize> {
     let mut map: HashMap<NetworkID, usize> = HashMap::new();
     for new in current_entities.iter() {
-        if let Some(position) = previous_entities.iter().position(|old| old == new) {
+        // Frames are sorted by NetworkID
+        if let Ok(position) = previous_entities.binary_search(new) {
             map.insert(*new, position);
         }
     }
     map
 }
 
-// When serializing a Vec<Option<Component>> with delta, we use 2 bits to see what happened since
+// The change codes of a column are written with the smallest of these encodings, 2 bits for the encoding:
+//
+//   Fixed   -> 2 bits for each code
+//   Flagged -> 1 bit for each entity to check if it changed, followed by the 2 bits code for the changed ones
+//   Runs    -> Varint with the length of each run of NoChange, followed by the 2 bits code ending the run.
+//              The last run ends with the frame and has no code
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeEncoding {
+    Fixed,
+    Flagged,
+    Runs,
+}
+
+impl ChangeEncoding {
+    fn bits(&self, changes: &[ComponentChange]) -> usize {
+        match self {
+            ChangeEncoding::Fixed => changes.len() * 2,
+            ChangeEncoding::Flagged => changes.len() + changed(changes).count() * 2,
+            ChangeEncoding::Runs => {
+                let mut bits = 0;
+                let mut run = 0;
+                for change in changes.iter() {
+                    match change {
+                        ComponentChange::NoChange => run += 1,
+                        _ => {
+                            bits += varint_bits(run) + 2;
+                            run = 0;
+                        }
+                    }
+                }
+                if run > 0 {
+                    bits += varint_bits(run);
+                }
+                bits
+            }
+        }
+    }
+}
+
+fn changed(changes: &[ComponentChange]) -> impl Iterator<Item = &ComponentChange> {
+    changes.iter().filter(|change| !matches!(change, ComponentChange::NoChange))
+}
+
+fn write_changes(writer: &mut BitWriter, changes: &[ComponentChange]) -> Result<(), io::Error> {
+    // The first encoding is kept on ties
+    let encoding = [ChangeEncoding::Fixed, ChangeEncoding::Flagged, ChangeEncoding::Runs]
+        .into_iter()
+        .min_by_key(|encoding| encoding.bits(changes))
+        .unwrap();
+    writer.write_bits(encoding as u32, 2)?;
+
+    match encoding {
+        ChangeEncoding::Fixed => {
+            for change in changes.iter() {
+                writer.write_bits(*change as u32, 2)?;
+            }
+        }
+        ChangeEncoding::Flagged => {
+            for change in changes.iter() {
+                writer.write_bool(!matches!(change, ComponentChange::NoChange))?;
+            }
+            for change in changed(changes) {
+                writer.write_bits(*change as u32, 2)?;
+            }
+        }
+        ChangeEncoding::Runs => {
+            let mut run = 0;
+            for change in changes.iter() {
+                match change {
+                    ComponentChange::NoChange => run += 1,
+                    _ => {
+                        writer.write_varint_u64(run)?;
+                        writer.write_bits(*change as u32, 2)?;
+                        run = 0;
+                    }
+                }
+            }
+            if run > 0 {
+                writer.write_varint_u64(run)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_change(reader: &mut BitReader) -> Result<ComponentChange, ReplicationError> {
+    let change = reader.read_bits(2)? as u8;
+    ComponentChange::try_from(change).map_err(|_| ReplicationError::InvalidChange(change))
+}
+
+fn read_changes(reader: &mut BitReader, len: usize) -> Result<Vec<ComponentChange>, ReplicationError> {
+    let encoding = match reader.read_bits(2)? {
+        0 => ChangeEncoding::Fixed,
+        1 => ChangeEncoding::Flagged,
+        2 => ChangeEncoding::Runs,
+        encoding => return Err(ReplicationError::InvalidChange(encoding as u8)),
+    };
+
+    let mut changes = Vec::with_capacity(len);
+    match encoding {
+        ChangeEncoding::Fixed => {
+            for _ in 0..len {
+                changes.push(read_change(reader)?);
+            }
+        }
+        ChangeEncoding::Flagged => {
+            let mut flags = Vec::with_capacity(len);
+            for _ in 0..len {
+                flags.push(reader.read_bool()?);
+            }
+            for changed in flags {
+                let change = if changed { read_change(reader)? } else { ComponentChange::NoChange };
+                changes.push(change);
+            }
+        }
+        ChangeEncoding::Runs => {
+            while changes.len() < len {
+                let run = reader.read_varint_u64()?;
+                if run > (len - changes.len()) as u64 {
+                    return Err(ReplicationError::InvalidData("change run longer than frame"));
+                }
+                changes.extend(std::iter::repeat(ComponentChange::NoChange).take(run as usize));
+                if changes.len() < len {
+                    changes.push(read_change(reader)?);
+                }
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+// When serializing a Vec<Option<Component>> with delta, we use a code to see what happened since
 // the delta frame:
 //
 //   FullChange  -> We can't delta with the old component or there is none to compare, full write
 //                  the component
 //   NoComponent -> No component in this frame, write nothing
-//   NoChange    -> The component is the same, write nothing
+//   NoChange    -> The component is the same or still missing, write nothing
 //   DeltaChange -> The component has change and we can delta encode with the old one, delta write
 //
 pub fn write_delta_component<T: NetworkedComponent>(
     writer: &mut BitWriter,
     entities: &[NetworkID],
-    current_components: &[Option<T::Component>],
-    previous_components: &[Option<T::Component>],
+    current_components: &[Option<Arc<T::Component>>],
+    previous_components: &[Option<Arc<T::Component>>],
     delta_mapping: &HashMap<NetworkID, usize>,
 ) -> Result<(), io::Error> {
-    let mut changes: Vec<ComponentChange> = Vec::with_capacity(current_components.len());
-    let mut write_change = |change: ComponentChange| -> Result<(), io::Error> {
-        changes.push(change);
-        writer.write_bits(change as u32, 2)
-    };
-    for (i, current) in current_components.iter().enumerate() {
-        let previous = delta_mapping
-            .get(&entities[i])
-            .and_then(|index| previous_components[*index].as_ref());
-        match (previous, current) {
-            (_, None) => write_change(ComponentChange::NoComponent)?,
-            (None, Some(_)) => write_change(ComponentChange::FullChange)?,
-            (Some(previous), Some(current)) if previous == current => write_change(ComponentChange::NoChange)?,
-            (Some(previous), Some(current)) if T::can_delta(previous, current) => write_change(ComponentChange::DeltaChange)?,
-            (Some(_), Some(_)) => write_change(ComponentChange::FullChange)?,
-        }
-    }
+    let changes = component_changes::<T>(entities, current_components, previous_components, delta_mapping);
+    write_changes(writer, &changes)?;
 
     for (i, change) in changes.iter().enumerate() {
         match change {
@@ -371,46 +1237,73 @@ pub fn write_delta_component<T: NetworkedComponent>(
     Ok(())
 }
 
-pub fn read_delta_component<T: NetworkedComponent>(
-    reader: &mut BitReader,
+/// Change of each component since the delta frame, the code written for it by [`write_delta_component`].
+pub fn component_changes<T: NetworkedComponent>(
     entities: &[NetworkID],
-    previous_components: &[Option<T::Component>],
+    current_components: &[Option<Arc<T::Component>>],
+    previous_components: &[Option<Arc<T::Component>>],
     delta_mapping: &HashMap<NetworkID, usize>,
-) -> Result<Vec<Option<T::Component>>, io::Error> {
-    let mut changes = Vec::with_capacity(entities.len());
-    for _ in 0..entities.len() {
-        let change = reader.read_bits(2)? as u8;
-        // Reading 2 bits should always return a valid ComponentChange id
-        let change = ComponentChange::try_from(change).unwrap();
+) -> Vec<ComponentChange> {
+    let mut changes: Vec<ComponentChange> = Vec::with_capacity(current_components.len());
+    for (i, current) in current_components.iter().enumerate() {
+        let previous_index = delta_mapping.get(&entities[i]);
+        let previous = previous_index.and_then(|index| previous_components[*index].as_ref());
+        let change = match (previous, current) {
+            (None, None) if previous_index.is_some() => ComponentChange::NoChange,
+            (_, None) => ComponentChange::NoComponent,
+            (None, Some(_)) => ComponentChange::FullChange,
+            (Some(previous), Some(current)) if previous == current => ComponentChange::NoChange,
+            (Some(previous), Some(current)) if T::can_delta(previous, current) => ComponentChange::DeltaChange,
+            (Some(_), Some(_)) => ComponentChange::FullChange,
+        };
         changes.push(change);
     }
 
-    let mut components: Vec<Option<T::Component>> = Vec::with_capacity(entities.len());
+    changes
+}
+
+pub fn read_delta_component<T: NetworkedComponent>(
+    reader: &mut BitReader,
+    entities: &[NetworkID],
+    previous_components: &[Option<Arc<T::Component>>],
+    delta_mapping: &HashMap<NetworkID, usize>,
+) -> Result<Vec<Option<Arc<T::Component>>>, ReplicationError> {
+    let changes = read_changes(reader, entities.len())?;
+
+    let mut components: Vec<Option<Arc<T::Component>>> = Vec::with_capacity(entities.len());
     for (i, change) in changes.iter().enumerate() {
         match change {
             ComponentChange::FullChange => {
-                let component = T::read_full(reader)?;
-                components.push(Some(component));
+                let component = T::read_full(reader).map_err(ReplicationError::component::<T>)?;
+                components.push(Some(Arc::new(component)));
             }
             ComponentChange::NoComponent => {
                 components.push(None);
             }
             ComponentChange::NoChange => match delta_mapping.get(&entities[i]) {
-                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Component not found in delta mapping")),
+                None => {
+                    return Err(ReplicationError::InvalidData(
+                        "component change for an entity not in the baseline frame",
+                    ))
+                }
                 Some(index) => {
                     let component = previous_components[*index].clone();
                     components.push(component);
                 }
             },
             ComponentChange::DeltaChange => match delta_mapping.get(&entities[i]) {
-                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Component not found in delta mapping")),
+                None => {
+                    return Err(ReplicationError::InvalidData(
+                        "component change for an entity not in the baseline frame",
+                    ))
+                }
                 Some(index) => {
                     let previous_component = match &previous_components[*index] {
                         Some(component) => component,
-                        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Component for delta encoding not found")),
+                        None => return Err(ReplicationError::InvalidData("component delta without a baseline component")),
                     };
-                    let component = T::read_delta(previous_component, reader)?;
-                    components.push(Some(component));
+                    let component = T::read_delta(previous_component, reader).map_err(ReplicationError::component::<T>)?;
+                    components.push(Some(Arc::new(component)));
                 }
             },
         }
@@ -419,71 +1312,219 @@ pub fn read_delta_component<T: NetworkedComponent>(
     Ok(components)
 }
 
+// Unchanged resources share the value of the previous frame.
+pub fn networked_resource<T: NetworkedResource>(world: &World, previous: Option<&Option<Arc<T::Resource>>>) -> Option<Arc<T::Resource>> {
+    let resource = world.get_resource::<T::Resource>()?;
+    match previous {
+        Some(Some(previous)) if **previous == *resource => Some(previous.clone()),
+        _ => Some(Arc::new(resource.clone())),
+    }
+}
+
+pub fn apply_resource<T: NetworkedResource>(world: &mut World, resource: &Option<Arc<T::Resource>>, previous: Option<&Arc<T::Resource>>) {
+    match (resource, previous) {
+        // Applied again only when it changed, so the resource isn't marked as changed every frame
+        (Some(resource), Some(previous)) if Arc::ptr_eq(resource, previous) || resource == previous => {}
+        (Some(resource), _) => T::apply(world, resource),
+        (None, Some(_)) => T::remove(world),
+        (None, None) => {}
+    }
+}
+
+pub fn write_full_resource<T: NetworkedResource>(writer: &mut BitWriter, resource: &Option<Arc<T::Resource>>) -> Result<(), io::Error> {
+    writer.write_bool(resource.is_some())?;
+    if let Some(resource) = resource {
+        T::write_full(resource, writer)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_full_resource<T: NetworkedResource>(reader: &mut BitReader) -> Result<Option<Arc<T::Resource>>, ReplicationError> {
+    if reader.read_bool()? {
+        let resource = T::read_full(reader).map_err(ReplicationError::resource::<T>)?;
+        Ok(Some(Arc::new(resource)))
+    } else {
+        Ok(None)
+    }
+}
+
+// Resources are written with the same 2 bits codes of the delta components.
+pub fn write_delta_resource<T: NetworkedResource>(
+    writer: &mut BitWriter,
+    current: &Option<Arc<T::Resource>>,
+    previous: &Option<Arc<T::Resource>>,
+) -> Result<(), io::Error> {
+    let change = resource_change::<T>(current, previous);
+    writer.write_bits(change as u32, 2)?;
+    match (change, previous, current) {
+        (ComponentChange::DeltaChange, Some(previous), Some(current)) => T::write_delta(previous, current, writer),
+        (ComponentChange::FullChange, _, Some(current)) => T::write_full(current, writer),
+        _ => Ok(()),
+    }
+}
+
+/// Change of the resource since the delta frame, the code written for it by [`write_delta_resource`].
+pub fn resource_change<T: NetworkedResource>(current: &Option<Arc<T::Resource>>, previous: &Option<Arc<T::Resource>>) -> ComponentChange {
+    match (previous, current) {
+        (None, None) => ComponentChange::NoChange,
+        (_, None) => ComponentChange::NoComponent,
+        (Some(previous), Some(current)) if previous == current => ComponentChange::NoChange,
+        (Some(previous), Some(current)) if T::can_delta(previous, current) => ComponentChange::DeltaChange,
+        (_, Some(_)) => ComponentChange::FullChange,
+    }
+}
+
+pub fn read_delta_resource<T: NetworkedResource>(
+    reader: &mut BitReader,
+    previous: &Option<Arc<T::Resource>>,
+) -> Result<Option<Arc<T::Resource>>, ReplicationError> {
+    match read_change(reader)? {
+        ComponentChange::FullChange => {
+            let resource = T::read_full(reader).map_err(ReplicationError::resource::<T>)?;
+            Ok(Some(Arc::new(resource)))
+        }
+        ComponentChange::NoComponent => Ok(None),
+        ComponentChange::NoChange => Ok(previous.clone()),
+        ComponentChange::DeltaChange => match previous {
+            Some(previous) => {
+                let resource = T::read_delta(previous, reader).map_err(ReplicationError::resource::<T>)?;
+                Ok(Some(Arc::new(resource)))
+            }
+            None => Err(ReplicationError::InvalidData("resource delta without a baseline resource")),
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
     use bevy::prelude::Component;
 
-    use crate::client::SnapshotInterpolationBuffer;
+    use crate::client::{NetworkMapping, SnapshotInterpolationBuffer};
+    use crate::network_entity::{DEFAULT_ID_BITS, MIN_ID_BITS};
+    use crate::test_utils::{column, NetworkFrame, Secret, Simple};
+    use crate::{read_entity, write_entity};
 
     use super::*;
 
     #[derive(Debug, Component, PartialEq, Eq, Clone)]
-    struct Simple(u32);
+    struct Target(Entity);
 
-    impl NetworkedComponent for Simple {
+    impl NetworkedComponent for Target {
         type Component = Self;
 
-        fn can_delta(old: &Self::Component, new: &Self::Component) -> bool {
-            new.0.abs_diff(old.0) < 32
-        }
-
         fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
-            writer.write_u32(component.0)
+            write_entity(writer, component.0)
         }
 
         fn read_full(reader: &mut BitReader) -> Result<Self::Component, io::Error> {
-            let value = reader.read_u32()?;
+            Ok(Self(read_entity(reader)?))
+        }
 
-            Ok(Self(value))
+        fn map_entities(component: &mut Self::Component, mapper: &dyn NetworkEntityMapper) -> Option<()> {
+            component.0 = mapper.map(component.0)?;
+            Some(())
         }
+    }
+
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    struct Score(u32);
 
-        fn write_delta(old: &Self::Component, new: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
-            let diff = new.0.abs_diff(old.0);
-            writer.write_bool(new.0 > old.0)?;
-            writer.write_bits(diff, 5)
+    impl NetworkedResource for Score {
+        type Resource = Self;
+
+        fn can_delta(old: &Self::Resource, new: &Self::Resource) -> bool {
+            new.0 >= old.0 && new.0 - old.0 < 16
         }
 
-        fn read_delta(old: &Self::Component, reader: &mut BitReader) -> Result<Self::Component, io::Error> {
-            let sign = reader.read_bool()?;
-            let diff = reader.read_bits(5)?;
-            let value = if sign { old.0 + diff } else { old.0 - diff };
+        fn write_full(resource: &Self::Resource, writer: &mut BitWriter) -> Result<(), io::Error> {
+            writer.write_u32(resource.0)
+        }
 
-            Ok(Self(value))
+        fn read_full(reader: &mut BitReader) -> Result<Self::Resource, io::Error> {
+            Ok(Self(reader.read_u32()?))
         }
+
+        fn write_delta(old: &Self::Resource, new: &Self::Resource, writer: &mut BitWriter) -> Result<(), io::Error> {
+            writer.write_bits(new.0 - old.0, 4)
+        }
+
+        fn read_delta(old: &Self::Resource, reader: &mut BitReader) -> Result<Self::Resource, io::Error> {
+            Ok(Self(old.0 + reader.read_bits(4)?))
+        }
+    }
+
+    mod with_resources {
+        use super::{Score, Simple};
+
+        crate::network_frame!(Simple; Score);
+    }
+
+    mod with_target {
+        use super::Target;
+
+        crate::network_frame!(Target);
     }
 
-    network_frame!(Simple);
+    mod with_transform {
+        use super::Simple;
+        use crate::networked_transform::TransformNetworked;
+
+        crate::network_frame!(Simple, TransformNetworked);
+    }
+
+    mod with_many_components {
+        use super::*;
+
+        macro_rules! counters {
+            ($($name:ident),*) => {
+                $(
+                    #[derive(Debug, Component, PartialEq, Eq, Clone)]
+                    pub struct $name(pub u8);
+
+                    impl NetworkedComponent for $name {
+                        type Component = Self;
+
+                        fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
+                            writer.write_bits(component.0 as u32, 8)
+                        }
+
+                        fn read_full(reader: &mut BitReader) -> Result<Self::Component, io::Error> {
+                            Ok(Self(reader.read_bits(8)? as u8))
+                        }
+                    }
+                )*
+
+                crate::network_frame!($($name),*);
+            };
+        }
+
+        // More components than the largest query tuple
+        counters!(C0, C1, C2, C3, C4, C5, C6, C7, C8, C9, C10, C11, C12, C13, C14, C15, C16, C17);
+    }
 
     #[test]
     fn test_full() {
-        let frame = NetworkFrame {
-            tick: 0,                                    // 8 bits + 1 bit for delta frame bool + 8 bits for len = 17 bits
-            entities: vec![NetworkID(0), NetworkID(1)], // 2 * 12 = 24 bits
+        let frame = NetworkFrame::new(
+            0, // 8 bits + 1 bit for delta frame bool + 8 bits for len = 17 bits
+            // 1 bit for bitset + 8 bits for bitset len + 2 bits bitset + 2 * 8 bits for generations = 27 bits
+            vec![NetworkID::new(0, 0), NetworkID::new(1, 0)],
             // Changes: 2 * 1 = 2
-            simple: vec![Some(Simple(10)), None], // 1 full = 32 bits
-        };
-        // 17 + 24 + 2 + 32 = 75 bits written
+            vec![Some(Simple(10)), None], // 1 full = 32 bits
+        );
+        // 17 + 27 + 2 + 32 = 78 bits written
 
         let mut writer = BitWriter::with_capacity(100);
         frame.write_full_frame(&mut writer).unwrap();
-        assert_eq!(writer.bits_written(), 75);
+        assert_eq!(writer.bits_written(), 78);
 
         let buffer = writer.consume().unwrap();
         let mut reader = BitReader::new(&buffer).unwrap();
 
         let mut world = bevy::prelude::World::new();
+        world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
         let read_frame = NetworkFrame::read_frame(&mut reader, &mut world).unwrap();
 
         assert_eq!(frame, read_frame);
@@ -498,17 +1539,32 @@ mod tests {
         // 4 -> From Some -> None
         // 10 -> Created entity with Some(..)
         // 11 -> Created entity with None
-        let first_frame = NetworkFrame {
-            tick: 0,
-            entities: vec![NetworkID(0), NetworkID(1), NetworkID(2), NetworkID(3), NetworkID(4)],
-            simple: vec![Some(Simple(10)), Some(Simple(0)), Some(Simple(0)), None, Some(Simple(4))],
-        };
-
-        let second_frame = NetworkFrame {
-            tick: 0, // 8 bits + 1 bit for delta frame bool + 8 bits for delta tick + 8 bits for len = 25 bits
-            entities: vec![NetworkID(0), NetworkID(1), NetworkID(3), NetworkID(4), NetworkID(10), NetworkID(11)], // 12 * 6 = 72 bits
-            simple: vec![
-                // Changes 2 * 6 = 12 bits
+        let first_frame = NetworkFrame::new(
+            0,
+            vec![
+                NetworkID::new(0, 0),
+                NetworkID::new(1, 0),
+                NetworkID::new(2, 0),
+                NetworkID::new(3, 0),
+                NetworkID::new(4, 0),
+            ],
+            vec![Some(Simple(10)), Some(Simple(0)), Some(Simple(0)), None, Some(Simple(4))],
+        );
+
+        let second_frame = NetworkFrame::new(
+            0, // 8 bits + 1 bit for delta frame bool + 8 bits for delta tick = 17 bits
+            // Despawned: 8 bits for len + 8 bits gap = 16 bits
+            // Spawned: 8 bits for len + 2 * 8 bits gaps + 2 * 8 bits generations = 40 bits
+            vec![
+                NetworkID::new(0, 0),
+                NetworkID::new(1, 0),
+                NetworkID::new(3, 0),
+                NetworkID::new(4, 0),
+                NetworkID::new(10, 0),
+                NetworkID::new(11, 0),
+            ],
+            vec![
+                // Changes 2 bits encoding + 2 * 6 = 14 bits
                 // Already had entity
                 Some(Simple(16)),
                 Some(Simple(100)),
@@ -518,10 +1574,11 @@ mod tests {
                 Some(Simple(50)),
                 None,
             ],
-        };
-        // 25 + 72 + 12 + 102 = 211 bits written
+        );
+        // 17 + 56 + 14 + 102 = 189 bits written
 
         let mut world = bevy::prelude::World::new();
+        world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
         let mut buffer = SnapshotInterpolationBuffer::new(5, Duration::ZERO, 60.);
         buffer.add_snapshot(Duration::ZERO, first_frame.clone());
         world.insert_resource(buffer);
@@ -529,7 +1586,7 @@ mod tests {
         let mut writer = BitWriter::with_capacity(100);
         second_frame.write_delta_frame(&mut writer, &first_frame).unwrap();
 
-        assert_eq!(writer.bits_written(), 211);
+        assert_eq!(writer.bits_written(), 189);
 
         let buffer = writer.consume().unwrap();
         let mut reader = BitReader::new(&buffer).unwrap();
@@ -538,4 +1595,435 @@ mod tests {
 
         assert_eq!(second_frame, read_frame);
     }
+
+    #[test]
+    fn test_change_encoding() {
+        let entities: Vec<NetworkID> = (0..100).map(|index| NetworkID::new(index, 0)).collect();
+        let delta_mapping = generate_delta_mapping(&entities, &entities);
+        let previous = column(vec![Some(Simple(0)); 100]);
+        let mut current = previous.clone();
+        current[50] = Some(Arc::new(Simple(1)));
+
+        // 2 bits encoding + 8 bits varint run + 2 bits code + 8 bits varint run = 20 bits + 6 bits delta
+        let mut writer = BitWriter::with_capacity(100);
+        write_delta_component::<Simple>(&mut writer, &entities, &current, &previous, &delta_mapping).unwrap();
+        assert_eq!(writer.bits_written(), 26);
+
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_components = read_delta_component::<Simple>(&mut reader, &entities, &previous, &delta_mapping).unwrap();
+        assert_eq!(read_components, current);
+
+        // Every entity changed, the codes are written with 2 bits
+        let current = column((0..100).map(|value| Some(Simple(value + 1))).collect());
+        let mut writer = BitWriter::with_capacity(1000);
+        write_delta_component::<Simple>(&mut writer, &entities, &current, &previous, &delta_mapping).unwrap();
+        assert_eq!(writer.bits_written(), 2 + 100 * 2 + 31 * 6 + 69 * 32);
+
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_components = read_delta_component::<Simple>(&mut reader, &entities, &previous, &delta_mapping).unwrap();
+        assert_eq!(read_components, current);
+    }
+
+    #[test]
+    fn test_networked_resources() {
+        use with_resources::NetworkFrame as ResourceFrame;
+
+        let mut server_world = bevy::prelude::World::new();
+        server_world.insert_resource(Score(10));
+        let first_frame = ResourceFrame::generate_frame(0, &mut server_world);
+        server_world.insert_resource(Score(12));
+        let second_frame = ResourceFrame::generate_frame(1, &mut server_world);
+        server_world.remove_resource::<Score>();
+        let third_frame = ResourceFrame::generate_frame(2, &mut server_world);
+
+        let mut client_world = bevy::prelude::World::new();
+        client_world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        client_world.insert_resource(SnapshotInterpolationBuffer::<ResourceFrame>::new(5, Duration::ZERO, 60.));
+
+        let mut writer = BitWriter::with_capacity(100);
+        first_frame.write_full_frame(&mut writer).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_first = ResourceFrame::read_frame(&mut reader, &mut client_world).unwrap();
+        assert_eq!(read_first, first_frame);
+        read_first.apply_in_world(&mut client_world, None);
+        assert_eq!(client_world.get_resource::<Score>(), Some(&Score(10)));
+        client_world
+            .resource_mut::<SnapshotInterpolationBuffer<ResourceFrame>>()
+            .add_snapshot(Duration::ZERO, read_first.clone());
+
+        // 17 bits header + 16 bits entities + 2 bits change encoding + 2 bits change + 4 bits delta = 41 bits
+        let mut writer = BitWriter::with_capacity(100);
+        second_frame.write_delta_frame(&mut writer, &first_frame).unwrap();
+        assert_eq!(writer.bits_written(), 41);
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_second = ResourceFrame::read_frame(&mut reader, &mut client_world).unwrap();
+        assert_eq!(read_second, second_frame);
+        read_second.apply_in_world(&mut client_world, Some(&read_first));
+        assert_eq!(client_world.get_resource::<Score>(), Some(&Score(12)));
+
+        // Unchanged resources are not applied again
+        client_world.clear_trackers();
+        read_second.clone().apply_in_world(&mut client_world, Some(&read_second));
+        assert!(!client_world.is_resource_changed::<Score>());
+
+        // Removed on the server
+        let mut writer = BitWriter::with_capacity(100);
+        third_frame.write_delta_frame(&mut writer, &first_frame).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_third = ResourceFrame::read_frame(&mut reader, &mut client_world).unwrap();
+        assert_eq!(read_third, third_frame);
+        read_third.apply_in_world(&mut client_world, Some(&read_second));
+        assert!(client_world.get_resource::<Score>().is_none());
+    }
+
+    #[test]
+    fn test_entities_encoding() {
+        // Sparse entities use the gaps: 8 bits for len + 1 bit for bitset + (8 + 16 + 16) bits gaps + 3 * 8 bits generations
+        let entities = vec![NetworkID::new(3, 0), NetworkID::new(200, 1), NetworkID::new(1000, 0)];
+        let mut writer = BitWriter::with_capacity(100);
+        write_full_entities(&mut writer, &entities).unwrap();
+        assert_eq!(writer.bits_written(), 73);
+
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        assert_eq!(read_full_entities(&mut reader, DEFAULT_ID_BITS).unwrap(), entities);
+
+        // Indices above the configured width are rejected
+        let mut reader = BitReader::new(&buffer).unwrap();
+        assert!(matches!(
+            read_full_entities(&mut reader, MIN_ID_BITS),
+            Err(ReplicationError::EntityLimit { id_bits: MIN_ID_BITS })
+        ));
+
+        // Index 200 is recycled, it's despawned and spawned again with the new generation
+        let baseline = entities;
+        let entities = vec![NetworkID::new(3, 0), NetworkID::new(7, 0), NetworkID::new(200, 2)];
+        let mut writer = BitWriter::with_capacity(100);
+        write_delta_entities(&mut writer, &entities, &baseline).unwrap();
+
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        assert_eq!(read_delta_entities(&mut reader, DEFAULT_ID_BITS, &baseline).unwrap(), entities);
+
+        // The despawned entities must exist in the baseline
+        let mut reader = BitReader::new(&buffer).unwrap();
+        assert!(read_delta_entities(&mut reader, DEFAULT_ID_BITS, &[NetworkID::new(3, 0)]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_frames() {
+        let baseline = NetworkFrame::new(1, vec![NetworkID::new(0, 0)], vec![Some(Simple(10))]);
+        let mut world = bevy::prelude::World::new();
+        world.insert_resource(NetworkMapping::new(MIN_ID_BITS));
+        let mut buffer = SnapshotInterpolationBuffer::new(5, Duration::ZERO, 60.);
+        buffer.add_snapshot(Duration::ZERO, baseline.clone());
+        world.insert_resource(buffer);
+
+        let mut read = |buffer: Vec<u8>| {
+            let mut reader = BitReader::new(&buffer).unwrap();
+            NetworkFrame::read_frame(&mut reader, &mut world)
+        };
+
+        // Delta against a frame the client doesn't have
+        let mut writer = BitWriter::with_capacity(100);
+        write_frame_header(&mut writer, 3, Some(2)).unwrap();
+        assert!(matches!(
+            read(writer.consume().unwrap()),
+            Err(ReplicationError::UnknownBaseline { tick: 2 })
+        ));
+
+        // More entities than the id_bits allow
+        let mut writer = BitWriter::with_capacity(100);
+        write_frame_header(&mut writer, 3, None).unwrap();
+        writer
+            .write_varint_u64(network_entity::max_entities(MIN_ID_BITS) as u64 + 1)
+            .unwrap();
+        assert!(matches!(
+            read(writer.consume().unwrap()),
+            Err(ReplicationError::EntityLimit { id_bits: MIN_ID_BITS })
+        ));
+
+        // Change encodings only use the values 0 to 2
+        let mut writer = BitWriter::with_capacity(100);
+        write_frame_header(&mut writer, 3, Some(1)).unwrap();
+        write_delta_entities(&mut writer, baseline.entities(), baseline.entities()).unwrap();
+        writer.write_bits(3, 2).unwrap();
+        assert!(matches!(read(writer.consume().unwrap()), Err(ReplicationError::InvalidChange(3))));
+
+        // The component is missing its data
+        let mut writer = BitWriter::with_capacity(100);
+        write_frame_header(&mut writer, 3, None).unwrap();
+        write_full_entities(&mut writer, baseline.entities()).unwrap();
+        writer.write_bool(true).unwrap();
+        match read(writer.consume().unwrap()) {
+            Err(ReplicationError::Component { component, .. }) => assert_eq!(component, std::any::type_name::<Simple>()),
+            result => panic!("expected component error, got {:?}", result),
+        }
+
+        // Delta change for a component without delta encoding
+        let entities = vec![NetworkID::new(0, 0)];
+        let mut writer = BitWriter::with_capacity(100);
+        writer.write_bits(ChangeEncoding::Fixed as u32, 2).unwrap();
+        writer.write_bits(ComponentChange::DeltaChange as u32, 2).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let previous = column(vec![Some(Secret(1))]);
+        let delta_mapping = generate_delta_mapping(&entities, &entities);
+        assert!(matches!(
+            read_delta_component::<Secret>(&mut reader, &entities, &previous, &delta_mapping),
+            Err(ReplicationError::Component { .. })
+        ));
+
+        let error = ReplicationError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(error, ReplicationError::Truncated));
+    }
+
+    #[test]
+    fn test_generate_sorted_frame() {
+        let mut world = bevy::prelude::World::new();
+        world.spawn().insert(NetworkID::new(2, 0)).insert(Simple(2));
+        world.spawn().insert(NetworkID::new(0, 0));
+        world.spawn().insert(NetworkID::new(1, 0)).insert(Simple(1));
+
+        let frame = NetworkFrame::generate_frame(0, &mut world);
+        assert_eq!(
+            frame.entities(),
+            vec![NetworkID::new(0, 0), NetworkID::new(1, 0), NetworkID::new(2, 0)]
+        );
+        assert_eq!(frame.simple(), column(vec![None, Some(Simple(1)), Some(Simple(2))]));
+    }
+
+    #[test]
+    fn test_apply_in_world() {
+        use crate::networked_transform::InterpolateTransform;
+        use bevy::prelude::Transform;
+        use with_transform::NetworkFrame as TransformFrame;
+
+        let mut server_world = bevy::prelude::World::new();
+        let server_entity = server_world
+            .spawn()
+            .insert(NetworkID::new(0, 0))
+            .insert(Simple(1))
+            .insert(Transform::default())
+            .id();
+        server_world.spawn().insert(NetworkID::new(1, 0)).insert(Simple(2));
+        let first_frame = TransformFrame::generate_frame(0, &mut server_world);
+        server_world.entity_mut(server_entity).remove::<Simple>().remove::<Transform>();
+        let second_frame = TransformFrame::generate_frame(1, &mut server_world);
+
+        let mut client_world = bevy::prelude::World::new();
+        client_world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        first_frame.apply_in_world(&mut client_world, None);
+        let mapping = client_world.resource::<NetworkMapping>();
+        let (entity, other_entity) = (mapping.entities[&NetworkID::new(0, 0)], mapping.entities[&NetworkID::new(1, 0)]);
+        assert_eq!(client_world.get::<Simple>(entity), Some(&Simple(1)));
+        assert!(client_world.get::<Transform>(entity).is_some());
+        assert!(client_world.get::<InterpolateTransform>(entity).is_some());
+
+        // Components missing since the previous frame are removed, TransformNetworked also removes its interpolation
+        second_frame.apply_in_world(&mut client_world, Some(&first_frame));
+        assert!(client_world.get_entity(entity).is_some());
+        assert!(client_world.get::<Simple>(entity).is_none());
+        assert!(client_world.get::<Transform>(entity).is_none());
+        assert!(client_world.get::<InterpolateTransform>(entity).is_none());
+        assert_eq!(client_world.get::<Simple>(other_entity), Some(&Simple(2)));
+    }
+
+    #[test]
+    fn test_many_components() {
+        use with_many_components::{NetworkFrame as ManyFrame, *};
+
+        let mut world = bevy::prelude::World::new();
+        world
+            .spawn()
+            .insert(NetworkID::new(0, 0))
+            .insert_bundle((C0(0), C1(1), C2(2), C3(3), C4(4), C5(5), C6(6), C7(7), C8(8)))
+            .insert_bundle((C9(9), C10(10), C11(11), C12(12), C13(13), C14(14), C15(15), C16(16), C17(17)));
+        let frame = ManyFrame::generate_frame(0, &mut world);
+        let dump = frame.dump(None).unwrap();
+        let values: Vec<String> = dump.entities[0].components.iter().filter_map(|value| value.value.clone()).collect();
+        assert_eq!(values.len(), 18);
+        assert_eq!(values[17], "C17(17)");
+
+        let mut writer = BitWriter::with_capacity(1000);
+        frame.write_full_frame(&mut writer).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let mut client_world = bevy::prelude::World::new();
+        client_world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        assert_eq!(ManyFrame::read_frame(&mut reader, &mut client_world).unwrap(), frame);
+    }
+
+    #[test]
+    fn test_shared_components() {
+        use with_target::NetworkFrame as TargetFrame;
+
+        let mut world = bevy::prelude::World::new();
+        let moving = world.spawn().insert(NetworkID::new(0, 0)).insert(Simple(0)).id();
+        world.spawn().insert(NetworkID::new(1, 0)).insert(Simple(1));
+
+        let first_frame = NetworkFrame::generate_frame(0, &mut world);
+        world.get_mut::<Simple>(moving).unwrap().0 = 5;
+        let second_frame = NetworkFrame::generate_frame(1, &mut world);
+
+        // Only the changed component is cloned, the other one is shared with the previous frame
+        assert_eq!(second_frame.simple(), column(vec![Some(Simple(5)), Some(Simple(1))]));
+        let first = first_frame.simple()[1].as_ref().unwrap();
+        let second = second_frame.simple()[1].as_ref().unwrap();
+        assert!(Arc::ptr_eq(first, second));
+        assert!(!Arc::ptr_eq(
+            first_frame.simple()[0].as_ref().unwrap(),
+            second_frame.simple()[0].as_ref().unwrap()
+        ));
+
+        // Despawning an entity still shares the components that don't reference it
+        world.despawn(moving);
+        let third_frame = NetworkFrame::generate_frame(2, &mut world);
+        assert_eq!(third_frame.simple(), column(vec![Some(Simple(1))]));
+        assert!(Arc::ptr_eq(second, third_frame.simple()[0].as_ref().unwrap()));
+
+        // The components referencing a despawned NetworkID are mapped again
+        let mut world = bevy::prelude::World::new();
+        let target = world.spawn().insert(NetworkID::new(0, 0)).id();
+        world.spawn().insert(NetworkID::new(1, 0)).insert(Target(target));
+        TargetFrame::generate_frame(0, &mut world);
+        world.entity_mut(target).insert(NetworkID::new(2, 0));
+        let frame = TargetFrame::generate_frame(1, &mut world);
+        assert_eq!(frame.entities(), &[NetworkID::new(1, 0), NetworkID::new(2, 0)]);
+
+        let mut client_world = bevy::prelude::World::new();
+        client_world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        frame.apply_in_world(&mut client_world, None);
+        let mapping = client_world.resource::<NetworkMapping>();
+        let (local_entity, local_target) = (mapping.entities[&NetworkID::new(1, 0)], mapping.entities[&NetworkID::new(2, 0)]);
+        assert_eq!(client_world.get::<Target>(local_entity), Some(&Target(local_target)));
+    }
+
+    #[test]
+    fn test_removed_components() {
+        let mut world = bevy::prelude::World::new();
+        let entity = world.spawn().insert(NetworkID::new(0, 0)).insert(Simple(0)).id();
+        world.spawn().insert(NetworkID::new(1, 0)).insert(Simple(1));
+        NetworkFrame::generate_frame(0, &mut world);
+
+        // The removals tracked in previous updates are kept until the next frame
+        world.entity_mut(entity).remove::<Simple>();
+        NetworkFrame::track_removals(&mut world);
+        world.clear_trackers();
+        let frame = NetworkFrame::generate_frame(1, &mut world);
+        assert_eq!(frame.entities(), &[NetworkID::new(0, 0), NetworkID::new(1, 0)]);
+        assert_eq!(frame.simple(), column(vec![None, Some(Simple(1))]));
+
+        world.entity_mut(entity).remove::<NetworkID>();
+        NetworkFrame::track_removals(&mut world);
+        world.clear_trackers();
+        let frame = NetworkFrame::generate_frame(2, &mut world);
+        assert_eq!(frame.entities(), &[NetworkID::new(1, 0)]);
+        assert_eq!(frame.simple(), column(vec![Some(Simple(1))]));
+    }
+
+    #[test]
+    fn test_map_entities() {
+        let mut server_world = bevy::prelude::World::new();
+        let target = server_world.spawn().insert(NetworkID::new(1, 2)).id();
+        let not_replicated = server_world.spawn().id();
+        let replicated = server_world.spawn().insert(NetworkID::new(3, 0)).insert(Target(target)).id();
+        let unmapped = server_world
+            .spawn()
+            .insert(NetworkID::new(4, 0))
+            .insert(Target(not_replicated))
+            .id();
+
+        // The component targeting an entity without NetworkID is not replicated yet
+        let network_target = Target(NetworkID::new(1, 2).to_entity());
+        let mut query = server_world.query::<FrameColumnQuery<Target>>();
+        let item = query.get(&server_world, replicated).unwrap();
+        let component = networked_component::<Target>(item, None, true, &[], &server_world);
+        assert_eq!(component, Ok(Some(Arc::new(network_target.clone()))));
+        let item = query.get(&server_world, unmapped).unwrap();
+        let component = networked_component::<Target>(item, None, true, &[], &server_world);
+        assert_eq!(component, Err(None));
+
+        // It keeps the value of the previous frame instead, so the clients don't remove it
+        let item = query.get(&server_world, unmapped).unwrap();
+        let previous = Some(Arc::new(network_target.clone()));
+        let component = networked_component::<Target>(item, Some(&previous), false, &[], &server_world);
+        assert_eq!(component, Err(previous));
+
+        let mut writer = BitWriter::with_capacity(100);
+        Target::write_full(&network_target, &mut writer).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_target = Target::read_full(&mut reader).unwrap();
+        assert_eq!(read_target, network_target);
+
+        let mut client_world = bevy::prelude::World::new();
+        let mut mapping = NetworkMapping::new(DEFAULT_ID_BITS);
+        let local_entity = client_world.spawn().id();
+        mapping.entities.insert(NetworkID::new(3, 0), local_entity);
+
+        // Target not spawned yet, the component is not applied
+        apply_components::<Target>(
+            &mut client_world,
+            &mapping,
+            &[NetworkID::new(3, 0)],
+            &[Some(Arc::new(read_target.clone()))],
+            &[],
+            &[],
+        );
+        assert!(client_world.get::<Target>(local_entity).is_none());
+
+        let local_target = client_world.spawn().id();
+        mapping.entities.insert(NetworkID::new(1, 2), local_target);
+        let applied = vec![Some(Arc::new(read_target))];
+        apply_components::<Target>(&mut client_world, &mapping, &[NetworkID::new(3, 0)], &applied, &[], &[]);
+        assert_eq!(client_world.get::<Target>(local_entity), Some(&Target(local_target)));
+
+        // Removed on the server since the previously applied frame
+        apply_components::<Target>(
+            &mut client_world,
+            &mapping,
+            &[NetworkID::new(3, 0)],
+            &[None],
+            &[NetworkID::new(3, 0)],
+            &applied,
+        );
+        assert!(client_world.get::<Target>(local_entity).is_none());
+    }
+
+    #[test]
+    fn test_unmapped_references() {
+        use with_target::NetworkFrame as TargetFrame;
+
+        let mut server_world = bevy::prelude::World::new();
+        let first_target = server_world.spawn().insert(NetworkID::new(0, 0)).id();
+        let second_target = server_world.spawn().id();
+        let entity = server_world.spawn().insert(NetworkID::new(1, 0)).insert(Target(first_target)).id();
+        let first_frame = TargetFrame::generate_frame(0, &mut server_world);
+
+        let mut client_world = bevy::prelude::World::new();
+        client_world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        first_frame.apply_in_world(&mut client_world, None);
+        let mapping = client_world.resource::<NetworkMapping>();
+        let (local_entity, local_target) = (mapping.entities[&NetworkID::new(1, 0)], mapping.entities[&NetworkID::new(0, 0)]);
+        assert_eq!(client_world.get::<Target>(local_entity), Some(&Target(local_target)));
+
+        // The new target doesn't have a NetworkID yet, the frame keeps the previous target
+        server_world.get_mut::<Target>(entity).unwrap().0 = second_target;
+        let second_frame = TargetFrame::generate_frame(1, &mut server_world);
+        second_frame.apply_in_world(&mut client_world, Some(&first_frame));
+        assert_eq!(client_world.get::<Target>(local_entity), Some(&Target(local_target)));
+
+        // Replicated once the target is, even though the component didn't change since
+        server_world.entity_mut(second_target).insert(NetworkID::new(2, 0));
+        let third_frame = TargetFrame::generate_frame(2, &mut server_world);
+        third_frame.apply_in_world(&mut client_world, Some(&second_frame));
+        let local_second_target = client_world.resource::<NetworkMapping>().entities[&NetworkID::new(2, 0)];
+        assert_eq!(client_world.get::<Target>(local_entity), Some(&Target(local_second_target)));
+    }
 }
@@ -0,0 +1,179 @@
+use crate::{client::NetworkMapping, network_frame::NetworkedComponent, read_network_id, write_network_id, NetworkID};
+
+use bevy::prelude::*;
+use bit_serializer::{BitReader, BitWriter};
+use std::{collections::HashMap, io};
+
+/// Replicated parent of a networked entity.
+///
+/// On the server it is kept in sync with the bevy [`Parent`] of entities with a NetworkID, the parent is
+/// only replicated when it also has a NetworkID. Once an entity had a networked parent the component is
+/// kept with `None` when the parent is removed, so the client also detaches it.
+/// On the client the bevy hierarchy is rebuilt from it through the [`NetworkMapping`].
+///
+/// The plugins only keep it in sync, list it in the [`network_frame!`](crate::network_frame) of the stream,
+/// or register it with [`ReplicateAppExt::replicate`](crate::dynamic_frame::ReplicateAppExt::replicate),
+/// for the hierarchy to be replicated.
+#[derive(Debug, Component, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkParent(pub Option<NetworkID>);
+
+impl NetworkedComponent for NetworkParent {
+    type Component = Self;
+
+    fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_bool(component.0.is_some())?;
+        if let Some(parent) = component.0 {
+            write_network_id(writer, parent)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_full(reader: &mut BitReader) -> Result<Self::Component, io::Error> {
+        let has_parent = reader.read_bool()?;
+        let parent = if has_parent { Some(read_network_id(reader)?) } else { None };
+
+        Ok(Self(parent))
+    }
+}
+
+pub fn sync_network_parent_system(
+    mut commands: Commands,
+    query: Query<(Entity, Option<&Parent>, Option<&NetworkParent>), With<NetworkID>>,
+    network_ids: Query<&NetworkID>,
+) {
+    for (entity, parent, network_parent) in query.iter() {
+        let parent_id = parent.and_then(|parent| network_ids.get(parent.get()).ok()).copied();
+        match network_parent {
+            Some(network_parent) if network_parent.0 == parent_id => {}
+            // Never had a networked parent, nothing to replicate
+            None if parent_id.is_none() => {}
+            _ => {
+                commands.entity(entity).insert(NetworkParent(parent_id));
+            }
+        }
+    }
+}
+
+// The parent can arrive after the child or be despawned before it,
+// unresolved parents are detached and retried every frame until they are mapped.
+// Parents that would make the entity its own ancestor come from malformed frames, they are ignored.
+pub fn apply_network_parent_system(
+    mut commands: Commands,
+    mapping: Res<NetworkMapping>,
+    query: Query<(Entity, &NetworkParent, Option<&Parent>)>,
+    parents: Query<&Parent>,
+    entities: Query<Entity>,
+    children: Query<(), With<Children>>,
+) {
+    // Parents changed by the commands of this run, not applied yet
+    let mut new_parents: HashMap<Entity, Option<Entity>> = HashMap::new();
+    for (entity, network_parent, parent) in query.iter() {
+        let mapped_parent = network_parent
+            .0
+            .and_then(|parent_id| mapping.entities.get(&parent_id))
+            .copied()
+            .filter(|mapped_parent| entities.get(*mapped_parent).is_ok());
+        let current_parent = parent.map(|parent| parent.get());
+        if mapped_parent == current_parent {
+            continue;
+        }
+
+        match mapped_parent {
+            Some(mapped_parent) => {
+                let mut ancestor = Some(mapped_parent);
+                while let Some(current) = ancestor {
+                    if current == entity {
+                        break;
+                    }
+                    ancestor = match new_parents.get(&current) {
+                        Some(new_parent) => *new_parent,
+                        None => parents.get(current).ok().map(|parent| parent.get()),
+                    };
+                }
+                if ancestor.is_some() {
+                    continue;
+                }
+
+                new_parents.insert(entity, Some(mapped_parent));
+                commands.entity(mapped_parent).push_children(&[entity]);
+            }
+            None => match current_parent {
+                Some(current_parent) if children.get(current_parent).is_ok() => {
+                    new_parents.insert(entity, None);
+                    commands.entity(current_parent).remove_children(&[entity]);
+                }
+                // The parent was despawned
+                _ => {
+                    new_parents.insert(entity, None);
+                    commands.entity(entity).remove::<Parent>();
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network_entity::DEFAULT_ID_BITS;
+
+    use super::*;
+
+    #[test]
+    fn test_parent_after_child() {
+        let mut app = App::new();
+        app.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        app.add_system(apply_network_parent_system);
+
+        let parent_id = NetworkID::new(0, 0);
+        let child = app.world.spawn().insert(NetworkParent(Some(parent_id))).id();
+        app.update();
+        assert!(app.world.get::<Parent>(child).is_none());
+
+        // Retried until the parent is mapped
+        let parent = app.world.spawn().id();
+        app.world.resource_mut::<NetworkMapping>().entities.insert(parent_id, parent);
+        app.update();
+        assert_eq!(app.world.get::<Parent>(child).map(|parent| parent.get()), Some(parent));
+
+        // Detached when the parent is despawned
+        app.world.resource_mut::<NetworkMapping>().entities.remove(&parent_id);
+        app.world.despawn(parent);
+        app.update();
+        assert!(app.world.get::<Parent>(child).is_none());
+    }
+
+    #[test]
+    fn test_invalid_parents() {
+        let mut app = App::new();
+        app.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        app.add_system(apply_network_parent_system);
+
+        // Parent of itself
+        let (first_id, second_id, stale_id) = (NetworkID::new(0, 0), NetworkID::new(1, 0), NetworkID::new(2, 0));
+        let first = app.world.spawn().insert(NetworkParent(Some(first_id))).id();
+        app.world.resource_mut::<NetworkMapping>().entities.insert(first_id, first);
+        app.update();
+        assert!(app.world.get::<Parent>(first).is_none());
+
+        // Parents of each other, only the first one applied is kept
+        let second = app.world.spawn().insert(NetworkParent(Some(first_id))).id();
+        app.world.resource_mut::<NetworkMapping>().entities.insert(second_id, second);
+        app.world.entity_mut(first).insert(NetworkParent(Some(second_id)));
+        app.update();
+        let first_parent = app.world.get::<Parent>(first).map(|parent| parent.get());
+        let second_parent = app.world.get::<Parent>(second).map(|parent| parent.get());
+        assert!(matches!((first_parent, second_parent), (Some(_), None) | (None, Some(_))));
+        app.update();
+        assert_eq!(app.world.get::<Parent>(first).map(|parent| parent.get()), first_parent);
+        assert_eq!(app.world.get::<Parent>(second).map(|parent| parent.get()), second_parent);
+
+        // Mapped to a despawned entity
+        let stale = app.world.spawn().id();
+        app.world.despawn(stale);
+        app.world.resource_mut::<NetworkMapping>().entities.insert(stale_id, stale);
+        let child = app.world.spawn().insert(NetworkParent(Some(stale_id))).id();
+        app.update();
+        assert!(app.world.get::<Parent>(child).is_none());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::network_frame::NetworkedComponent;
+
+use bevy::prelude::*;
+use bincode::Options;
+use bit_serializer::{BitReader, BitWriter};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, io, marker::PhantomData};
+
+/// Replicate any serde component without writing a bit level encoding for it,
+/// the component is written as a length prefixed bincode payload with varint integers.
+/// Prefer a custom implementation for components that change frequently, this one never delta encodes.
+/// Since `network_frame!` needs a plain name for each component, use it through a type alias:
+/// ```ignore
+/// type PlayerNameNetworked = SerdeNetworked<PlayerName>;
+/// network_frame!(TransformNetworked, PlayerNameNetworked);
+/// ```
+pub struct SerdeNetworked<T>(PhantomData<T>);
+
+fn serde_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl<T> NetworkedComponent for SerdeNetworked<T>
+where
+    T: Component + Serialize + DeserializeOwned + PartialEq + Clone + Debug,
+{
+    type Component = T;
+
+    fn write_full(component: &T, writer: &mut BitWriter) -> Result<(), io::Error> {
+        let bytes = serde_options()
+            .serialize(component)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        writer.write_varint_u64(bytes.len() as u64)?;
+        for byte in bytes {
+            writer.write_bits(byte as u32, 8)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_full(reader: &mut BitReader) -> Result<T, io::Error> {
+        let len = reader.read_varint_u64()? as usize;
+        // The length comes from the network, only trust it after the bytes were actually read
+        let mut bytes = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            bytes.push(reader.read_bits(8)? as u8);
+        }
+
+        serde_options()
+            .deserialize(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Component, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+    struct Named {
+        name: String,
+        level: u32,
+    }
+
+    fn write_read(named: &Named) -> usize {
+        let mut writer = BitWriter::with_capacity(100);
+        SerdeNetworked::<Named>::write_full(named, &mut writer).unwrap();
+        let bits = writer.bits_written();
+
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        assert_eq!(&SerdeNetworked::<Named>::read_full(&mut reader).unwrap(), named);
+
+        bits
+    }
+
+    #[test]
+    fn test_serde_networked() {
+        let named = Named {
+            name: "bob".to_string(),
+            level: 5,
+        };
+        // bincode varints below 251 are a single byte: string length, 3 bytes string, level
+        assert_eq!(serde_options().serialize(&named).unwrap(), vec![3, b'b', b'o', b'b', 5]);
+        // 8 bits length + 5 bytes payload
+        assert_eq!(write_read(&named), 48);
+
+        let named = Named {
+            name: "bob".to_string(),
+            level: 300,
+        };
+        // Larger values are a 251 marker followed by a u16
+        assert_eq!(serde_options().serialize(&named).unwrap(), vec![3, b'b', b'o', b'b', 251, 44, 1]);
+        // 8 bits length + 7 bytes payload
+        assert_eq!(write_read(&named), 64);
+    }
+}
@@ -1,8 +1,11 @@
-use crate::{client::NetworkInterpolation, network_frame::NetworkedComponent};
+use crate::{
+    client::NetworkInterpolation,
+    encoding::{read_f32_range, read_quat, write_f32_range, write_quat},
+    network_frame::{NetworkedComponent, NetworkedFrame},
+};
 
 use bevy::{ecs::world::EntityMut, prelude::*};
 use bit_serializer::{BitReader, BitWriter};
-use std::f32::consts::FRAC_1_SQRT_2;
 use std::io;
 
 // TODO: add configuration
@@ -83,9 +86,18 @@ impl NetworkedComponent for TransformNetworked {
 
         entity_mut.insert(InterpolateTransform { from, to: *component });
     }
+
+    fn remove(mut entity_mut: EntityMut<'_>) {
+        entity_mut.remove::<Transform>();
+        entity_mut.remove::<InterpolateTransform>();
+    }
 }
 
-pub fn interpolate_transform_system(interpolation: Res<NetworkInterpolation>, mut query: Query<(&mut Transform, &InterpolateTransform)>) {
+/// Interpolate the transforms with the interpolation of the stream T, the one replicating [`TransformNetworked`].
+pub fn interpolate_transform_system<T: NetworkedFrame>(
+    interpolation: Res<NetworkInterpolation<T>>,
+    mut query: Query<(&mut Transform, &InterpolateTransform)>,
+) {
     let t = interpolation.0;
     for (mut transform, interpolate) in query.iter_mut() {
         transform.translation = interpolate.from.translation.lerp(interpolate.to.translation, t);
@@ -93,117 +105,3 @@ pub fn interpolate_transform_system(interpolation: Res<NetworkInterpolation>, mu
         transform.rotation = interpolate.from.rotation.slerp(interpolate.to.rotation, t);
     }
 }
-
-fn bits_required(min: u32, max: u32) -> usize {
-    let diff = max - min;
-    (u32::BITS - diff.leading_zeros()) as usize
-}
-
-fn write_f32_range(writer: &mut BitWriter, value: f32, min: f32, max: f32, precision: f32) -> Result<(), io::Error> {
-    let delta = max - min;
-    let values = delta / precision;
-
-    let max_integer_value = values.ceil() as u32;
-    let bits = bits_required(0, max_integer_value);
-
-    let normalized_value = ((value - min) / delta).clamp(0., 1.);
-    let integer_value = (normalized_value * max_integer_value as f32 + 0.5).floor() as u32;
-
-    writer.write_bits(integer_value, bits)?;
-
-    Ok(())
-}
-
-fn read_f32_range(reader: &mut BitReader, min: f32, max: f32, precision: f32) -> Result<f32, io::Error> {
-    let delta = max - min;
-    let values = delta / precision;
-
-    let max_integer_value = values.ceil() as u32;
-    let bits = bits_required(0, max_integer_value);
-
-    let integer_value = reader.read_bits(bits)?;
-
-    let normalized_value = integer_value as f32 / max_integer_value as f32;
-    let value = normalized_value * delta + min;
-
-    Ok(value)
-}
-
-fn write_f32_range_bits(writer: &mut BitWriter, mut value: f32, min: f32, max: f32, bits: usize) -> Result<(), io::Error> {
-    let delta = max - min;
-    let umax = (1 << bits) - 1;
-    let q = umax as f32 / delta;
-
-    if value < min {
-        value = min;
-    }
-
-    let mut u = ((value - min) * q) as u32;
-
-    if u > umax {
-        u = umax;
-    }
-
-    writer.write_bits(u, bits)
-}
-
-fn read_f32_range_bits(reader: &mut BitReader, min: f32, max: f32, bits: usize) -> Result<f32, io::Error> {
-    let delta = max - min;
-    let umax = (1 << bits) - 1;
-    let q = umax as f32 / delta;
-
-    let u = reader.read_bits(bits)?;
-    let value = min + (u as f32 / q);
-
-    Ok(value)
-}
-
-fn write_quat(writer: &mut BitWriter, quat: Quat, bits: usize) -> Result<(), io::Error> {
-    let quat = quat.normalize();
-    let mut largest_index = 3; // w
-    let mut quat = quat.to_array();
-    for i in 0..3 {
-        if quat[i].abs() > quat[largest_index].abs() {
-            largest_index = i;
-        }
-    }
-
-    if quat[largest_index] < 0.0 {
-        for i in 0..4 {
-            quat[i] *= -1.0;
-        }
-    }
-
-    writer.write_bits(largest_index as u32, 2)?;
-
-    for i in 0..4 {
-        if i != largest_index {
-            write_f32_range_bits(writer, quat[i], -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
-        }
-    }
-
-    Ok(())
-}
-
-fn read_quat(reader: &mut BitReader, bits: usize) -> Result<Quat, io::Error> {
-    let largest_index = reader.read_bits(2)? as usize;
-
-    let a = read_f32_range_bits(reader, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
-    let b = read_f32_range_bits(reader, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
-    let c = read_f32_range_bits(reader, -FRAC_1_SQRT_2, FRAC_1_SQRT_2, bits)?;
-
-    let mut result = [0.0; 4];
-    result[largest_index] = f32::sqrt(1.0 - a * a - b * b - c * c);
-
-    let values = [a, b, c];
-    let mut index_value = 0;
-    for i in 0..4 {
-        if i != largest_index {
-            result[i] = values[index_value];
-            index_value += 1;
-        }
-    }
-
-    let quat = Quat::from_array(result);
-    Ok(quat)
-}
@@ -0,0 +1,101 @@
+use crate::network_frame::NetworkedComponent;
+
+use bevy::prelude::*;
+use bit_serializer::{BitReader, BitWriter};
+use std::io;
+
+/// Client that owns a networked entity, set by the server. Changing it on the server transfers the ownership.
+/// It's only replicated when listed in the frame, e.g. `network_frame!(TransformNetworked, NetworkOwner)`,
+/// the owner visibility of the components works without replicating it.
+#[derive(Debug, Component, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetworkOwner(pub u64);
+
+impl NetworkedComponent for NetworkOwner {
+    type Component = Self;
+
+    fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_varint_u64(component.0)
+    }
+
+    fn read_full(reader: &mut BitReader) -> Result<Self::Component, io::Error> {
+        Ok(Self(reader.read_varint_u64()?))
+    }
+}
+
+/// Client: id of this client, used to mark the entities it owns with [`IsLocallyOwned`].
+pub struct LocalClientId(pub u64);
+
+/// Client: marks the networked entities owned by this client.
+#[derive(Debug, Default, Component, Clone, Copy)]
+pub struct IsLocallyOwned;
+
+pub fn update_locally_owned_system(
+    mut commands: Commands,
+    local_client_id: Option<Res<LocalClientId>>,
+    query: Query<(Entity, &NetworkOwner, Option<&IsLocallyOwned>)>,
+    changed: Query<(), Changed<NetworkOwner>>,
+    removals: RemovedComponents<NetworkOwner>,
+    locally_owned: Query<(), With<IsLocallyOwned>>,
+) {
+    let local_client_id = match local_client_id {
+        Some(local_client_id) => local_client_id,
+        None => return,
+    };
+
+    for (entity, owner, is_locally_owned) in query.iter() {
+        // Everything is checked again when the local client id changes
+        if !local_client_id.is_changed() && changed.get(entity).is_err() {
+            continue;
+        }
+
+        match (owner.0 == local_client_id.0, is_locally_owned.is_some()) {
+            (true, false) => {
+                commands.entity(entity).insert(IsLocallyOwned);
+            }
+            (false, true) => {
+                commands.entity(entity).remove::<IsLocallyOwned>();
+            }
+            _ => {}
+        }
+    }
+
+    for entity in removals.iter() {
+        if locally_owned.get(entity).is_ok() {
+            commands.entity(entity).remove::<IsLocallyOwned>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locally_owned() {
+        let mut app = App::new();
+        app.add_system(update_locally_owned_system);
+        let owned = app.world.spawn().insert(NetworkOwner(1)).id();
+        let other = app.world.spawn().insert(NetworkOwner(2)).id();
+
+        // Nothing is marked until the id of the client is known
+        app.update();
+        assert!(app.world.get::<IsLocallyOwned>(owned).is_none());
+
+        app.insert_resource(LocalClientId(1));
+        app.update();
+        assert!(app.world.get::<IsLocallyOwned>(owned).is_some());
+        assert!(app.world.get::<IsLocallyOwned>(other).is_none());
+
+        // The ownership is transferred
+        app.world.get_mut::<NetworkOwner>(owned).unwrap().0 = 2;
+        app.world.get_mut::<NetworkOwner>(other).unwrap().0 = 1;
+        app.update();
+        assert!(app.world.get::<IsLocallyOwned>(owned).is_none());
+        assert!(app.world.get::<IsLocallyOwned>(other).is_some());
+
+        // The entity is not owned anymore
+        app.world.entity_mut(other).remove::<NetworkOwner>();
+        app.update();
+        assert!(app.world.get::<IsLocallyOwned>(other).is_none());
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{read_network_id, write_network_id, NetworkID};
+
+use bevy::prelude::*;
+use bit_serializer::{BitReader, BitWriter};
+use std::{collections::HashMap, fmt, io, marker::PhantomData};
+
+/// Provisional id given by a client to an entity it spawned before the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PredictionId(pub u32);
+
+/// Client: entity spawned by the client while waiting for the server to confirm it.
+/// The game sends the PredictionId to the server with the request that spawns the entity.
+/// When the server confirms it, the entity is adopted instead of spawning a duplicate,
+/// if the server rejects it, the entity is despawned.
+#[derive(Debug, Component, Clone, Copy)]
+pub struct Predicted(pub PredictionId);
+
+/// Client: ticks a confirmed prediction waits for a frame with its entity,
+/// after that the server entity is assumed gone and the predicted entity is despawned.
+pub const CONFIRMED_SPAWN_TIMEOUT_TICKS: u64 = 128;
+
+/// Client: ticks a predicted entity waits for the server to confirm or reject it, counted from the first frame
+/// received after it was spawned. After that the request is assumed lost and the predicted entity is despawned.
+pub const PENDING_SPAWN_TIMEOUT_TICKS: u64 = 128;
+
+/// Client: predicted entities waiting for the server and the ones confirmed but not yet in an applied frame.
+#[derive(Debug, Default)]
+pub struct PredictedSpawns {
+    next_id: u32,
+    // Tick of the first frame received after the entity was spawned, set when that frame is read
+    pending: HashMap<PredictionId, (Entity, Option<u64>)>,
+    // Tick of the frame that confirmed the entity
+    confirmed: HashMap<NetworkID, (Entity, u64)>,
+}
+
+impl PredictedSpawns {
+    pub fn next_id(&mut self) -> PredictionId {
+        let id = PredictionId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+}
+
+/// Server: authoritative entity spawned for a client prediction,
+/// the client is told which NetworkID it became once it is assigned.
+#[derive(Debug, Component, Clone, Copy)]
+pub struct PredictedBy {
+    pub client_id: u64,
+    pub prediction: PredictionId,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PredictionResult {
+    Confirmed(NetworkID),
+    Rejected,
+}
+
+#[derive(Debug)]
+struct PredictionReport {
+    prediction: PredictionId,
+    result: PredictionResult,
+    // Tick of the first frame that included this report
+    sent_tick: Option<u64>,
+}
+
+/// Server: confirmed and rejected predictions, they are sent with every frame of the stream T to the client
+/// until it acknowledges a frame that included them.
+pub struct ServerPredictions<T> {
+    reports: HashMap<u64, Vec<PredictionReport>>,
+    data: PhantomData<T>,
+}
+
+impl<T> Default for ServerPredictions<T> {
+    fn default() -> Self {
+        Self {
+            reports: HashMap::new(),
+            data: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for ServerPredictions<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerPredictions").field("reports", &self.reports).finish()
+    }
+}
+
+impl<T> ServerPredictions<T> {
+    pub fn reject(&mut self, client_id: u64, prediction: PredictionId) {
+        self.report(client_id, prediction, PredictionResult::Rejected);
+    }
+
+    pub fn remove_client(&mut self, client_id: u64) {
+        self.reports.remove(&client_id);
+    }
+
+    fn report(&mut self, client_id: u64, prediction: PredictionId, result: PredictionResult) {
+        let report = PredictionReport {
+            prediction,
+            result,
+            sent_tick: None,
+        };
+        self.reports.entry(client_id).or_default().push(report);
+    }
+
+    pub(crate) fn write_reports(
+        &mut self,
+        writer: &mut BitWriter,
+        client_id: u64,
+        tick: u64,
+        last_received_tick: Option<u64>,
+    ) -> Result<(), io::Error> {
+        let reports = match self.reports.get_mut(&client_id) {
+            Some(reports) => reports,
+            None => return writer.write_varint_u64(0),
+        };
+
+        // Any frame the client received after the first one with the report also had it
+        if let Some(last_received_tick) = last_received_tick {
+            reports.retain(|report| !matches!(report.sent_tick, Some(sent_tick) if sent_tick <= last_received_tick));
+        }
+
+        writer.write_varint_u64(reports.len() as u64)?;
+        for report in reports.iter_mut() {
+            report.sent_tick.get_or_insert(tick);
+            writer.write_varint_u64(report.prediction.0 as u64)?;
+            match report.result {
+                PredictionResult::Confirmed(network_id) => {
+                    writer.write_bool(true)?;
+                    write_network_id(writer, network_id)?;
+                }
+                PredictionResult::Rejected => writer.write_bool(false)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the reports sent with the frame of the tick.
+pub(crate) fn read_prediction_reports(reader: &mut BitReader, world: &mut World, tick: u64) -> Result<(), io::Error> {
+    let len = reader.read_varint_u64()?;
+    let mut rejected = vec![];
+    for _ in 0..len {
+        let prediction = reader.read_varint_u64()?;
+        if prediction > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "prediction id above limit"));
+        }
+        let prediction = PredictionId(prediction as u32);
+        let confirmed = reader.read_bool()?;
+        let network_id = if confirmed { Some(read_network_id(reader)?) } else { None };
+
+        // Reports are resent until acknowledged, only the first one is handled
+        let mut predicted_spawns = world.resource_mut::<PredictedSpawns>();
+        if let Some((entity, _)) = predicted_spawns.pending.remove(&prediction) {
+            match network_id {
+                Some(network_id) => {
+                    predicted_spawns.confirmed.insert(network_id, (entity, tick));
+                }
+                None => rejected.push(entity),
+            }
+        }
+    }
+
+    let mut predicted_spawns = world.resource_mut::<PredictedSpawns>();
+    // Predictions the server never answered, e.g. the request spawning the entity was lost
+    predicted_spawns.pending.retain(|_, (entity, first_tick)| {
+        let expired = tick.saturating_sub(*first_tick.get_or_insert(tick)) > PENDING_SPAWN_TIMEOUT_TICKS;
+        if expired {
+            rejected.push(*entity);
+        }
+        !expired
+    });
+
+    // Confirmed entities that never showed up in a frame, e.g. despawned by the server in the meantime
+    predicted_spawns.confirmed.retain(|_, (entity, confirmed_tick)| {
+        let expired = tick.saturating_sub(*confirmed_tick) > CONFIRMED_SPAWN_TIMEOUT_TICKS;
+        if expired {
+            rejected.push(*entity);
+        }
+        !expired
+    });
+
+    for entity in rejected {
+        world.despawn(entity);
+    }
+
+    Ok(())
+}
+
+/// Use the predicted entity confirmed for this NetworkID, if there is one.
+pub(crate) fn adopt_predicted_spawn(world: &mut World, network_id: NetworkID) -> Option<Entity> {
+    let (entity, _) = world.get_resource_mut::<PredictedSpawns>()?.confirmed.remove(&network_id)?;
+    let mut entity_mut = world.get_entity_mut(entity)?;
+    entity_mut.insert(network_id);
+    entity_mut.remove::<Predicted>();
+
+    Some(entity)
+}
+
+pub fn track_predicted_spawn_system(mut predicted_spawns: ResMut<PredictedSpawns>, query: Query<(Entity, &Predicted), Added<Predicted>>) {
+    for (entity, predicted) in query.iter() {
+        predicted_spawns.pending.insert(predicted.0, (entity, None));
+    }
+}
+
+pub fn confirm_predicted_spawn_system<T: Send + Sync + 'static>(
+    mut server_predictions: ResMut<ServerPredictions<T>>,
+    query: Query<(&NetworkID, &PredictedBy), Added<NetworkID>>,
+) {
+    for (network_id, predicted_by) in query.iter() {
+        server_predictions.report(
+            predicted_by.client_id,
+            predicted_by.prediction,
+            PredictionResult::Confirmed(*network_id),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predicted_spawns() {
+        let mut app = App::new();
+        app.init_resource::<PredictedSpawns>().add_system(track_predicted_spawn_system);
+        let mut predicted_spawns = app.world.resource_mut::<PredictedSpawns>();
+        let (confirmed, rejected, expired) = (predicted_spawns.next_id(), predicted_spawns.next_id(), predicted_spawns.next_id());
+        let confirmed_entity = app.world.spawn().insert(Predicted(confirmed)).id();
+        let rejected_entity = app.world.spawn().insert(Predicted(rejected)).id();
+        let expired_entity = app.world.spawn().insert(Predicted(expired)).id();
+        app.update();
+        let world = &mut app.world;
+
+        let mut server_predictions = ServerPredictions::<()>::default();
+        server_predictions.report(1, confirmed, PredictionResult::Confirmed(NetworkID::new(3, 0)));
+        server_predictions.reject(1, rejected);
+        server_predictions.report(1, expired, PredictionResult::Confirmed(NetworkID::new(4, 0)));
+        let mut writer = BitWriter::with_capacity(100);
+        server_predictions.write_reports(&mut writer, 1, 10, None).unwrap();
+        // Resent until the client acknowledges a frame with them, only the first one is handled
+        server_predictions.write_reports(&mut writer, 1, 11, None).unwrap();
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        read_prediction_reports(&mut reader, world, 10).unwrap();
+        read_prediction_reports(&mut reader, world, 11).unwrap();
+        assert!(world.get_entity(rejected_entity).is_none());
+
+        assert_eq!(adopt_predicted_spawn(world, NetworkID::new(3, 0)), Some(confirmed_entity));
+        assert_eq!(world.get::<NetworkID>(confirmed_entity), Some(&NetworkID::new(3, 0)));
+        assert!(world.get::<Predicted>(confirmed_entity).is_none());
+        assert_eq!(adopt_predicted_spawn(world, NetworkID::new(3, 0)), None);
+
+        // Confirmed entities not adopted in time are despawned
+        let mut writer = BitWriter::with_capacity(8);
+        writer.write_varint_u64(0).unwrap();
+        let buffer = writer.consume().unwrap();
+        read_prediction_reports(&mut BitReader::new(&buffer).unwrap(), world, 10 + CONFIRMED_SPAWN_TIMEOUT_TICKS).unwrap();
+        assert!(world.get_entity(expired_entity).is_some());
+        read_prediction_reports(&mut BitReader::new(&buffer).unwrap(), world, 11 + CONFIRMED_SPAWN_TIMEOUT_TICKS).unwrap();
+        assert!(world.get_entity(expired_entity).is_none());
+        assert_eq!(adopt_predicted_spawn(world, NetworkID::new(4, 0)), None);
+    }
+
+    #[test]
+    fn test_pending_spawn_timeout() {
+        let mut app = App::new();
+        app.init_resource::<PredictedSpawns>().add_system(track_predicted_spawn_system);
+        let prediction = app.world.resource_mut::<PredictedSpawns>().next_id();
+        let entity = app.world.spawn().insert(Predicted(prediction)).id();
+        app.update();
+        let world = &mut app.world;
+
+        let mut writer = BitWriter::with_capacity(8);
+        writer.write_varint_u64(0).unwrap();
+        let buffer = writer.consume().unwrap();
+
+        // The timeout starts with the first frame received after the entity was spawned
+        read_prediction_reports(&mut BitReader::new(&buffer).unwrap(), world, 20).unwrap();
+        read_prediction_reports(&mut BitReader::new(&buffer).unwrap(), world, 20 + PENDING_SPAWN_TIMEOUT_TICKS).unwrap();
+        assert!(world.get_entity(entity).is_some());
+        read_prediction_reports(&mut BitReader::new(&buffer).unwrap(), world, 21 + PENDING_SPAWN_TIMEOUT_TICKS).unwrap();
+        assert!(world.get_entity(entity).is_none());
+        assert!(world.resource::<PredictedSpawns>().pending.is_empty());
+
+        // A late confirmation is ignored, the server entity is spawned as any other entity
+        let mut server_predictions = ServerPredictions::<()>::default();
+        server_predictions.report(1, prediction, PredictionResult::Confirmed(NetworkID::new(3, 0)));
+        let mut writer = BitWriter::with_capacity(100);
+        server_predictions.write_reports(&mut writer, 1, 30, None).unwrap();
+        let buffer = writer.consume().unwrap();
+        read_prediction_reports(&mut BitReader::new(&buffer).unwrap(), world, 30 + PENDING_SPAWN_TIMEOUT_TICKS).unwrap();
+        assert_eq!(adopt_predicted_spawn(world, NetworkID::new(3, 0)), None);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{relevancy::Relevancy, sequence_buffer::SequenceBuffer, visibility::VisibilityContext, NetworkID, NetworkedFrame};
+
+use bevy::prelude::*;
+use bit_serializer::BitWriter;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    io,
+};
+
+/// Priority used for entities without a NetworkPriority.
+pub const DEFAULT_PRIORITY: f32 = 1.0;
+
+/// Server: priority added every tick to the accumulated priority of the entity, for each client it was not sent to.
+/// When a client has a bit budget, the entities with the highest accumulated priority are sent first.
+/// Can be set by the game, for example by distance or by how much the entity changed.
+#[derive(Debug, Component, Clone, Copy, PartialEq)]
+pub struct NetworkPriority(pub f32);
+
+impl Default for NetworkPriority {
+    fn default() -> Self {
+        Self(DEFAULT_PRIORITY)
+    }
+}
+
+#[derive(Debug)]
+struct ClientState<T> {
+    sent_frames: SequenceBuffer<T>,
+    accumulated: HashMap<NetworkID, f32>,
+    // Tick of the frame the priorities were last accumulated for and the entities sent in it,
+    // building the frame of the same tick again sends the same entities
+    accumulated_tick: Option<u64>,
+    sent: Vec<NetworkID>,
+    // Tick of the newest frame sent to the client, acknowledged or not
+    latest_tick: Option<u64>,
+}
+
+/// Server: the frames sent to each client, the priorities accumulated by the entities not sent yet
+/// and the relevancy strategies deciding which entities each client sees.
+/// The delta frames of a client are encoded against the frames sent to it, and deferred entities
+/// keep the newest state sent to the client.
+#[derive(Debug)]
+pub struct ClientReplication<T> {
+    buffer_size: usize,
+    pub(crate) priorities: HashMap<NetworkID, f32>,
+    relevancy: Vec<Box<dyn Relevancy>>,
+    visibility: VisibilityContext,
+    budgets: HashMap<u64, usize>,
+    clients: HashMap<u64, ClientState<T>>,
+}
+
+impl<T: NetworkedFrame> ClientReplication<T> {
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            priorities: HashMap::new(),
+            relevancy: Vec::new(),
+            visibility: VisibilityContext::default(),
+            budgets: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Override the bit budget per tick of the client, `None` uses the budget from the config.
+    pub fn set_budget(&mut self, client_id: u64, bits: Option<usize>) {
+        match bits {
+            Some(bits) => self.budgets.insert(client_id, bits),
+            None => self.budgets.remove(&client_id),
+        };
+    }
+
+    /// Add a strategy deciding which entities are relevant to each client, see [`Relevancy`].
+    pub fn add_relevancy<R: Relevancy>(&mut self, relevancy: R) {
+        self.relevancy.push(Box::new(relevancy));
+    }
+
+    pub fn remove_client(&mut self, client_id: u64) {
+        self.budgets.remove(&client_id);
+        self.clients.remove(&client_id);
+    }
+
+    /// Frame sent to the client in the given tick, `None` when the generated frame was sent as is,
+    /// see [`ClientReplication::client_frame`].
+    pub fn sent_frame(&self, client_id: u64, tick: u64) -> Option<&T> {
+        self.clients.get(&client_id)?.sent_frames.get(tick)
+    }
+
+    pub(crate) fn insert_sent_frame(&mut self, client_id: u64, tick: u64, frame: T) {
+        if let Some(state) = self.clients.get_mut(&client_id) {
+            state.sent_frames.insert(tick, frame);
+            if state.latest_tick.map_or(true, |latest_tick| tick > latest_tick) {
+                state.latest_tick = Some(tick);
+            }
+        }
+    }
+
+    pub(crate) fn update(&mut self, world: &mut World) {
+        self.priorities.clear();
+        let mut query = world.query::<(&NetworkID, &NetworkPriority)>();
+        for (network_id, priority) in query.iter(world) {
+            self.priorities.insert(*network_id, priority.0);
+        }
+
+        for relevancy in self.relevancy.iter_mut() {
+            relevancy.update(world);
+        }
+
+        self.visibility.update(world);
+    }
+
+    /// Entities relevant to the client, `None` when every entity is relevant.
+    pub(crate) fn relevant_entities(&self, client_id: u64) -> Option<HashSet<NetworkID>> {
+        if self.relevancy.is_empty() {
+            return None;
+        }
+
+        let mut relevant = HashSet::new();
+        for relevancy in self.relevancy.iter() {
+            relevancy.relevant_entities(client_id, &mut relevant);
+        }
+
+        Some(relevant)
+    }
+
+    /// Build the frame for the client, with the relevant entities that fit in its bit budget.
+    /// The entities left out keep their state from the newest frame sent to the client, even if it wasn't
+    /// acknowledged yet, so the client never goes back to an older state. New entities are only spawned once sent.
+    /// Without a sent frame the client only keeps the entities sent in the frame.
+    /// Entities that are not relevant are never kept, so the client despawns them.
+    /// Components the client can't see are removed from the frame.
+    /// Returns `None` when the generated frame is sent as is: without budget, relevancy and hidden components.
+    /// These frames are not kept in the sent frames, the baselines are then looked up in `frames`.
+    pub(crate) fn client_frame(
+        &mut self,
+        client_id: u64,
+        frame: &T,
+        frames: &SequenceBuffer<T>,
+        last_received_tick: Option<u64>,
+        default_budget: Option<usize>,
+    ) -> Result<Option<T>, io::Error> {
+        let budget = self.budgets.get(&client_id).copied().or(default_budget);
+        if budget.is_none() && self.relevancy.is_empty() && !frame.hides_components() {
+            if let Some(state) = self.clients.get_mut(&client_id) {
+                state.sent_frames.remove(frame.tick());
+                state.latest_tick = Some(frame.tick());
+            }
+            return Ok(None);
+        }
+
+        let mut client_frame = self.budgeted_frame(client_id, frame, frames, last_received_tick, budget)?;
+        client_frame.hide_components(client_id, &self.visibility);
+
+        Ok(Some(client_frame))
+    }
+
+    fn budgeted_frame(
+        &mut self,
+        client_id: u64,
+        frame: &T,
+        frames: &SequenceBuffer<T>,
+        last_received_tick: Option<u64>,
+        budget: Option<usize>,
+    ) -> Result<T, io::Error> {
+        let relevant_frame = match self.relevant_entities(client_id) {
+            Some(relevant) => {
+                let selected: Vec<bool> = frame.entities().iter().map(|network_id| relevant.contains(network_id)).collect();
+                frame.select_entities(&selected, None)
+            }
+            None => frame.clone(),
+        };
+
+        let buffer_size = self.buffer_size;
+        let state = self.clients.entry(client_id).or_insert_with(|| ClientState {
+            sent_frames: SequenceBuffer::with_capacity(buffer_size),
+            accumulated: HashMap::new(),
+            accumulated_tick: None,
+            sent: Vec::new(),
+            latest_tick: None,
+        });
+
+        let budget = match budget {
+            Some(budget) => budget,
+            None => return Ok(relevant_frame),
+        };
+
+        let frame = &relevant_frame;
+        let sent_frame = |tick: u64| state.sent_frames.get(tick).or_else(|| frames.get(tick));
+        let baseline = last_received_tick.and_then(sent_frame);
+        // The client may have received newer frames than the baseline, the deferred entities keep that state
+        let latest = state.latest_tick.and_then(sent_frame).or(baseline);
+        let entities = frame.entities();
+
+        // Priorities build up once per tick while the entities are not sent
+        if state.accumulated_tick != Some(frame.tick()) {
+            state.accumulated_tick = Some(frame.tick());
+            for network_id in state.sent.drain(..) {
+                state.accumulated.insert(network_id, 0.);
+            }
+            state.accumulated.retain(|network_id, _| entities.binary_search(network_id).is_ok());
+            for network_id in entities.iter() {
+                let priority = self.priorities.get(network_id).copied().unwrap_or(DEFAULT_PRIORITY);
+                *state.accumulated.entry(*network_id).or_default() += priority;
+            }
+        }
+
+        let accumulated: Vec<f32> = entities.iter().map(|network_id| state.accumulated[network_id]).collect();
+        let mut order: Vec<usize> = (0..entities.len()).collect();
+        order.sort_by(|&a, &b| {
+            accumulated[b]
+                .partial_cmp(&accumulated[a])
+                .unwrap_or(Ordering::Equal)
+                .then(a.cmp(&b))
+        });
+
+        // Fill the budget with the estimated bits of each entity, highest priority first,
+        // on top of the frame keeping every entity from the newest sent frame
+        let select = |sent: &[usize]| {
+            let mut selected = vec![false; entities.len()];
+            for &i in sent.iter() {
+                selected[i] = true;
+            }
+            frame.select_entities(&selected, latest)
+        };
+        let mut bits = frame_bits(&select(&[]), baseline)?;
+        let mut writer = BitWriter::with_capacity(1000);
+        let mut sent = Vec::new();
+        for &i in order.iter() {
+            let entity_bits = frame.entity_bits(i, baseline, &mut writer)?;
+            if bits + entity_bits <= budget {
+                bits += entity_bits;
+                sent.push(i);
+            }
+        }
+
+        // The estimates don't include every bit of the change encodings, when the frame doesn't fit
+        // keep the most entities with the highest priority that fit, halving the range of candidates each time
+        let mut client_frame = select(&sent);
+        if frame_bits(&client_frame, baseline)? > budget {
+            let (mut fits, mut exceeds) = (0, sent.len());
+            while exceeds - fits > 1 {
+                let middle = (fits + exceeds) / 2;
+                if frame_bits(&select(&sent[..middle]), baseline)? <= budget {
+                    fits = middle;
+                } else {
+                    exceeds = middle;
+                }
+            }
+            sent.truncate(fits);
+            client_frame = select(&sent);
+        }
+
+        state.sent = sent.iter().map(|&i| entities[i]).collect();
+
+        Ok(client_frame)
+    }
+}
+
+pub(crate) fn frame_bits<T: NetworkedFrame>(frame: &T, baseline: Option<&T>) -> Result<usize, io::Error> {
+    let mut writer = BitWriter::with_capacity(1000);
+    match baseline {
+        Some(baseline) => frame.write_delta_frame(&mut writer, baseline)?,
+        None => frame.write_full_frame(&mut writer)?,
+    }
+
+    Ok(writer.bits_written())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{column, NetworkFrame, Simple};
+
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_budget() {
+        let frame = NetworkFrame::new(
+            1,
+            vec![NetworkID::new(0, 0), NetworkID::new(1, 0)],
+            vec![Some(Simple(0)), Some(Simple(1))],
+        );
+        let mut frames = SequenceBuffer::with_capacity(8);
+        frames.insert(1, frame.clone());
+        let mut replication = ClientReplication::<NetworkFrame>::new(8);
+        replication.priorities.insert(NetworkID::new(1, 0), 2.);
+
+        // Without budget and relevancy the generated frame is sent as is
+        assert_eq!(replication.client_frame(0, &frame, &frames, None, None).unwrap(), None);
+        assert!(replication.sent_frame(0, 1).is_none());
+
+        // Only one entity fits in the budget (67 bits, 110 bits with both), the highest priority is sent
+        let client_frame = replication.client_frame(0, &frame, &frames, None, Some(90)).unwrap().unwrap();
+        assert_eq!(client_frame.entities(), &[NetworkID::new(1, 0)]);
+        assert_eq!(frame_bits(&client_frame, None).unwrap(), 67);
+        replication.insert_sent_frame(0, 1, client_frame);
+
+        // The deferred entity built up priority and is sent, the other one keeps the state the client received
+        // (87 bits, 93 bits when also sending the changed entity)
+        let second_frame = NetworkFrame::new(2, frame.entities().to_vec(), vec![Some(Simple(0)), Some(Simple(5))]);
+        let client_frame = replication
+            .client_frame(0, &second_frame, &frames, Some(1), Some(90))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_frame.entities(), frame.entities());
+        assert_eq!(client_frame.simple(), column(vec![Some(Simple(0)), Some(Simple(1))]));
+
+        // The priorities are accumulated once per tick, building the frame again sends the same entities
+        let retried_frame = replication
+            .client_frame(0, &second_frame, &frames, Some(1), Some(90))
+            .unwrap()
+            .unwrap();
+        assert_eq!(retried_frame, client_frame);
+        assert_eq!(replication.clients[&0].accumulated[&NetworkID::new(1, 0)], 2.);
+
+        // Frames sent as is are the baseline of the next frames
+        let client_frame = replication
+            .client_frame(1, &second_frame, &frames, Some(1), Some(1000))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_frame, second_frame);
+    }
+
+    #[test]
+    fn test_deferred_unacked_state() {
+        let (first, second, spawned) = (NetworkID::new(0, 0), NetworkID::new(1, 0), NetworkID::new(2, 0));
+        let frames = SequenceBuffer::with_capacity(8);
+        let mut replication = ClientReplication::<NetworkFrame>::new(8);
+
+        let first_frame = NetworkFrame::new(1, vec![first, second], vec![Some(Simple(0)), Some(Simple(1))]);
+        let client_frame = replication
+            .client_frame(0, &first_frame, &frames, None, Some(1000))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_frame, first_frame);
+        replication.insert_sent_frame(0, 1, client_frame);
+
+        // Sent but not acknowledged yet, the client may have received it
+        let second_frame = NetworkFrame::new(
+            2,
+            vec![first, second, spawned],
+            vec![Some(Simple(0)), Some(Simple(5)), Some(Simple(2))],
+        );
+        let client_frame = replication
+            .client_frame(0, &second_frame, &frames, Some(1), Some(1000))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_frame, second_frame);
+        replication.insert_sent_frame(0, 2, client_frame);
+
+        // Nothing fits in the budget, the entities keep the state of the unacknowledged frame instead of the baseline,
+        // the entity spawned in it is kept
+        let third_frame = NetworkFrame::new(
+            3,
+            vec![first, second, spawned],
+            vec![Some(Simple(7)), Some(Simple(6)), Some(Simple(3))],
+        );
+        let client_frame = replication
+            .client_frame(0, &third_frame, &frames, Some(1), Some(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client_frame.entities(), &[first, second, spawned]);
+        assert_eq!(
+            client_frame.simple(),
+            column(vec![Some(Simple(0)), Some(Simple(5)), Some(Simple(2))])
+        );
+    }
+}
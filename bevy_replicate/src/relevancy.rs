@@ -0,0 +1,223 @@
+use crate::NetworkID;
+
+use bevy::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+/// Server: decides which entities each client sees, see [`ClientReplication::add_relevancy`](crate::priority::ClientReplication::add_relevancy).
+/// An entity is relevant to a client when any of the added strategies considers it relevant,
+/// the entities that stop being relevant are despawned on the client.
+/// When no strategy is added every entity is relevant to every client.
+pub trait Relevancy: std::fmt::Debug + Send + Sync + 'static {
+    /// Update the strategy from the world, called once per tick after the frame is generated.
+    fn update(&mut self, world: &mut World);
+
+    /// Add the entities relevant to the client.
+    fn relevant_entities(&self, client_id: u64, relevant: &mut HashSet<NetworkID>);
+}
+
+/// Marks an entity as relevant to every client, when used with [`GlobalRelevancy`].
+#[derive(Debug, Default, Component, Clone, Copy)]
+pub struct AlwaysRelevant;
+
+/// Entities with [`AlwaysRelevant`] are relevant to every client.
+#[derive(Debug, Default)]
+pub struct GlobalRelevancy {
+    entities: Vec<NetworkID>,
+}
+
+impl Relevancy for GlobalRelevancy {
+    fn update(&mut self, world: &mut World) {
+        let mut query = world.query_filtered::<&NetworkID, With<AlwaysRelevant>>();
+        self.entities.clear();
+        self.entities.extend(query.iter(world));
+    }
+
+    fn relevant_entities(&self, _client_id: u64, relevant: &mut HashSet<NetworkID>) {
+        relevant.extend(self.entities.iter());
+    }
+}
+
+/// Marks the entity the client is focused on, usually its player or camera, when used with [`SpatialRelevancy`].
+#[derive(Debug, Component, Clone, Copy)]
+pub struct RelevancyFocus(pub u64);
+
+/// Splits the world in a grid of cubic cells, entities are relevant to a client when they are
+/// at most `radius` cells away, in any axis, from the cell of the client's [`RelevancyFocus`].
+/// Uses the GlobalTransform of the entities, entities without one are not relevant by distance.
+#[derive(Debug)]
+pub struct SpatialRelevancy {
+    cell_size: f32,
+    radius: i32,
+    cells: HashMap<IVec3, Vec<NetworkID>>,
+    focus: HashMap<u64, IVec3>,
+}
+
+impl SpatialRelevancy {
+    pub fn new(cell_size: f32, radius: i32) -> Self {
+        assert!(cell_size > 0., "cell size must be positive");
+
+        Self {
+            cell_size,
+            radius,
+            cells: HashMap::new(),
+            focus: HashMap::new(),
+        }
+    }
+}
+
+fn grid_cell(position: Vec3, cell_size: f32) -> IVec3 {
+    (position / cell_size).floor().as_ivec3()
+}
+
+impl Relevancy for SpatialRelevancy {
+    fn update(&mut self, world: &mut World) {
+        self.cells.clear();
+        self.focus.clear();
+
+        let mut query = world.query::<(&NetworkID, &GlobalTransform)>();
+        for (network_id, transform) in query.iter(world) {
+            let cell = grid_cell(transform.translation(), self.cell_size);
+            self.cells.entry(cell).or_default().push(*network_id);
+        }
+
+        let mut query = world.query::<(&RelevancyFocus, &GlobalTransform)>();
+        for (focus, transform) in query.iter(world) {
+            self.focus.insert(focus.0, grid_cell(transform.translation(), self.cell_size));
+        }
+    }
+
+    fn relevant_entities(&self, client_id: u64, relevant: &mut HashSet<NetworkID>) {
+        let center = match self.focus.get(&client_id) {
+            Some(center) => *center,
+            None => return,
+        };
+
+        for x in -self.radius..=self.radius {
+            for y in -self.radius..=self.radius {
+                for z in -self.radius..=self.radius {
+                    if let Some(entities) = self.cells.get(&(center + IVec3::new(x, y, z))) {
+                        relevant.extend(entities.iter());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Room or team the entity belongs to, when used with [`RoomRelevancy`].
+#[derive(Debug, Component, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetworkRoom(pub u64);
+
+/// Server: rooms each client is a member of.
+#[derive(Debug, Default)]
+pub struct RoomMembers {
+    rooms: HashMap<u64, HashSet<u64>>,
+}
+
+impl RoomMembers {
+    pub fn join(&mut self, client_id: u64, room: u64) {
+        self.rooms.entry(client_id).or_default().insert(room);
+    }
+
+    pub fn leave(&mut self, client_id: u64, room: u64) {
+        if let Some(rooms) = self.rooms.get_mut(&client_id) {
+            rooms.remove(&room);
+        }
+    }
+
+    pub fn remove_client(&mut self, client_id: u64) {
+        self.rooms.remove(&client_id);
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = u64> + '_ {
+        self.rooms.keys().copied()
+    }
+
+    pub fn rooms(&self, client_id: u64) -> impl Iterator<Item = u64> + '_ {
+        self.rooms.get(&client_id).into_iter().flatten().copied()
+    }
+}
+
+/// Entities with a [`NetworkRoom`] are relevant to the clients that joined the room in [`RoomMembers`].
+#[derive(Debug, Default)]
+pub struct RoomRelevancy {
+    entities: HashMap<u64, Vec<NetworkID>>,
+    members: HashMap<u64, Vec<u64>>,
+}
+
+impl Relevancy for RoomRelevancy {
+    fn update(&mut self, world: &mut World) {
+        self.entities.clear();
+        self.members.clear();
+
+        let mut query = world.query::<(&NetworkID, &NetworkRoom)>();
+        for (network_id, room) in query.iter(world) {
+            self.entities.entry(room.0).or_default().push(*network_id);
+        }
+
+        if let Some(room_members) = world.get_resource::<RoomMembers>() {
+            for client_id in room_members.clients() {
+                self.members.insert(client_id, room_members.rooms(client_id).collect());
+            }
+        }
+    }
+
+    fn relevant_entities(&self, client_id: u64, relevant: &mut HashSet<NetworkID>) {
+        for room in self.members.get(&client_id).into_iter().flatten() {
+            if let Some(entities) = self.entities.get(room) {
+                relevant.extend(entities.iter());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::priority::ClientReplication;
+    use crate::sequence_buffer::SequenceBuffer;
+    use crate::test_utils::NetworkFrame;
+    use crate::NetworkedFrame;
+
+    use super::*;
+
+    #[test]
+    fn test_relevancy() {
+        let mut world = World::new();
+        let mut room_members = RoomMembers::default();
+        room_members.join(1, 7);
+        world.insert_resource(room_members);
+
+        let far = GlobalTransform::from(Transform::from_xyz(50., 0., 0.));
+        world
+            .spawn()
+            .insert(NetworkID::new(0, 0))
+            .insert(RelevancyFocus(1))
+            .insert(GlobalTransform::default());
+        world.spawn().insert(NetworkID::new(1, 0)).insert(far);
+        world.spawn().insert(NetworkID::new(2, 0)).insert(far).insert(AlwaysRelevant);
+        world.spawn().insert(NetworkID::new(3, 0)).insert(far).insert(NetworkRoom(7));
+        let frame = NetworkFrame::generate_frame(0, &mut world);
+        let frames = SequenceBuffer::with_capacity(8);
+
+        let mut replication = ClientReplication::<NetworkFrame>::new(8);
+        replication.add_relevancy(GlobalRelevancy::default());
+        replication.add_relevancy(SpatialRelevancy::new(10., 1));
+        replication.add_relevancy(RoomRelevancy::default());
+        replication.update(&mut world);
+
+        let client_frame = replication.client_frame(1, &frame, &frames, None, None).unwrap().unwrap();
+        let relevant = vec![NetworkID::new(0, 0), NetworkID::new(2, 0), NetworkID::new(3, 0)];
+        assert_eq!(client_frame.entities(), relevant);
+        replication.insert_sent_frame(1, 0, client_frame);
+
+        // Without focus or rooms only the global entities are relevant
+        let client_frame = replication.client_frame(2, &frame, &frames, None, None).unwrap().unwrap();
+        assert_eq!(client_frame.entities(), &[NetworkID::new(2, 0)]);
+
+        // Entities leaving relevancy are not kept from the baseline
+        world.resource_mut::<RoomMembers>().leave(1, 7);
+        replication.update(&mut world);
+        let client_frame = replication.client_frame(1, &frame, &frames, Some(0), None).unwrap().unwrap();
+        assert_eq!(client_frame.entities(), &relevant[..2]);
+    }
+}
@@ -1,94 +1,320 @@
 use crate::{
-    network_entity::{cleanup_network_entity_system, track_network_entity_system, NetworkEntities},
+    compression::{compress_frame, FrameCodec},
+    fragment::fragment_frame,
+    message::{clear_server_messages_system, NetworkMessages, ServerMessages},
+    network_entity::{
+        assign_network_id_system, assign_stream_id_system, cleanup_network_entity_system, cleanup_stream_entity_system,
+        release_network_id_system, release_stream_id_system, track_network_entity_system, track_stream_entity_system, NetworkEntities,
+        NetworkIdsExhausted, StreamEntities, DEFAULT_ID_BITS,
+    },
+    networked_hierarchy::sync_network_parent_system,
+    prediction::{confirm_predicted_spawn_system, ServerPredictions},
+    priority::ClientReplication,
+    relevancy::RoomMembers,
     sequence_buffer::SequenceBuffer,
-    NetworkedFrame,
+    NetworkedFrame, ReplicationError,
 };
 use bevy::{prelude::*, time::FixedTimestep};
 use bit_serializer::BitWriter;
 use iyes_loopless::prelude::*;
-use std::{collections::HashMap, io, marker::PhantomData};
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
 
-pub struct NetworkTick(pub u64);
+/// Server: current tick of the replication stream of the frame type T, each stream ticks at its own rate.
+pub struct NetworkTick<T>(pub u64, PhantomData<T>);
+
+impl<T> NetworkTick<T> {
+    pub fn new(tick: u64) -> Self {
+        Self(tick, PhantomData)
+    }
+}
 
 pub struct NetworkFrameBuffer<T>(pub SequenceBuffer<T>);
 
-pub struct LastNetworkTick(pub HashMap<u64, u64>);
+/// Server: last tick of the stream T received by each client.
+pub struct LastNetworkTick<T>(pub HashMap<u64, u64>, PhantomData<T>);
+
+impl<T> Default for LastNetworkTick<T> {
+    fn default() -> Self {
+        Self(HashMap::new(), PhantomData)
+    }
+}
+
+/// Server: config of the replication stream of the frame type T.
+pub struct StreamConfig<T>(pub ReplicateServerConfig, PhantomData<T>);
+
+impl<T> StreamConfig<T> {
+    pub fn new(config: ReplicateServerConfig) -> Self {
+        Self(config, PhantomData)
+    }
+}
+
+/// How a replication stream allocates the NetworkIDs of its entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkIdAllocation {
+    /// Entities marked with [`Replicated`](crate::Replicated) get their NetworkID from the [`NetworkEntities`]
+    /// shared by the streams using this allocation, every one of them replicates those entities.
+    /// The NetworkEntities is created with the id_bits of the first stream added.
+    Shared,
+    /// Entities marked with [`ReplicatedBy<T>`](crate::ReplicatedBy) get their NetworkID from the [`StreamEntities`]
+    /// of the stream, with indices starting at `first_index`, and are only replicated by this stream.
+    /// The indices of the streams must not overlap, the clients need an id_bits that covers the highest index.
+    Separate { first_index: u32 },
+}
+
+// The shared systems are added to the app once, by the first stream
+struct SharedServerSystems;
+struct SharedNetworkIdSystems;
+
+// Streams that are active: the plugins without state and the state plugins that are in their state.
+// The resources shared by the streams are inserted when the first one becomes active and removed when the last one leaves its state.
+#[derive(Default)]
+struct ActiveStreams {
+    streams: usize,
+    // Streams using the shared NetworkEntities
+    shared_network_ids: usize,
+}
+
+impl ActiveStreams {
+    // Returns whether it is the first active stream, and the first one using the shared NetworkEntities
+    fn activate(&mut self, network_ids: NetworkIdAllocation) -> (bool, bool) {
+        self.streams += 1;
+        let first_shared_stream = match network_ids {
+            NetworkIdAllocation::Shared => {
+                self.shared_network_ids += 1;
+                self.shared_network_ids == 1
+            }
+            NetworkIdAllocation::Separate { .. } => false,
+        };
+
+        (self.streams == 1, first_shared_stream)
+    }
 
+    // Returns whether it was the last active stream, and the last one using the shared NetworkEntities
+    fn deactivate(&mut self, network_ids: NetworkIdAllocation) -> (bool, bool) {
+        self.streams = self.streams.saturating_sub(1);
+        let last_shared_stream = match network_ids {
+            NetworkIdAllocation::Shared => {
+                self.shared_network_ids = self.shared_network_ids.saturating_sub(1);
+                self.shared_network_ids == 0
+            }
+            NetworkIdAllocation::Separate { .. } => false,
+        };
+
+        (self.streams == 0, last_shared_stream)
+    }
+}
+
+/// Several plugins with different frame types can be added, each one is an independent replication stream
+/// with its own tick rate, acknowledged ticks and frames. The entities are shared between the streams
+/// or not depending on [`ReplicateServerConfig::network_ids`].
 pub struct ReplicateServerPlugin<T> {
-    tick_rate: f64,
+    config: ReplicateServerConfig,
     data: PhantomData<T>,
 }
 
 impl<T> Default for ReplicateServerPlugin<T> {
     fn default() -> Self {
         Self {
-            tick_rate: 20.,
+            config: Default::default(),
             data: PhantomData,
         }
     }
 }
 
+impl<T> ReplicateServerPlugin<T> {
+    pub fn new(config: ReplicateServerConfig) -> Self {
+        Self { config, data: PhantomData }
+    }
+}
+
 impl<T: NetworkedFrame> Plugin for ReplicateServerPlugin<T> {
     fn build(&self, app: &mut App) {
-        app.insert_resource(NetworkEntities::default());
-        app.insert_resource(NetworkTick(0));
-        app.insert_resource(LastNetworkTick(HashMap::new()));
+        app.insert_resource(NetworkTick::<T>::new(0));
+        app.insert_resource(LastNetworkTick::<T>::default());
+        app.insert_resource(ServerPredictions::<T>::default());
+        app.insert_resource(StreamConfig::<T>::new(self.config.clone()));
+        app.insert_resource(ClientReplication::<T>::new(self.config.buffer_size));
+        app.init_resource::<RoomMembers>();
+        app.init_resource::<ServerMessages>();
+        app.init_resource::<NetworkMessages>();
 
-        let buffer: SequenceBuffer<T> = SequenceBuffer::with_capacity(60);
+        let buffer: SequenceBuffer<T> = SequenceBuffer::with_capacity(self.config.buffer_size);
         app.insert_resource(NetworkFrameBuffer(buffer));
 
+        match self.config.network_ids {
+            NetworkIdAllocation::Shared => {
+                if !app.world.contains_resource::<NetworkEntities>() {
+                    app.insert_resource(NetworkEntities::new(self.config.id_bits));
+                }
+            }
+            NetworkIdAllocation::Separate { first_index } => {
+                let network_entities = NetworkEntities::with_first_index(self.config.id_bits, first_index);
+                app.insert_resource(StreamEntities::<T>::new(network_entities));
+            }
+        }
+
         app.add_system_to_stage(
             CoreStage::PreUpdate,
-            tick_network.with_run_criteria(FixedTimestep::steps_per_second(self.tick_rate)),
+            tick_network::<T>.with_run_criteria(FixedTimestep::steps_per_second(self.config.tick_rate)),
         );
         app.add_system_to_stage(
             CoreStage::Update,
             generate_network_frame::<T>
                 .exclusive_system()
                 .at_end()
-                .with_run_criteria(FixedTimestep::steps_per_second(self.tick_rate)),
+                .with_run_criteria(FixedTimestep::steps_per_second(self.config.tick_rate)),
         );
+        app.add_system(confirm_predicted_spawn_system::<T>);
+        // The world only keeps the removals until the end of the update, the frames are generated at the tick rate
+        app.add_system_to_stage(CoreStage::Last, T::track_removals.exclusive_system());
+
+        app.add_event::<NetworkIdsExhausted>();
+        if let NetworkIdAllocation::Separate { .. } = self.config.network_ids {
+            app.add_system_to_stage(CoreStage::PostUpdate, assign_stream_id_system::<T>);
+            app.add_system_to_stage(CoreStage::PostUpdate, release_stream_id_system::<T>);
+            app.add_system(track_stream_entity_system::<T>);
+            app.add_system_to_stage(CoreStage::Last, cleanup_stream_entity_system::<T>);
+        }
 
-        app.add_system(track_network_entity_system);
-        app.add_system(cleanup_network_entity_system);
+        // The stream is always active
+        app.world
+            .get_resource_or_insert_with(ActiveStreams::default)
+            .activate(self.config.network_ids);
+        add_shared_systems(app, self.config.network_ids);
     }
 }
 
-fn generate_network_frame<T: NetworkedFrame>(world: &mut World) {
-    let tick = world.resource::<NetworkTick>().0;
+// The systems shared by the streams are added once and run while any stream using them is active,
+// whatever the state of the stream that added them.
+fn add_shared_systems(app: &mut App, network_ids: NetworkIdAllocation) {
+    if network_ids == NetworkIdAllocation::Shared && !app.world.contains_resource::<SharedNetworkIdSystems>() {
+        app.insert_resource(SharedNetworkIdSystems);
+        app.add_system_to_stage(CoreStage::PostUpdate, assign_network_id_system.run_if(any_shared_stream_active));
+        app.add_system_to_stage(CoreStage::PostUpdate, release_network_id_system.run_if(any_shared_stream_active));
+        app.add_system(track_network_entity_system.run_if(any_shared_stream_active));
+        // Runs in the last stage to see the NetworkID removals from the whole frame
+        app.add_system_to_stage(CoreStage::Last, cleanup_network_entity_system.run_if(any_shared_stream_active));
+    }
+
+    if !app.world.contains_resource::<SharedServerSystems>() {
+        app.insert_resource(SharedServerSystems);
+        app.add_system(sync_network_parent_system.run_if(any_stream_active));
+        app.add_system_to_stage(CoreStage::Last, clear_server_messages_system.run_if(any_stream_active));
+    }
+}
+
+fn any_stream_active(active_streams: Option<Res<ActiveStreams>>) -> bool {
+    active_streams.map_or(false, |active_streams| active_streams.streams > 0)
+}
+
+fn any_shared_stream_active(active_streams: Option<Res<ActiveStreams>>) -> bool {
+    active_streams.map_or(false, |active_streams| active_streams.shared_network_ids > 0)
+}
+
+pub(crate) fn generate_network_frame<T: NetworkedFrame>(world: &mut World) {
+    let tick = world.resource::<NetworkTick<T>>().0;
     let frame = T::generate_frame(tick, world);
+    let frame = stream_frame(frame, world);
     let buffer = &mut world.resource_mut::<NetworkFrameBuffer<T>>().0;
     buffer.insert(tick, frame);
+
+    // The released NetworkIDs are reused once the clients acknowledged a frame generated after their release
+    let acknowledged_tick = world.resource::<LastNetworkTick<T>>().0.values().min().copied();
+    let config = &world.resource::<StreamConfig<T>>().0;
+    let (network_ids, buffer_size) = (config.network_ids, config.buffer_size);
+    match network_ids {
+        NetworkIdAllocation::Shared => {
+            if let Some(mut network_entities) = world.get_resource_mut::<NetworkEntities>() {
+                network_entities.acknowledge_frame::<T>(tick, acknowledged_tick, buffer_size);
+            }
+        }
+        NetworkIdAllocation::Separate { .. } => {
+            if let Some(mut stream_entities) = world.get_resource_mut::<StreamEntities<T>>() {
+                stream_entities.acknowledge_frame::<T>(tick, acknowledged_tick, buffer_size);
+            }
+        }
+    }
+
+    world.resource_scope(|world, mut replication: Mut<ClientReplication<T>>| {
+        replication.update(world);
+    });
+}
+
+// The frames are generated with every networked entity, the stream only keeps the entities
+// with a NetworkID from its allocation.
+fn stream_frame<T: NetworkedFrame>(frame: T, world: &World) -> T {
+    let network_entities = match world.resource::<StreamConfig<T>>().0.network_ids {
+        NetworkIdAllocation::Shared => world.get_resource::<NetworkEntities>(),
+        NetworkIdAllocation::Separate { .. } => world.get_resource::<StreamEntities<T>>().map(|stream_entities| &**stream_entities),
+    };
+    let network_entities = match network_entities {
+        Some(network_entities) => network_entities,
+        None => return frame,
+    };
+
+    let selected: Vec<bool> = frame
+        .entities()
+        .iter()
+        .map(|network_id| network_entities.contains(*network_id))
+        .collect();
+    if selected.iter().all(|selected| *selected) {
+        return frame;
+    }
+
+    frame.select_entities(&selected, None)
 }
 
-fn tick_network(mut network_tick: ResMut<NetworkTick>) {
+fn tick_network<T: NetworkedFrame>(mut network_tick: ResMut<NetworkTick<T>>) {
     network_tick.0 += 1;
 }
 
+/// Encode the current frame for the client, split in fragments of at most `config.fragment_size` bytes.
+/// Every fragment should be sent to the client and given to `process_snapshot` when received.
+/// When the client has a bit budget, only the entities with the highest priority that fit in it are sent.
+/// The frame is compressed with `config.compression` when it makes it smaller.
+/// Fails with [`ReplicationError::MissingFrame`] when the frame of the current tick was not generated yet.
 pub fn replicate<T: NetworkedFrame>(
     client: u64,
-    tick: &NetworkTick,
-    last_ticks: &LastNetworkTick,
+    tick: &NetworkTick<T>,
+    last_ticks: &LastNetworkTick<T>,
     buffer: &NetworkFrameBuffer<T>,
-) -> Result<Vec<u8>, io::Error> {
+    predictions: &mut ServerPredictions<T>,
+    config: &ReplicateServerConfig,
+    replication: &mut ClientReplication<T>,
+) -> Result<Vec<Vec<u8>>, ReplicationError> {
     // TODO: add cache for full frame or generating a frame with the same delta_tick
     // struct DeltaCache(HashMap<delta_tick, Bytes>), return Bytes instead of Vec<u8>
     let mut writer = BitWriter::with_capacity(1000);
-    let frame = buffer.0.get(tick.0).unwrap();
-    if let Some(last_received_tick) = last_ticks.0.get(&client) {
-        match buffer.0.get(*last_received_tick) {
-            Some(last_received_frame) => {
-                frame.write_delta_frame(&mut writer, last_received_frame)?;
-            }
-            None => {
-                frame.write_full_frame(&mut writer)?;
-            }
+    let frame = match buffer.0.get(tick.0) {
+        Some(frame) => frame,
+        None => return Err(ReplicationError::MissingFrame { tick: tick.0 }),
+    };
+    let last_received_tick = last_ticks.0.get(&client).copied();
+    let client_frame = replication.client_frame(client, frame, &buffer.0, last_received_tick, config.bits_per_client)?;
+    // Delta frames are encoded against the frame that was sent to the client,
+    // the generated frames sent as is are only kept in the frame buffer
+    let last_received_frame = last_received_tick.and_then(|last_received_tick| {
+        replication
+            .sent_frame(client, last_received_tick)
+            .or_else(|| buffer.0.get(last_received_tick))
+    });
+    let sent_frame = client_frame.as_ref().unwrap_or(frame);
+    match last_received_frame {
+        Some(last_received_frame) => {
+            sent_frame.write_delta_frame(&mut writer, last_received_frame)?;
+        }
+        None => {
+            sent_frame.write_full_frame(&mut writer)?;
         }
-    } else {
-        frame.write_full_frame(&mut writer)?;
     }
+    if let Some(client_frame) = client_frame {
+        replication.insert_sent_frame(client, tick.0, client_frame);
+    }
+    predictions.write_reports(&mut writer, client, tick.0, last_received_tick)?;
 
-    writer.consume()
+    let payload = writer.consume()?;
+    let payload = compress_frame(&payload, config.compression.as_deref())?;
+    Ok(fragment_frame(tick.0, &payload, config.fragment_size)?)
 }
 
 pub struct ReplicateServerStatePlugin<T, S> {
@@ -97,9 +323,22 @@ pub struct ReplicateServerStatePlugin<T, S> {
     state: PhantomData<S>,
 }
 
+#[derive(Debug, Clone)]
 pub struct ReplicateServerConfig {
     pub tick_rate: f64,
     pub buffer_size: usize,
+    /// Width in bits of the generated NetworkIDs, between 8 and 24 bits (256 to 16777216 networked entities).
+    pub id_bits: usize,
+    /// Maximum size in bytes of each message returned by `replicate`, larger frames are fragmented,
+    /// in up to [`MAX_FRAGMENTS`](crate::fragment::MAX_FRAGMENTS) fragments.
+    pub fragment_size: usize,
+    /// Bits each client can receive per tick, the entities that don't fit are sent in later ticks.
+    /// `None` sends every entity in every tick, see [`ClientReplication::set_budget`] to change it per client.
+    pub bits_per_client: Option<usize>,
+    /// Codec used to compress the frames, the client needs the same codec in its config. Disabled by default.
+    pub compression: Option<Arc<dyn FrameCodec>>,
+    /// Whether the NetworkIDs are shared with the other streams, see [`NetworkIdAllocation`].
+    pub network_ids: NetworkIdAllocation,
 }
 
 impl<T, S> Default for ReplicateServerStatePlugin<T, S> {
@@ -112,10 +351,17 @@ impl<T, S> Default for ReplicateServerStatePlugin<T, S> {
     }
 }
 
-
 impl Default for ReplicateServerConfig {
     fn default() -> Self {
-        Self { tick_rate: 20., buffer_size: 60 }
+        Self {
+            tick_rate: 20.,
+            buffer_size: 60,
+            id_bits: DEFAULT_ID_BITS,
+            fragment_size: 1024,
+            bits_per_client: None,
+            compression: None,
+            network_ids: NetworkIdAllocation::Shared,
+        }
     }
 }
 
@@ -129,12 +375,12 @@ impl<T: NetworkedFrame, S: bevy::ecs::schedule::StateData> ReplicateServerStateP
     }
 
     pub fn build(self, app: &mut App, state: S) {
-        app.add_enter_system(state.clone(), resources_setup::<T>);
-        app.add_exit_system(state.clone(), resources_cleanup::<T>);
+        app.add_enter_system(state.clone(), resources_setup::<T>.exclusive_system());
+        app.add_exit_system(state.clone(), resources_cleanup::<T>.exclusive_system());
 
         app.add_system_to_stage(
             CoreStage::PreUpdate,
-            iyes_loopless::condition::IntoConditionalExclusiveSystem::run_in_state(tick_network, state.clone())
+            iyes_loopless::condition::IntoConditionalExclusiveSystem::run_in_state(tick_network::<T>, state.clone())
                 .at_end()
                 .with_run_criteria(FixedTimestep::steps_per_second(self.config.tick_rate)),
         );
@@ -144,26 +390,140 @@ impl<T: NetworkedFrame, S: bevy::ecs::schedule::StateData> ReplicateServerStateP
                 .at_end()
                 .with_run_criteria(FixedTimestep::steps_per_second(self.config.tick_rate)),
         );
+        app.add_system(confirm_predicted_spawn_system::<T>.run_in_state(state.clone()));
+        app.add_system_to_stage(
+            CoreStage::Last,
+            iyes_loopless::condition::IntoConditionalExclusiveSystem::run_in_state(T::track_removals, state.clone()),
+        );
 
-        app.add_system(track_network_entity_system.run_in_state(state.clone()));
-        app.add_system(cleanup_network_entity_system.run_in_state(state));
+        app.add_event::<NetworkIdsExhausted>();
+        if let NetworkIdAllocation::Separate { .. } = self.config.network_ids {
+            app.add_system_to_stage(CoreStage::PostUpdate, assign_stream_id_system::<T>.run_in_state(state.clone()));
+            app.add_system_to_stage(CoreStage::PostUpdate, release_stream_id_system::<T>.run_in_state(state.clone()));
+            app.add_system(track_stream_entity_system::<T>.run_in_state(state.clone()));
+            app.add_system_to_stage(CoreStage::Last, cleanup_stream_entity_system::<T>.run_in_state(state));
+        }
+        add_shared_systems(app, self.config.network_ids);
 
-        app.insert_resource(self.config);
+        app.init_resource::<NetworkMessages>();
+        app.insert_resource(StreamConfig::<T>::new(self.config));
     }
 }
 
-fn resources_setup<T: NetworkedFrame>(mut commands: Commands, config: Res<ReplicateServerConfig>) {
-    commands.insert_resource(NetworkEntities::default());
-    commands.insert_resource(NetworkTick(0));
-    commands.insert_resource(LastNetworkTick(HashMap::new()));
+fn resources_setup<T: NetworkedFrame>(world: &mut World) {
+    let config = world.resource::<StreamConfig<T>>().0.clone();
+    world.insert_resource(NetworkTick::<T>::new(0));
+    world.insert_resource(LastNetworkTick::<T>::default());
+    world.insert_resource(ServerPredictions::<T>::default());
+    world.insert_resource(ClientReplication::<T>::new(config.buffer_size));
+
+    let (first_stream, first_shared_stream) = world
+        .get_resource_or_insert_with(ActiveStreams::default)
+        .activate(config.network_ids);
+
+    match config.network_ids {
+        NetworkIdAllocation::Shared => {
+            if first_shared_stream {
+                world.insert_resource(NetworkEntities::new(config.id_bits));
+            }
+        }
+        NetworkIdAllocation::Separate { first_index } => {
+            let network_entities = NetworkEntities::with_first_index(config.id_bits, first_index);
+            world.insert_resource(StreamEntities::<T>::new(network_entities));
+        }
+    }
+    if first_stream {
+        world.insert_resource(RoomMembers::default());
+        world.insert_resource(ServerMessages::default());
+    }
 
     let buffer: SequenceBuffer<T> = SequenceBuffer::with_capacity(config.buffer_size);
-    commands.insert_resource(NetworkFrameBuffer(buffer));
+    world.insert_resource(NetworkFrameBuffer(buffer));
 }
 
-fn resources_cleanup<T: NetworkedFrame>(mut commands: Commands) {
-    commands.remove_resource::<NetworkEntities>();
-    commands.remove_resource::<NetworkTick>();
-    commands.remove_resource::<LastNetworkTick>();
-    commands.remove_resource::<NetworkFrameBuffer<T>>();
+fn resources_cleanup<T: NetworkedFrame>(world: &mut World) {
+    let network_ids = world.resource::<StreamConfig<T>>().0.network_ids;
+    world.remove_resource::<StreamEntities<T>>();
+    world.remove_resource::<NetworkTick<T>>();
+    world.remove_resource::<LastNetworkTick<T>>();
+    world.remove_resource::<ServerPredictions<T>>();
+    world.remove_resource::<ClientReplication<T>>();
+    world.remove_resource::<NetworkFrameBuffer<T>>();
+    T::remove_generator(world);
+
+    let (last_stream, last_shared_stream) = world.get_resource_or_insert_with(ActiveStreams::default).deactivate(network_ids);
+    if let Some(mut network_entities) = world.get_resource_mut::<NetworkEntities>() {
+        network_entities.remove_stream::<T>();
+    }
+
+    if last_shared_stream {
+        world.remove_resource::<NetworkEntities>();
+    }
+    if last_stream {
+        world.remove_resource::<RoomMembers>();
+        world.remove_resource::<ServerMessages>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dynamic_frame::{DynamicFrame, ReplicateAppExt};
+    use crate::network_entity::MIN_ID_BITS;
+    use crate::test_utils::{NetworkFrame, Simple};
+    use crate::{NetworkID, Replicated, ReplicatedBy};
+
+    use super::*;
+
+    #[test]
+    fn test_replication_streams() {
+        let mut server = App::new();
+        server.init_resource::<Time>();
+        server.add_plugin(ReplicateServerPlugin::<NetworkFrame>::default());
+        server.add_plugin(ReplicateServerPlugin::<DynamicFrame>::new(ReplicateServerConfig {
+            id_bits: MIN_ID_BITS,
+            network_ids: NetworkIdAllocation::Separate { first_index: 4096 },
+            ..Default::default()
+        }));
+        server.replicate::<Simple>();
+        server.world.resource_mut::<NetworkTick<DynamicFrame>>().0 = 7;
+
+        // The markers get their NetworkID from the allocation of their streams
+        let shared_entity = server.world.spawn().insert(Replicated).insert(Simple(1)).id();
+        let stream_entity = server
+            .world
+            .spawn()
+            .insert(ReplicatedBy::<DynamicFrame>::default())
+            .insert(Simple(2))
+            .id();
+        server.update();
+        server.update();
+
+        let shared_id = *server.world.get::<NetworkID>(shared_entity).unwrap();
+        let stream_id = *server.world.get::<NetworkID>(stream_entity).unwrap();
+        assert_eq!(shared_id, NetworkID::new(0, 0));
+        assert_eq!(stream_id, NetworkID::new(4096, 0));
+        assert_eq!(
+            server.world.resource::<NetworkEntities>().network_map.get(&shared_id),
+            Some(&shared_entity)
+        );
+        let stream_entities = server.world.resource::<StreamEntities<DynamicFrame>>();
+        assert_eq!(stream_entities.network_map.get(&stream_id), Some(&stream_entity));
+
+        // Each stream has its own tick and only replicates the entities from its allocation
+        generate_network_frame::<NetworkFrame>(&mut server.world);
+        generate_network_frame::<DynamicFrame>(&mut server.world);
+        let frame = server.world.resource::<NetworkFrameBuffer<NetworkFrame>>().0.get(0).unwrap();
+        assert_eq!(frame.entities(), &[shared_id]);
+        let frame = server.world.resource::<NetworkFrameBuffer<DynamicFrame>>().0.get(7).unwrap();
+        assert_eq!(frame.entities(), &[stream_id]);
+
+        // Removing the markers releases the ids in their allocation
+        server.world.entity_mut(shared_entity).remove::<Replicated>();
+        server.world.entity_mut(stream_entity).remove::<ReplicatedBy<DynamicFrame>>();
+        server.update();
+        assert!(server.world.get::<NetworkID>(shared_entity).is_none());
+        assert!(server.world.get::<NetworkID>(stream_entity).is_none());
+        assert!(server.world.resource::<NetworkEntities>().network_map.is_empty());
+        assert!(server.world.resource::<StreamEntities<DynamicFrame>>().network_map.is_empty());
+    }
 }
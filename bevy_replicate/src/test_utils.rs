@@ -0,0 +1,83 @@
+//! Components and frames shared by the tests of the modules.
+
+use std::{io, sync::Arc};
+
+use bevy::prelude::Component;
+
+use crate::{visibility::ComponentVisibility, BitReader, BitWriter, NetworkID, NetworkedComponent};
+
+/// Delta encoded when the value changes by less than 32.
+#[derive(Debug, Component, PartialEq, Eq, Clone)]
+pub struct Simple(pub u32);
+
+impl NetworkedComponent for Simple {
+    type Component = Self;
+
+    fn can_delta(old: &Self::Component, new: &Self::Component) -> bool {
+        new.0.abs_diff(old.0) < 32
+    }
+
+    fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u32(component.0)
+    }
+
+    fn read_full(reader: &mut BitReader) -> Result<Self::Component, io::Error> {
+        let value = reader.read_u32()?;
+
+        Ok(Self(value))
+    }
+
+    fn write_delta(old: &Self::Component, new: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
+        let diff = new.0.abs_diff(old.0);
+        writer.write_bool(new.0 > old.0)?;
+        writer.write_bits(diff, 5)
+    }
+
+    fn read_delta(old: &Self::Component, reader: &mut BitReader) -> Result<Self::Component, io::Error> {
+        let sign = reader.read_bool()?;
+        let diff = reader.read_bits(5)?;
+        let value = if sign { old.0 + diff } else { old.0 - diff };
+
+        Ok(Self(value))
+    }
+}
+
+/// Only replicated to the owner of the entity.
+#[derive(Debug, Component, PartialEq, Eq, Clone)]
+pub struct Secret(pub u32);
+
+impl NetworkedComponent for Secret {
+    type Component = Self;
+
+    fn visibility() -> ComponentVisibility {
+        ComponentVisibility::Owner
+    }
+
+    fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_u32(component.0)
+    }
+
+    fn read_full(reader: &mut BitReader) -> Result<Self::Component, io::Error> {
+        Ok(Self(reader.read_u32()?))
+    }
+}
+
+crate::network_frame!(Simple);
+
+impl NetworkFrame {
+    pub fn new(tick: u64, entities: Vec<NetworkID>, simple: Vec<Option<Simple>>) -> Self {
+        Self {
+            tick,
+            entities,
+            simple: column(simple),
+        }
+    }
+
+    pub fn simple(&self) -> &[Option<Arc<Simple>>] {
+        &self.simple
+    }
+}
+
+pub fn column<C>(components: Vec<Option<C>>) -> Vec<Option<Arc<C>>> {
+    components.into_iter().map(|component| component.map(Arc::new)).collect()
+}
@@ -0,0 +1,262 @@
+use crate::{
+    relevancy::{NetworkRoom, RoomMembers},
+    NetworkID, NetworkOwner, NetworkedComponent,
+};
+
+use bevy::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// Which clients receive a component type, see [`NetworkedComponent::visibility`].
+/// Clients that can't see the component receive the entity without it.
+#[derive(Debug, Clone, Copy)]
+pub enum ComponentVisibility {
+    Everyone,
+    /// Only the client in the entity's [`NetworkOwner`] on the server, the component is hidden from every client
+    /// when the entity has no owner.
+    Owner,
+    /// Only the clients that joined the entity's [`NetworkRoom`] in [`RoomMembers`], e.g. the team of the entity.
+    Room,
+    Custom(fn(&Viewer) -> bool),
+}
+
+impl ComponentVisibility {
+    pub fn is_visible(&self, viewer: &Viewer) -> bool {
+        match self {
+            ComponentVisibility::Everyone => true,
+            ComponentVisibility::Owner => viewer.is_owner(),
+            ComponentVisibility::Room => viewer.in_room(),
+            ComponentVisibility::Custom(is_visible) => is_visible(viewer),
+        }
+    }
+}
+
+/// Client asking to see a component of an entity, given to custom visibility rules.
+#[derive(Debug)]
+pub struct Viewer<'a> {
+    pub client_id: u64,
+    pub network_id: NetworkID,
+    pub owner: Option<NetworkOwner>,
+    context: &'a VisibilityContext,
+}
+
+impl Viewer<'_> {
+    pub fn is_owner(&self) -> bool {
+        self.owner.map_or(false, |owner| owner.0 == self.client_id)
+    }
+
+    /// Room of the entity, from its NetworkRoom.
+    pub fn room(&self) -> Option<u64> {
+        self.context.rooms.get(&self.network_id).copied()
+    }
+
+    /// Whether the client joined the room of the entity.
+    pub fn in_room(&self) -> bool {
+        match (self.room(), self.context.members.get(&self.client_id)) {
+            (Some(room), Some(client_rooms)) => client_rooms.contains(&room),
+            _ => false,
+        }
+    }
+}
+
+/// Server: the owners and rooms of the entities and the rooms of the clients when the frame was generated.
+#[derive(Debug, Default)]
+pub struct VisibilityContext {
+    owners: HashMap<NetworkID, NetworkOwner>,
+    rooms: HashMap<NetworkID, u64>,
+    members: HashMap<u64, HashSet<u64>>,
+}
+
+impl VisibilityContext {
+    pub(crate) fn update(&mut self, world: &mut World) {
+        self.owners.clear();
+        self.rooms.clear();
+        self.members.clear();
+
+        // The owners are read from the world, NetworkOwner doesn't need to be replicated
+        let mut query = world.query::<(&NetworkID, &NetworkOwner)>();
+        for (network_id, owner) in query.iter(world) {
+            self.owners.insert(*network_id, *owner);
+        }
+
+        let mut query = world.query::<(&NetworkID, &NetworkRoom)>();
+        for (network_id, room) in query.iter(world) {
+            self.rooms.insert(*network_id, room.0);
+        }
+
+        if let Some(room_members) = world.get_resource::<RoomMembers>() {
+            for client_id in room_members.clients() {
+                self.members.insert(client_id, room_members.rooms(client_id).collect());
+            }
+        }
+    }
+}
+
+/// Remove the components the client can't see from the frame column, they are written as `NoComponent`.
+pub fn hide_components<T: NetworkedComponent>(
+    client_id: u64,
+    context: &VisibilityContext,
+    entities: &[NetworkID],
+    components: &mut [Option<Arc<T::Component>>],
+) {
+    let visibility = T::visibility();
+    if let ComponentVisibility::Everyone = visibility {
+        return;
+    }
+
+    for (i, component) in components.iter_mut().enumerate() {
+        if component.is_none() {
+            continue;
+        }
+
+        let viewer = Viewer {
+            client_id,
+            network_id: entities[i],
+            owner: context.owners.get(&entities[i]).copied(),
+            context,
+        };
+        if !visibility.is_visible(&viewer) {
+            *component = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::client::{NetworkMapping, SnapshotInterpolationBuffer};
+    use crate::network_entity::DEFAULT_ID_BITS;
+    use crate::priority::ClientReplication;
+    use crate::relevancy::{AlwaysRelevant, GlobalRelevancy, RoomRelevancy};
+    use crate::sequence_buffer::SequenceBuffer;
+    use crate::test_utils::{column, Secret, Simple};
+    use crate::{BitReader, BitWriter, NetworkedFrame};
+    use with_secret::NetworkFrame as SecretFrame;
+
+    use super::*;
+
+    mod with_secret {
+        use crate::test_utils::{Secret, Simple};
+
+        crate::network_frame!(Simple, Secret);
+    }
+
+    // Build the frame of client 1 against its acknowledged frame and read it in the client world
+    fn send_frame(
+        replication: &mut ClientReplication<SecretFrame>,
+        frame: &SecretFrame,
+        acknowledged_tick: Option<u64>,
+        client_world: &mut World,
+    ) -> SecretFrame {
+        let frames = SequenceBuffer::with_capacity(8);
+        let client_frame = replication
+            .client_frame(1, frame, &frames, acknowledged_tick, None)
+            .unwrap()
+            .unwrap();
+        let mut writer = BitWriter::with_capacity(100);
+        match acknowledged_tick.and_then(|tick| replication.sent_frame(1, tick)) {
+            Some(baseline) => client_frame.write_delta_frame(&mut writer, baseline).unwrap(),
+            None => client_frame.write_full_frame(&mut writer).unwrap(),
+        }
+        replication.insert_sent_frame(1, frame.tick(), client_frame.clone());
+
+        let buffer = writer.consume().unwrap();
+        let mut reader = BitReader::new(&buffer).unwrap();
+        let read_frame = SecretFrame::read_frame(&mut reader, client_world).unwrap();
+        assert_eq!(read_frame, client_frame);
+        client_world
+            .resource_mut::<SnapshotInterpolationBuffer<SecretFrame>>()
+            .add_snapshot(Duration::ZERO, read_frame.clone());
+
+        read_frame
+    }
+
+    #[test]
+    fn test_component_visibility() {
+        let mut world = World::new();
+        world.spawn().insert(NetworkID::new(0, 0)).insert(NetworkOwner(1));
+        world.spawn().insert(NetworkID::new(1, 0)).insert(NetworkOwner(2));
+        world.spawn().insert(NetworkID::new(2, 0));
+
+        // The owners are read from the server world, they don't need to be replicated
+        let mut context = VisibilityContext::default();
+        context.update(&mut world);
+
+        let entities = vec![NetworkID::new(0, 0), NetworkID::new(1, 0), NetworkID::new(2, 0)];
+        let secrets = column(vec![Some(Secret(3)), Some(Secret(4)), Some(Secret(5))]);
+
+        // Only the owner sees the component, other clients receive the entities without it
+        let mut owner_secrets = secrets.clone();
+        hide_components::<Secret>(1, &context, &entities, &mut owner_secrets);
+        assert_eq!(owner_secrets, column(vec![Some(Secret(3)), None, None]));
+
+        let mut other_secrets = secrets.clone();
+        hide_components::<Secret>(2, &context, &entities, &mut other_secrets);
+        assert_eq!(other_secrets, column(vec![None, Some(Secret(4)), None]));
+    }
+    #[test]
+    fn test_visibility_changes() {
+        let mut server_world = World::new();
+        let mut room_members = RoomMembers::default();
+        room_members.join(1, 7);
+        server_world.insert_resource(room_members);
+        let server_entity = server_world
+            .spawn()
+            .insert(NetworkID::new(0, 0))
+            .insert(NetworkRoom(7))
+            .insert(NetworkOwner(1))
+            .insert(Simple(1))
+            .insert(Secret(3))
+            .id();
+        server_world
+            .spawn()
+            .insert(NetworkID::new(1, 0))
+            .insert(AlwaysRelevant)
+            .insert(Simple(2));
+
+        let mut replication = ClientReplication::<SecretFrame>::new(8);
+        replication.add_relevancy(GlobalRelevancy::default());
+        replication.add_relevancy(RoomRelevancy::default());
+
+        let mut client_world = World::new();
+        client_world.insert_resource(NetworkMapping::new(DEFAULT_ID_BITS));
+        client_world.insert_resource(SnapshotInterpolationBuffer::<SecretFrame>::new(8, Duration::ZERO, 60.));
+
+        let first_frame = SecretFrame::generate_frame(0, &mut server_world);
+        replication.update(&mut server_world);
+        let read_first = send_frame(&mut replication, &first_frame, None, &mut client_world);
+        read_first.apply_in_world(&mut client_world, None);
+        let entity = client_world.resource::<NetworkMapping>().entities[&NetworkID::new(0, 0)];
+        assert_eq!(client_world.get::<Secret>(entity), Some(&Secret(3)));
+
+        // Hidden from the client while its acknowledged frame still has the entity
+        server_world.resource_mut::<RoomMembers>().leave(1, 7);
+        let second_frame = SecretFrame::generate_frame(1, &mut server_world);
+        replication.update(&mut server_world);
+        let read_second = send_frame(&mut replication, &second_frame, Some(0), &mut client_world);
+        assert_eq!(read_second.entities(), &[NetworkID::new(1, 0)]);
+        read_second.apply_in_world(&mut client_world, Some(&read_first));
+        assert!(client_world.get_entity(entity).is_none());
+        assert!(!client_world
+            .resource::<NetworkMapping>()
+            .entities
+            .contains_key(&NetworkID::new(0, 0)));
+
+        // Shown again before the frame without it is acknowledged, the delta against the acknowledged frame
+        // only has the changes since then and the client respawns the entity with all its components
+        server_world.resource_mut::<RoomMembers>().join(1, 7);
+        server_world.entity_mut(server_entity).insert(Simple(4));
+        let third_frame = SecretFrame::generate_frame(2, &mut server_world);
+        replication.update(&mut server_world);
+        let read_third = send_frame(&mut replication, &third_frame, Some(0), &mut client_world);
+        read_third.apply_in_world(&mut client_world, Some(&read_second));
+        let entity = client_world.resource::<NetworkMapping>().entities[&NetworkID::new(0, 0)];
+        assert_eq!(client_world.get::<NetworkID>(entity), Some(&NetworkID::new(0, 0)));
+        assert_eq!(client_world.get::<Simple>(entity), Some(&Simple(4)));
+        assert_eq!(client_world.get::<Secret>(entity), Some(&Secret(3)));
+        assert_eq!(client_world.query::<&NetworkID>().iter(&client_world).count(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+use bevy::prelude::Component;
+use bevy_replicate::{BitReader, BitWriter, NetworkedComponent};
+
+#[derive(Debug, Component, PartialEq, Clone, NetworkedComponent)]
+struct Derived {
+    #[replicate(bits = 5)]
+    small: u8,
+    #[replicate(range(-10.0, 10.0, 0.01))]
+    position: f32,
+    #[replicate(varint)]
+    score: u32,
+    #[replicate(skip)]
+    local: bool,
+    value: u16,
+}
+
+#[derive(Debug, Component, PartialEq, Clone, NetworkedComponent)]
+struct Offset(#[replicate(varint)] i32, #[replicate(bits = 12)] u8);
+
+#[test]
+fn test_derive() {
+    let derived = Derived {
+        small: 3,
+        position: 0.0,
+        score: 70,
+        local: true,
+        value: 9,
+    };
+
+    // 5 bits + 11 bits for the range + 8 bits varint + 16 bits = 40 bits
+    let mut writer = BitWriter::with_capacity(100);
+    Derived::write_full(&derived, &mut writer).unwrap();
+    assert_eq!(writer.bits_written(), 40);
+
+    let buffer = writer.consume().unwrap();
+    let mut reader = BitReader::new(&buffer).unwrap();
+    let read_derived = Derived::read_full(&mut reader).unwrap();
+    // Skipped fields are not replicated
+    assert_eq!(
+        read_derived,
+        Derived {
+            local: false,
+            ..derived.clone()
+        }
+    );
+
+    // 4 bits for the changed fields + 8 bits varint = 12 bits
+    let changed = Derived {
+        score: 71,
+        ..derived.clone()
+    };
+    assert!(Derived::can_delta(&derived, &changed));
+    let mut writer = BitWriter::with_capacity(100);
+    Derived::write_delta(&derived, &changed, &mut writer).unwrap();
+    assert_eq!(writer.bits_written(), 12);
+
+    let buffer = writer.consume().unwrap();
+    let mut reader = BitReader::new(&buffer).unwrap();
+    let read_changed = Derived::read_delta(&derived, &mut reader).unwrap();
+    assert_eq!(read_changed, changed);
+
+    // Values that don't fit in their bits are rejected instead of truncated
+    let mut writer = BitWriter::with_capacity(100);
+    let too_large = Derived { small: 32, ..derived };
+    assert!(Derived::write_full(&too_large, &mut writer).is_err());
+}
+
+#[test]
+fn test_derive_signed_varint() {
+    // Zig-zag encoded: -3 is 5, a single varint byte
+    let offset = Offset(-3, 0);
+    let mut writer = BitWriter::with_capacity(100);
+    Offset::write_full(&offset, &mut writer).unwrap();
+    assert_eq!(writer.bits_written(), 8 + 12);
+
+    let buffer = writer.consume().unwrap();
+    let mut reader = BitReader::new(&buffer).unwrap();
+    assert_eq!(Offset::read_full(&mut reader).unwrap(), offset);
+
+    // Read values above the field type are rejected
+    let mut writer = BitWriter::with_capacity(100);
+    writer.write_varint_u64(0).unwrap();
+    writer.write_bits(300, 12).unwrap();
+    let buffer = writer.consume().unwrap();
+    let mut reader = BitReader::new(&buffer).unwrap();
+    assert!(Offset::read_full(&mut reader).is_err());
+}
@@ -0,0 +1,249 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    Data, DeriveInput, Error, Expr, Fields, Ident, Index, LitInt, Member, Token, Type,
+};
+
+/// Derive `NetworkedComponent` for a struct, the component is its own networked type.
+///
+/// Fields are written in declaration order, by default with `bevy_replicate::encoding::NetworkedField`.
+/// The encoding of a field can be changed with the `replicate` attribute:
+///
+/// - `#[replicate(bits = 5)]`: unsigned integer written with the given number of bits (1 to 32),
+///   values that don't fit are rejected, see `bevy_replicate::encoding::BitsField`
+/// - `#[replicate(range(min, max, precision))]`: f32 quantized in the range with the given precision
+/// - `#[replicate(varint)]`: integer written as a varint, signed integers are zig-zag encoded,
+///   see `bevy_replicate::encoding::VarintField`
+/// - `#[replicate(skip)]`: not replicated, the client uses `Default::default()`
+///
+/// Delta encoding writes one bit per replicated field to mark the changed fields,
+/// followed by the changed fields.
+#[proc_macro_derive(NetworkedComponent, attributes(replicate))]
+pub fn derive_networked_component(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+enum Encoding {
+    Default,
+    Bits(LitInt),
+    Range { min: Expr, max: Expr, precision: Expr },
+    Varint,
+    Skip,
+}
+
+impl Parse for Encoding {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ident: Ident = input.parse()?;
+        match ident.to_string().as_str() {
+            "bits" => {
+                input.parse::<Token![=]>()?;
+                let bits: LitInt = input.parse()?;
+                let value: u32 = bits.base10_parse()?;
+                if !(1..=32).contains(&value) {
+                    return Err(Error::new(bits.span(), "bits must be between 1 and 32"));
+                }
+                Ok(Encoding::Bits(bits))
+            }
+            "range" => {
+                let content;
+                syn::parenthesized!(content in input);
+                let args = Punctuated::<Expr, Token![,]>::parse_terminated(&content)?;
+                if args.len() != 3 {
+                    return Err(Error::new(ident.span(), "expected range(min, max, precision)"));
+                }
+                let mut args = args.into_iter();
+                Ok(Encoding::Range {
+                    min: args.next().unwrap(),
+                    max: args.next().unwrap(),
+                    precision: args.next().unwrap(),
+                })
+            }
+            "varint" => Ok(Encoding::Varint),
+            "skip" => Ok(Encoding::Skip),
+            _ => Err(Error::new(ident.span(), "expected one of: bits, range, varint, skip")),
+        }
+    }
+}
+
+struct Field {
+    member: Member,
+    local: Ident,
+    ty: Type,
+    encoding: Encoding,
+}
+
+impl Field {
+    fn is_replicated(&self) -> bool {
+        !matches!(self.encoding, Encoding::Skip)
+    }
+
+    fn write(&self, value: TokenStream2) -> TokenStream2 {
+        match &self.encoding {
+            Encoding::Default => quote! { ::bevy_replicate::encoding::NetworkedField::write_field(#value, writer)?; },
+            Encoding::Bits(bits) => quote! { ::bevy_replicate::encoding::BitsField::write_bits_field(#value, writer, #bits)?; },
+            Encoding::Range { min, max, precision } => {
+                quote! { ::bevy_replicate::encoding::write_f32_range(writer, *#value, #min, #max, #precision)?; }
+            }
+            Encoding::Varint => quote! { ::bevy_replicate::encoding::VarintField::write_varint_field(#value, writer)?; },
+            Encoding::Skip => quote! {},
+        }
+    }
+
+    fn read(&self) -> TokenStream2 {
+        let ty = &self.ty;
+        match &self.encoding {
+            Encoding::Default => quote! { <#ty as ::bevy_replicate::encoding::NetworkedField>::read_field(reader)? },
+            Encoding::Bits(bits) => quote! { <#ty as ::bevy_replicate::encoding::BitsField>::read_bits_field(reader, #bits)? },
+            Encoding::Range { min, max, precision } => {
+                quote! { ::bevy_replicate::encoding::read_f32_range(reader, #min, #max, #precision)? }
+            }
+            Encoding::Varint => quote! { <#ty as ::bevy_replicate::encoding::VarintField>::read_varint_field(reader)? },
+            Encoding::Skip => quote! { ::core::default::Default::default() },
+        }
+    }
+}
+
+fn parse_fields(fields: &Fields) -> syn::Result<Vec<Field>> {
+    let mut parsed = Vec::with_capacity(fields.len());
+    for (i, field) in fields.iter().enumerate() {
+        let mut encoding = Encoding::Default;
+        for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("replicate")) {
+            if !matches!(encoding, Encoding::Default) {
+                return Err(Error::new_spanned(attr, "only one replicate attribute is allowed per field"));
+            }
+            encoding = attr.parse_args()?;
+        }
+
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+
+        parsed.push(Field {
+            member,
+            local: format_ident!("field_{}", i),
+            ty: field.ty.clone(),
+            encoding,
+        });
+    }
+
+    Ok(parsed)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let data = match &input.data {
+        Data::Struct(data) => data,
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "NetworkedComponent can only be derived for structs",
+            ))
+        }
+    };
+    let fields = parse_fields(&data.fields)?;
+
+    let locals: Vec<&Ident> = fields.iter().map(|field| &field.local).collect();
+    let construct = match &data.fields {
+        Fields::Named(_) => {
+            let members = fields.iter().map(|field| &field.member);
+            quote! { Self { #(#members: #locals),* } }
+        }
+        Fields::Unnamed(_) => quote! { Self(#(#locals),*) },
+        Fields::Unit => quote! { Self },
+    };
+
+    let write_full = fields.iter().map(|field| {
+        let member = &field.member;
+        field.write(quote! { &component.#member })
+    });
+    let read_full = fields.iter().map(|field| {
+        let local = &field.local;
+        let read = field.read();
+        quote! { let #local = #read; }
+    });
+
+    let replicated: Vec<&Field> = fields.iter().filter(|field| field.is_replicated()).collect();
+    let unchanged = replicated.iter().map(|field| {
+        let member = &field.member;
+        quote! { old.#member == new.#member }
+    });
+    let write_delta = replicated.iter().map(|field| {
+        let member = &field.member;
+        let write = field.write(quote! { &new.#member });
+        quote! {
+            let changed = old.#member != new.#member;
+            writer.write_bool(changed)?;
+            if changed {
+                #write
+            }
+        }
+    });
+    let read_delta = fields.iter().map(|field| {
+        let member = &field.member;
+        let local = &field.local;
+        if field.is_replicated() {
+            let read = field.read();
+            quote! {
+                let #local = if reader.read_bool()? { #read } else { ::core::clone::Clone::clone(&old.#member) };
+            }
+        } else {
+            quote! { let #local = ::core::clone::Clone::clone(&old.#member); }
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::bevy_replicate::NetworkedComponent for #name #ty_generics #where_clause {
+            type Component = Self;
+
+            // Delta encoding only saves bits when some field didn't change
+            fn can_delta(old: &Self::Component, new: &Self::Component) -> bool {
+                false #(|| #unchanged)*
+            }
+
+            #[allow(unused_variables)]
+            fn write_delta(
+                old: &Self::Component,
+                new: &Self::Component,
+                writer: &mut ::bevy_replicate::BitWriter,
+            ) -> ::core::result::Result<(), ::std::io::Error> {
+                #(#write_delta)*
+                Ok(())
+            }
+
+            #[allow(unused_variables)]
+            fn read_delta(
+                old: &Self::Component,
+                reader: &mut ::bevy_replicate::BitReader,
+            ) -> ::core::result::Result<Self::Component, ::std::io::Error> {
+                #(#read_delta)*
+                Ok(#construct)
+            }
+
+            #[allow(unused_variables)]
+            fn write_full(
+                component: &Self::Component,
+                writer: &mut ::bevy_replicate::BitWriter,
+            ) -> ::core::result::Result<(), ::std::io::Error> {
+                #(#write_full)*
+                Ok(())
+            }
+
+            #[allow(unused_variables)]
+            fn read_full(reader: &mut ::bevy_replicate::BitReader) -> ::core::result::Result<Self::Component, ::std::io::Error> {
+                #(#read_full)*
+                Ok(#construct)
+            }
+        }
+    })
+}
@@ -6,7 +6,9 @@ use bevy_renet::{
 };
 use bevy_replicate::{
     client::{process_snapshot, LastReceivedNetworkTick, ReplicateClientPlugin},
+    message::{write_client_messages, ClientMessages, NetworkMessageAppExt, NetworkMessages},
     networked_transform::interpolate_transform_system,
+    LocalClientId, NetworkID,
 };
 use demo::{panic_on_error_system, setup, NetworkFrame, Player, PlayerInput, PROTOCOL_ID};
 use renet_visualizer::RenetClientVisualizer;
@@ -35,18 +37,25 @@ fn main() {
     app.add_plugin(EguiPlugin);
 
     app.add_plugin(RenetClientPlugin);
-    app.insert_resource(new_renet_client());
+    let client = new_renet_client();
+    app.insert_resource(LocalClientId(client.client_id()));
+    app.insert_resource(client);
     app.insert_resource(PlayerInput::default());
     app.add_system(player_input);
     app.add_system(spawn_client_bundle);
     app.add_system(client_send_input.with_run_criteria(run_if_client_connected));
+    app.add_system_to_stage(
+        CoreStage::PostUpdate,
+        client_send_messages.with_run_criteria(run_if_client_connected),
+    );
     app.add_system(client_send_last_received_tick.with_run_criteria(run_if_client_connected));
 
     app.insert_resource(RenetClientVisualizer::<200>::default());
     app.add_system(update_client_visulizer_system);
 
     app.add_plugin(ReplicateClientPlugin::<NetworkFrame>::default());
-    app.add_system(interpolate_transform_system);
+    app.add_network_message::<PlayerInput>();
+    app.add_system(interpolate_transform_system::<NetworkFrame>);
     app.add_system_to_stage(CoreStage::PreUpdate, read_network_frame.exclusive_system().at_end());
 
     app.add_startup_system(setup);
@@ -58,7 +67,10 @@ fn main() {
 fn read_network_frame(world: &mut World) {
     world.resource_scope(|world, mut client: Mut<RenetClient>| {
         while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
-            process_snapshot::<NetworkFrame>(message, world).unwrap();
+            // Malformed frames are dropped, the next frames are still read
+            if let Err(e) = process_snapshot::<NetworkFrame>(message, world) {
+                warn!("Dropped network frame: {}", e);
+            }
         }
     });
 }
@@ -70,13 +82,26 @@ fn player_input(keyboard_input: Res<Input<KeyCode>>, mut player_input: ResMut<Pl
     player_input.down = keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down);
 }
 
-fn client_send_input(player_input: Res<PlayerInput>, mut client: ResMut<RenetClient>) {
-    let input_message = bincode::serialize(&*player_input).unwrap();
+fn client_send_input(player_input: Res<PlayerInput>, mut client_messages: ResMut<ClientMessages>) {
+    client_messages.send(*player_input);
+}
 
-    client.send_message(DefaultChannel::Reliable, input_message);
+fn client_send_messages(
+    mut client: ResMut<RenetClient>,
+    mut client_messages: ResMut<ClientMessages>,
+    messages: Res<NetworkMessages>,
+    network_ids: Query<&NetworkID>,
+) {
+    let messages = write_client_messages(&mut client_messages, &messages, &network_ids).unwrap();
+    for message in messages.unmapped.iter() {
+        warn!("Dropped message referencing an entity without NetworkID: {:?}", message);
+    }
+    if let Some(payload) = messages.payload {
+        client.send_message(DefaultChannel::Reliable, payload);
+    }
 }
 
-fn client_send_last_received_tick(mut client: ResMut<RenetClient>, last_received_tick: Res<LastReceivedNetworkTick>) {
+fn client_send_last_received_tick(mut client: ResMut<RenetClient>, last_received_tick: Res<LastReceivedNetworkTick<NetworkFrame>>) {
     if let Some(tick) = last_received_tick.0 {
         client.send_message(DefaultChannel::Unreliable, tick.to_le_bytes().to_vec());
     }
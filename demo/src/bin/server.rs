@@ -5,8 +5,11 @@ use bevy_renet::{
     RenetServerPlugin,
 };
 use bevy_replicate::{
-    server::{replicate, LastNetworkTick, NetworkFrameBuffer, NetworkTick, ReplicateServerPlugin},
-    NetworkEntities,
+    message::{process_client_messages, FromClient, NetworkMessageAppExt},
+    prediction::ServerPredictions,
+    priority::ClientReplication,
+    server::{replicate, LastNetworkTick, NetworkFrameBuffer, NetworkTick, ReplicateServerPlugin, StreamConfig},
+    NetworkOwner, ReplicateCommandsExt,
 };
 
 use demo::{panic_on_error_system, setup, NetworkFrame, Player, PlayerInput, PROTOCOL_ID};
@@ -23,8 +26,12 @@ fn main() {
 
     app.add_plugin(RenetServerPlugin);
     app.add_plugin(ReplicateServerPlugin::<NetworkFrame>::default());
+    app.add_network_message::<PlayerInput>();
     app.insert_resource(new_renet_server());
     app.add_system(server_update_system);
+    app.add_system_to_stage(CoreStage::PreUpdate, receive_client_messages.exclusive_system().at_end());
+    app.add_system(player_input_system);
+    app.add_system(client_disconnect_system);
     app.add_system(move_players_system);
     app.add_system_to_stage(CoreStage::PostUpdate, server_sync_players.exclusive_system().at_start());
 
@@ -48,10 +55,7 @@ fn server_update_system(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut server: ResMut<RenetServer>,
-    mut network_entities: ResMut<NetworkEntities>,
-    mut last_received_tick: ResMut<LastNetworkTick>,
-    mut player_query: Query<(Entity, &Player, &mut PlayerInput)>,
+    player_query: Query<(Entity, &NetworkOwner), With<Player>>,
 ) {
     for event in server_events.iter() {
         match event {
@@ -66,38 +70,71 @@ fn server_update_system(
                         ..Default::default()
                     })
                     .insert(PlayerInput::default())
-                    .insert(Player(*id))
-                    .insert(network_entities.generate().unwrap());
+                    .insert(Player)
+                    .insert(NetworkOwner(*id))
+                    .replicate();
             }
             ServerEvent::ClientDisconnected(id) => {
                 println!("Player {} disconnected.", id);
-                last_received_tick.0.remove(id);
-                for (entity, player, _) in player_query.iter() {
-                    if player.0 == *id {
+                for (entity, owner) in player_query.iter() {
+                    if owner.0 == *id {
                         commands.entity(entity).despawn();
                     }
                 }
             }
         }
     }
+}
 
-    for client_id in server.clients_id().into_iter() {
-        while let Some(message) = server.receive_message(client_id, DefaultChannel::Reliable) {
-            let player_input: PlayerInput = bincode::deserialize(&message).unwrap();
-            for (_, player, mut input) in player_query.iter_mut() {
-                if player.0 == client_id {
-                    *input = player_input;
+fn receive_client_messages(world: &mut World) {
+    world.resource_scope(|world, mut server: Mut<RenetServer>| {
+        for client_id in server.clients_id().into_iter() {
+            while let Some(message) = server.receive_message(client_id, DefaultChannel::Reliable) {
+                if let Err(e) = process_client_messages(client_id, &message, world) {
+                    warn!("Dropped messages from client {}: {}", client_id, e);
                 }
             }
         }
+    });
+}
+
+fn player_input_system(
+    mut input_events: EventReader<FromClient<PlayerInput>>,
+    mut player_query: Query<(&NetworkOwner, &mut PlayerInput), With<Player>>,
+) {
+    for event in input_events.iter() {
+        for (owner, mut input) in player_query.iter_mut() {
+            if owner.0 == event.client_id {
+                *input = event.message;
+            }
+        }
+    }
+}
+
+// Remove the replication state kept for disconnected clients
+fn client_disconnect_system(
+    mut server_events: EventReader<ServerEvent>,
+    mut last_received_tick: ResMut<LastNetworkTick<NetworkFrame>>,
+    mut predictions: ResMut<ServerPredictions<NetworkFrame>>,
+    mut replication: ResMut<ClientReplication<NetworkFrame>>,
+) {
+    for event in server_events.iter() {
+        if let ServerEvent::ClientDisconnected(id) = event {
+            last_received_tick.0.remove(id);
+            predictions.remove_client(*id);
+            replication.remove_client(*id);
+        }
     }
 }
 
 fn server_sync_players(
     mut server: ResMut<RenetServer>,
-    network_tick: Res<NetworkTick>,
+    network_tick: Res<NetworkTick<NetworkFrame>>,
     network_buffer: Res<NetworkFrameBuffer<NetworkFrame>>,
-    mut last_received_tick: ResMut<LastNetworkTick>,
+    mut last_received_tick: ResMut<LastNetworkTick<NetworkFrame>>,
+    mut predictions: ResMut<ServerPredictions<NetworkFrame>>,
+    config: Res<StreamConfig<NetworkFrame>>,
+    mut replication: ResMut<ClientReplication<NetworkFrame>>,
 ) {
     // Update last received tick
     for client_id in server.clients_id().into_iter() {
@@ -117,8 +154,24 @@ fn server_sync_players(
     }
 
     for client_id in server.clients_id().into_iter() {
-        let message = replicate::<NetworkFrame>(client_id, &network_tick, &last_received_tick, &network_buffer).unwrap();
-        server.send_message(client_id, DefaultChannel::Unreliable, message);
+        let fragments = match replicate::<NetworkFrame>(
+            client_id,
+            &network_tick,
+            &last_received_tick,
+            &network_buffer,
+            &mut predictions,
+            &config.0,
+            &mut replication,
+        ) {
+            Ok(fragments) => fragments,
+            Err(e) => {
+                warn!("Failed to replicate frame to client {}: {}", client_id, e);
+                continue;
+            }
+        };
+        for fragment in fragments {
+            server.send_message(client_id, DefaultChannel::Unreliable, fragment);
+        }
     }
 }
 
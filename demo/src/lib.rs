@@ -1,14 +1,15 @@
 use bevy::prelude::*;
 use bevy_renet::renet::RenetError;
-use bevy_replicate::{network_frame, networked_transform::TransformNetworked, NetworkedComponent};
-use bit_serializer::{BitReader, BitWriter};
-use serde::{Deserialize, Serialize};
+use bevy_replicate::{
+    message::NetworkMessage, network_frame, networked_transform::TransformNetworked, BitReader, BitWriter, NetworkOwner, NetworkedComponent,
+};
+use std::io;
 
 pub const PROTOCOL_ID: u64 = 7;
 
-network_frame!(TransformNetworked, Player);
+network_frame!(TransformNetworked, Player, NetworkOwner);
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Component)]
+#[derive(Debug, Default, Clone, Copy, Component)]
 pub struct PlayerInput {
     pub up: bool,
     pub down: bool,
@@ -16,22 +17,28 @@ pub struct PlayerInput {
     pub right: bool,
 }
 
-#[derive(Debug, Component, PartialEq, Eq, Clone)]
-pub struct Player(pub u64);
-
-impl NetworkedComponent for Player {
-    type Component = Self;
-
-    fn write_full(component: &Self::Component, writer: &mut BitWriter) -> Result<(), std::io::Error> {
-        writer.write_u64(component.0)
+impl NetworkMessage for PlayerInput {
+    fn write(&self, writer: &mut BitWriter) -> Result<(), io::Error> {
+        writer.write_bool(self.up)?;
+        writer.write_bool(self.down)?;
+        writer.write_bool(self.left)?;
+        writer.write_bool(self.right)
     }
 
-    fn read_full(reader: &mut BitReader) -> Result<Self::Component, std::io::Error> {
-        let id = reader.read_u64()?;
-        Ok(Self(id))
+    fn read(reader: &mut BitReader) -> Result<Self, io::Error> {
+        Ok(Self {
+            up: reader.read_bool()?,
+            down: reader.read_bool()?,
+            left: reader.read_bool()?,
+            right: reader.read_bool()?,
+        })
     }
 }
 
+// The client controlling the player is replicated with NetworkOwner
+#[derive(Debug, Component, PartialEq, Eq, Clone, NetworkedComponent)]
+pub struct Player;
+
 /// set up a simple 3D scene
 pub fn setup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>) {
     // plane